@@ -0,0 +1,399 @@
+//! Coalesces texts from concurrent requests into one call to the model.
+//!
+//! Each request embeds only a text or two, while a model embeds a batch of
+//! texts in little more time than a single one. A [`Batcher`] in front of the
+//! model queues the texts of concurrent callers, hands them to the model
+//! together and gives each caller back its own vectors.
+
+use std::io::{self, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use tokio::sync::oneshot;
+
+use crate::embedding::{Embedder, ModelRegistry};
+
+/// How texts are batched, as configured under `[embedding.batching]`.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Batching {
+    /// Most texts handed to the model at once. A request with more texts is
+    /// never split, but nothing is queued behind it. 1 turns batching off.
+    #[serde(default = "default_max_batch_size")]
+    pub(crate) max_batch_size: usize,
+    /// How long, in milliseconds, the first text queued waits for others to
+    /// join its batch. With 0, a batch is whatever queued up while the model
+    /// was busy, so a lone text never waits.
+    #[serde(default)]
+    pub(crate) max_wait_ms: u64,
+}
+
+fn default_max_batch_size() -> usize {
+    32
+}
+
+impl Default for Batching {
+    fn default() -> Self {
+        Self {
+            max_batch_size: default_max_batch_size(),
+            max_wait_ms: 0,
+        }
+    }
+}
+
+impl Batching {
+    pub(crate) fn enabled(&self) -> bool {
+        self.max_batch_size > 1
+    }
+}
+
+/// The texts of one caller, and where their vectors go.
+struct Job {
+    texts: Vec<String>,
+    reply: oneshot::Sender<Result<Vec<Vec<f32>>, String>>,
+}
+
+/// Runs an embedder on a thread of its own, which takes queued texts in
+/// batches. The thread stops once the batcher is dropped.
+pub(crate) struct Batcher {
+    jobs: Sender<Job>,
+}
+
+impl Batcher {
+    pub(crate) fn new(embedder: Arc<dyn Embedder>, config: &Batching) -> Self {
+        let (jobs, queue) = mpsc::channel();
+        let max_batch_size = config.max_batch_size.max(1);
+        let max_wait = Duration::from_millis(config.max_wait_ms);
+        thread::Builder::new()
+            .name("embedding-batcher".to_string())
+            .spawn(move || run(embedder.as_ref(), queue, max_batch_size, max_wait))
+            .expect("failed to spawn the embedding batcher");
+        Self { jobs }
+    }
+
+    /// Queues `texts` and waits for their vectors without blocking the
+    /// caller's thread, so other requests on it can join the batch.
+    pub(crate) async fn embed(
+        &self,
+        texts: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>, fastembed::Error> {
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+        let stopped = || fastembed::Error::msg("The embedding batcher stopped");
+        let (reply, vectors) = oneshot::channel();
+        self.jobs
+            .send(Job { texts, reply })
+            .map_err(|_| stopped())?;
+        vectors
+            .await
+            .map_err(|_| stopped())?
+            .map_err(fastembed::Error::msg)
+    }
+}
+
+fn run(embedder: &dyn Embedder, queue: Receiver<Job>, max_batch_size: usize, max_wait: Duration) {
+    while let Ok(first) = queue.recv() {
+        let deadline = Instant::now() + max_wait;
+        let mut size = first.texts.len();
+        let mut batch = vec![first];
+        while size < max_batch_size {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            match queue.recv_timeout(wait) {
+                Ok(job) => {
+                    size += job.texts.len();
+                    batch.push(job);
+                }
+                Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        let counts: Vec<_> = batch.iter().map(|job| job.texts.len()).collect();
+        let texts = batch
+            .iter_mut()
+            .flat_map(|job| std::mem::take(&mut job.texts))
+            .collect();
+        // Callers that gave up waiting no longer take replies, which is fine.
+        match embedder.embed(texts) {
+            Ok(vectors) => {
+                let mut vectors = vectors.into_iter();
+                for (job, count) in batch.into_iter().zip(counts) {
+                    let _ = job.reply.send(Ok(vectors.by_ref().take(count).collect()));
+                }
+            }
+            Err(e) => {
+                let message = e.to_string();
+                for job in batch {
+                    let _ = job.reply.send(Err(message.clone()));
+                }
+            }
+        }
+    }
+}
+
+/// Measures how many texts per second the server embeds when `clients`
+/// threads each send it `requests` searches of a single text, once with each
+/// text embedded on its own and once through a [`Batcher`]. Each run starts
+/// a server on a scratch database, so the texts take the same path through
+/// the HTTP handlers as they would in production.
+pub(crate) fn bench(
+    config: &crate::config::Config,
+    model: Option<&str>,
+    clients: usize,
+    requests: usize,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let registry = ModelRegistry::new(config).map_err(io::Error::other)?;
+    let model = match model {
+        Some(name) => registry
+            .resolve(name)
+            .ok_or_else(|| io::Error::other(format!("Unknown model: {}", name)))?,
+        None => registry.default_model().clone(),
+    };
+    let mut batching = config.embedding.batching.clone();
+    if !batching.enabled() {
+        batching = Batching::default();
+    }
+    let unbatched = Batching {
+        max_batch_size: 1,
+        ..batching.clone()
+    };
+    let scratch = tempfile::tempdir()?;
+
+    writeln!(
+        out,
+        "{} clients sending {} texts each to {}, batched up to {} within {}ms",
+        clients,
+        requests,
+        model.name(),
+        batching.max_batch_size,
+        batching.max_wait_ms
+    )?;
+    writeln!(out, "{:<12} {:>10} {:>10}", "PATH", "SECONDS", "TEXTS/S")?;
+    let mut rates = Vec::new();
+    for (path, batching) in [("per-request", unbatched), ("batched", batching)] {
+        let mut config = config.clone();
+        config.embedding.batching = batching;
+        // Every text would be a cache miss anyway, but storing it isn't free.
+        config.embedding.cache.max_entries = 0;
+        config.database.path = scratch
+            .path()
+            .join(format!("{}.db", path))
+            .to_string_lossy()
+            .into_owned();
+        config.server.host = "127.0.0.1".to_string();
+        config.server.port = 0;
+
+        let model = model.name().to_string();
+        let seconds = actix_web::rt::System::new().block_on(async move {
+            let (server, addr) = crate::web::server(config).await?;
+            let handle = server.handle();
+            actix_web::rt::spawn(server);
+            let url = format!("http://{}", addr);
+            let seconds =
+                actix_web::web::block(move || time_clients(&url, &model, clients, requests))
+                    .await
+                    .map_err(io::Error::other)?;
+            handle.stop(true).await;
+            seconds
+        })?;
+        let rate = (clients * requests) as f64 / seconds;
+        writeln!(out, "{:<12} {:>10.3} {:>10.1}", path, seconds, rate)?;
+        rates.push(rate);
+    }
+    writeln!(out, "Speedup: {:.2}x", rates[1] / rates[0])
+}
+
+/// Creates a collection for `model` on the server at `url`, then times
+/// `clients` threads searching it `requests` times each.
+fn time_clients(url: &str, model: &str, clients: usize, requests: usize) -> io::Result<f64> {
+    let agent = ureq::Agent::new();
+    let failed = |e: ureq::Error| io::Error::other(format!("Benchmark request failed: {}", e));
+    agent
+        .post(&format!("{}/collection", url))
+        .send_json(serde_json::json!({"name": "bench", "model": model}))
+        .map_err(failed)?;
+    let search = format!("{}/collection/bench/search", url);
+    // The first call may be slow while the model warms up.
+    agent
+        .post(&search)
+        .send_json(serde_json::json!({"text": "warm up", "limit": 1}))
+        .map_err(failed)?;
+
+    let start = Instant::now();
+    let handles: Vec<_> = (0..clients)
+        .map(|client| {
+            let (agent, search) = (agent.clone(), search.clone());
+            thread::spawn(move || {
+                for request in 0..requests {
+                    let text = format!(
+                        "Query {} from client {} about embedding throughput",
+                        request, client
+                    );
+                    agent
+                        .post(&search)
+                        .send_json(serde_json::json!({"text": text, "limit": 1}))
+                        .map_err(failed)?;
+                }
+                Ok::<_, io::Error>(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle
+            .join()
+            .map_err(|_| io::Error::other("A benchmark client panicked"))??;
+    }
+    Ok(start.elapsed().as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::{HashEmbedder, HashModel};
+    use std::sync::Mutex;
+
+    /// Records the size of each batch it is handed.
+    struct Recorder {
+        inner: HashEmbedder,
+        batches: Mutex<Vec<usize>>,
+        fail: bool,
+    }
+
+    impl Embedder for Recorder {
+        fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, fastembed::Error> {
+            self.batches.lock().unwrap().push(texts.len());
+            if self.fail {
+                return Err(fastembed::Error::msg("model crashed"));
+            }
+            self.inner.embed(texts)
+        }
+    }
+
+    fn recorder(fail: bool) -> Arc<Recorder> {
+        Arc::new(Recorder {
+            inner: HashEmbedder::new(&HashModel { dimension: 16 }),
+            batches: Mutex::new(Vec::new()),
+            fail,
+        })
+    }
+
+    /// Embeds each text list in a task of its own, all at once on the test's
+    /// single thread, like requests on one actix worker.
+    async fn embed_concurrently(
+        batcher: &Arc<Batcher>,
+        texts: Vec<Vec<&'static str>>,
+    ) -> Vec<Result<Vec<Vec<f32>>, String>> {
+        let handles: Vec<_> = texts
+            .into_iter()
+            .map(|texts| {
+                let batcher = batcher.clone();
+                actix_web::rt::spawn(async move {
+                    batcher
+                        .embed(texts.iter().map(|text| text.to_string()).collect())
+                        .await
+                        .map_err(|e| e.to_string())
+                })
+            })
+            .collect();
+        let mut results = Vec::new();
+        for handle in handles {
+            results.push(handle.await.unwrap());
+        }
+        results
+    }
+
+    #[actix_web::test]
+    async fn test_coalesces_concurrent_callers() {
+        let model = recorder(false);
+        // The wait is long enough that only a full batch ends it.
+        let config = Batching {
+            max_batch_size: 4,
+            max_wait_ms: 10_000,
+        };
+        let batcher = Arc::new(Batcher::new(model.clone(), &config));
+
+        let texts = vec![vec!["rust"], vec!["bread", "recipes"], vec!["safe"]];
+        let results = embed_concurrently(&batcher, texts.clone()).await;
+
+        assert_eq!(*model.batches.lock().unwrap(), vec![4]);
+        for (texts, result) in texts.into_iter().zip(results) {
+            let expected = model
+                .inner
+                .embed(texts.iter().map(|text| text.to_string()).collect())
+                .unwrap();
+            assert_eq!(result.unwrap(), expected);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_batch_limits() {
+        let model = recorder(false);
+        let batcher = Batcher::new(model.clone(), &Batching::default());
+
+        // A lone text doesn't wait for company.
+        let start = Instant::now();
+        batcher.embed(vec!["rust".to_string()]).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // An oversized request goes through whole, and on its own.
+        let config = Batching {
+            max_batch_size: 2,
+            max_wait_ms: 0,
+        };
+        let batcher = Batcher::new(model.clone(), &config);
+        let vectors = batcher
+            .embed(["a", "b", "c"].iter().map(|t| t.to_string()).collect())
+            .await
+            .unwrap();
+        assert_eq!(vectors.len(), 3);
+        assert_eq!(
+            batcher.embed(Vec::new()).await.unwrap(),
+            Vec::<Vec<f32>>::new()
+        );
+        assert_eq!(*model.batches.lock().unwrap(), vec![1, 3]);
+    }
+
+    #[actix_web::test]
+    async fn test_failures_reach_every_caller() {
+        let model = recorder(true);
+        let config = Batching {
+            max_batch_size: 2,
+            max_wait_ms: 10_000,
+        };
+        let batcher = Arc::new(Batcher::new(model.clone(), &config));
+
+        let results = embed_concurrently(&batcher, vec![vec!["rust"], vec!["bread"]]).await;
+        assert_eq!(*model.batches.lock().unwrap(), vec![2]);
+        for result in results {
+            assert_eq!(result.unwrap_err(), "model crashed");
+        }
+    }
+
+    #[test]
+    fn test_bench() {
+        let mut out = Vec::new();
+        bench(&crate::config::Config::hermetic(), None, 2, 3, &mut out).unwrap();
+        let report = String::from_utf8(out).unwrap();
+
+        let lines: Vec<_> = report.lines().collect();
+        assert_eq!(
+            lines[0],
+            "2 clients sending 3 texts each to hash, batched up to 32 within 0ms"
+        );
+        assert!(lines[2].starts_with("per-request "), "{}", report);
+        assert!(lines[3].starts_with("batched "), "{}", report);
+        assert!(lines[4].starts_with("Speedup: "), "{}", report);
+
+        let error = bench(
+            &crate::config::Config::hermetic(),
+            Some("nope"),
+            1,
+            1,
+            &mut Vec::new(),
+        );
+        assert_eq!(error.unwrap_err().to_string(), "Unknown model: nope");
+    }
+}
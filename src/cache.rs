@@ -0,0 +1,258 @@
+//! Embeddings computed before, kept in the database so re-indexing unchanged
+//! texts and repeating a search skip the model.
+//!
+//! Entries are keyed by the model, the length of its vectors and a hash of
+//! its configuration, so a model configured afresh under the same name misses
+//! instead of getting the old model's vectors. The key also takes the
+//! template the text was wrapped in and a SHA-256 hash of the text. Once
+//! there are more than `max_entries`, the least recently used ones are
+//! evicted.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use rusqlite::Connection;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use zerocopy::IntoBytes;
+
+use crate::document::vector_from_blob;
+
+pub(crate) const CACHE_TABLE: &str = "_rusticle_embedding_cache";
+
+/// What an embedding is cached under.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Key {
+    model: String,
+    dimension: usize,
+    /// Hash of how the model is configured, see
+    /// [`crate::embedding::ModelRegistry::fingerprint`].
+    config: [u8; 32],
+    /// The template the text was wrapped in, empty if none.
+    prefix: String,
+    hash: [u8; 32],
+}
+
+impl Key {
+    pub(crate) fn new(
+        model: &str,
+        dimension: usize,
+        config: &str,
+        prefix: &str,
+        text: &str,
+    ) -> Self {
+        Self {
+            model: model.to_string(),
+            dimension,
+            config: Sha256::digest(config.as_bytes()).into(),
+            prefix: prefix.to_string(),
+            hash: Sha256::digest(text.as_bytes()).into(),
+        }
+    }
+}
+
+/// How the cache is configured, and how well it has done since the server
+/// started.
+pub(crate) struct EmbeddingCache {
+    max_entries: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub(crate) struct Stats {
+    pub(crate) entries: i64,
+    pub(crate) max_entries: usize,
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+}
+
+impl EmbeddingCache {
+    pub(crate) fn new(config: &crate::config::Cache) -> Self {
+        Self {
+            max_entries: config.max_entries,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn enabled(&self) -> bool {
+        self.max_entries > 0
+    }
+
+    pub(crate) fn max_entries(&self) -> usize {
+        self.max_entries
+    }
+
+    pub(crate) fn record(&self, hits: usize, misses: usize) {
+        self.hits.fetch_add(hits as u64, Ordering::Relaxed);
+        self.misses.fetch_add(misses as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self, conn: &Connection) -> rusqlite::Result<Stats> {
+        Ok(Stats {
+            entries: len(conn)?,
+            max_entries: self.max_entries,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        })
+    }
+}
+
+pub(crate) fn init(conn: &Connection) -> rusqlite::Result<()> {
+    // `used` orders entries by when they were last looked up or stored,
+    // counting up from the most recent.
+    conn.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS {table} (
+            model TEXT NOT NULL,
+            dimension INTEGER NOT NULL,
+            config BLOB NOT NULL,
+            prefix TEXT NOT NULL,
+            hash BLOB NOT NULL,
+            vector BLOB NOT NULL,
+            used INTEGER NOT NULL,
+            PRIMARY KEY (model, dimension, config, prefix, hash)
+        );
+        CREATE INDEX IF NOT EXISTS {table}_used ON {table} (used);",
+        table = CACHE_TABLE
+    ))
+}
+
+/// Looks up each key, marking the ones found as just used, all at once.
+pub(crate) fn get(conn: &Connection, keys: &[Key]) -> rusqlite::Result<Vec<Option<Vec<f32>>>> {
+    let mut select = conn.prepare_cached(&format!(
+        "SELECT rowid, vector FROM {}
+         WHERE model = ?1 AND dimension = ?2 AND config = ?3 AND prefix = ?4 AND hash = ?5",
+        CACHE_TABLE
+    ))?;
+    let mut found = Vec::new();
+    let vectors = keys
+        .iter()
+        .map(|key| {
+            let params =
+                rusqlite::params![key.model, key.dimension, key.config, key.prefix, key.hash];
+            select
+                .query_row(params, |row| {
+                    found.push(row.get::<_, i64>(0)?);
+                    Ok(Some(vector_from_blob(&row.get::<_, Vec<u8>>(1)?)))
+                })
+                .or_else(|e| match e {
+                    rusqlite::Error::QueryReturnedNoRows => Ok(None),
+                    e => Err(e),
+                })
+        })
+        .collect::<rusqlite::Result<_>>()?;
+    if !found.is_empty() {
+        conn.execute(
+            &format!(
+                "UPDATE {table} SET used = (SELECT max(used) + 1 FROM {table})
+                 WHERE rowid IN (SELECT value FROM json_each(?1))",
+                table = CACHE_TABLE
+            ),
+            [serde_json::to_string(&found).expect("rowids serialize")],
+        )?;
+    }
+    Ok(vectors)
+}
+
+/// Stores embeddings, then evicts the least recently used entries beyond
+/// `max_entries`.
+pub(crate) fn put(
+    conn: &Connection,
+    entries: &[(Key, Vec<f32>)],
+    max_entries: usize,
+) -> rusqlite::Result<()> {
+    let mut insert = conn.prepare_cached(&format!(
+        "INSERT INTO {table} (model, dimension, config, prefix, hash, vector, used)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, (SELECT coalesce(max(used), 0) + 1 FROM {table}))
+         ON CONFLICT (model, dimension, config, prefix, hash)
+         DO UPDATE SET vector = excluded.vector, used = excluded.used",
+        table = CACHE_TABLE
+    ))?;
+    for (key, vector) in entries {
+        insert.execute(rusqlite::params![
+            key.model,
+            key.dimension,
+            key.config,
+            key.prefix,
+            key.hash,
+            vector.as_bytes()
+        ])?;
+    }
+    conn.execute(
+        &format!(
+            "DELETE FROM {table} WHERE used <= (
+                SELECT used FROM {table} ORDER BY used DESC LIMIT 1 OFFSET ?1
+            )",
+            table = CACHE_TABLE
+        ),
+        [max_entries as i64],
+    )?;
+    Ok(())
+}
+
+pub(crate) fn len(conn: &Connection) -> rusqlite::Result<i64> {
+    conn.query_row(
+        &format!("SELECT count(*) FROM {}", CACHE_TABLE),
+        [],
+        |row| row.get(0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_and_put() {
+        let conn = Connection::open_in_memory().unwrap();
+        init(&conn).unwrap();
+
+        let rust = Key::new("hash", 2, "", "", "rust");
+        let query = Key::new("hash", 2, "", "search_query: ", "rust");
+        put(&conn, &[(rust.clone(), vec![1.0, 2.0])], 10).unwrap();
+
+        assert_eq!(
+            get(&conn, &[rust.clone(), query.clone()]).unwrap(),
+            vec![Some(vec![1.0, 2.0]), None]
+        );
+        assert_eq!(
+            get(&conn, &[Key::new("minilm", 2, "", "", "rust")]).unwrap(),
+            vec![None]
+        );
+        // The same model reconfigured doesn't get the old model's vectors.
+        assert_eq!(
+            get(
+                &conn,
+                &[
+                    Key::new("hash", 3, "", "", "rust"),
+                    Key::new("hash", 2, "remote http://localhost/", "", "rust")
+                ]
+            )
+            .unwrap(),
+            vec![None, None]
+        );
+
+        put(&conn, &[(rust.clone(), vec![3.0, 4.0])], 10).unwrap();
+        assert_eq!(get(&conn, &[rust]).unwrap(), vec![Some(vec![3.0, 4.0])]);
+        assert_eq!(len(&conn).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let conn = Connection::open_in_memory().unwrap();
+        init(&conn).unwrap();
+        let key = |text| Key::new("hash", 1, "", "", text);
+
+        put(&conn, &[(key("a"), vec![1.0]), (key("b"), vec![2.0])], 3).unwrap();
+        put(&conn, &[(key("c"), vec![3.0])], 3).unwrap();
+        // Looking `a` up makes `b` the least recently used.
+        get(&conn, &[key("a")]).unwrap();
+        put(&conn, &[(key("d"), vec![4.0])], 3).unwrap();
+
+        assert_eq!(len(&conn).unwrap(), 3);
+        assert_eq!(
+            get(&conn, &[key("a"), key("b"), key("c"), key("d")]).unwrap(),
+            vec![Some(vec![1.0]), None, Some(vec![3.0]), Some(vec![4.0])]
+        );
+    }
+}
@@ -0,0 +1,304 @@
+//! Ingest-time splitting of long documents into overlapping chunks.
+//!
+//! A collection created with chunking stores one vector per chunk instead of
+//! one per document, so long posts are embedded piece by piece rather than
+//! truncated at the model's token limit. Chunks are slices of the original
+//! text, so a chunk returned as a search snippet reads exactly as written.
+
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Strategy {
+    /// Runs of `size` whitespace-separated words.
+    #[default]
+    Words,
+    /// Runs of `size` of the collection model's tokens, as its tokenizer
+    /// splits the text, so a chunk fits the model's token limit exactly.
+    /// Templates and the model's special tokens count against the limit too.
+    Tokens,
+    /// Runs of `size` sentences.
+    Sentences,
+    /// One chunk per markdown section. Sections longer than `size` words
+    /// are split further as with `words`.
+    Headings,
+}
+
+/// How a collection splits documents, as given when it is created.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Chunking {
+    #[serde(default)]
+    pub(crate) strategy: Strategy,
+    /// Length of a chunk, in words, tokens or sentences depending on the
+    /// strategy.
+    pub(crate) size: usize,
+    /// How much of the previous chunk each chunk repeats, in the same unit.
+    #[serde(default)]
+    pub(crate) overlap: usize,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum InvalidChunking {
+    ZeroSize,
+    OverlapTooLarge,
+}
+
+impl Chunking {
+    pub(crate) fn validate(&self) -> Result<(), InvalidChunking> {
+        if self.size == 0 {
+            Err(InvalidChunking::ZeroSize)
+        } else if self.overlap >= self.size {
+            Err(InvalidChunking::OverlapTooLarge)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Splits `text` into chunks. `tokens` are the byte spans of the model's
+    /// tokens in `text`, which only the `tokens` strategy reads. Always
+    /// returns at least one chunk, so even an empty document is stored with a
+    /// vector.
+    pub(crate) fn split<'a>(&self, text: &'a str, tokens: &[(usize, usize)]) -> Vec<&'a str> {
+        let spans = match self.strategy {
+            Strategy::Words => windows(&words(text, 0, text.len()), self.size, self.overlap),
+            Strategy::Tokens => windows(tokens, self.size, self.overlap),
+            Strategy::Sentences => windows(&sentences(text), self.size, self.overlap),
+            Strategy::Headings => sections(text)
+                .into_iter()
+                .flat_map(|(start, end)| windows(&words(text, start, end), self.size, self.overlap))
+                .collect(),
+        };
+        if spans.is_empty() {
+            return vec![text];
+        }
+        spans
+            .into_iter()
+            .map(|(start, end)| &text[start..end])
+            .collect()
+    }
+}
+
+/// Byte spans of the whitespace-separated words in `text[start..end]`.
+fn words(text: &str, start: usize, end: usize) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut word_start = None;
+    for (i, c) in text[start..end].char_indices() {
+        match (c.is_whitespace(), word_start) {
+            (false, None) => word_start = Some(start + i),
+            (true, Some(s)) => {
+                spans.push((s, start + i));
+                word_start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = word_start {
+        spans.push((s, end));
+    }
+    spans
+}
+
+/// Byte spans of the sentences in `text`. A sentence ends at `.`, `!` or `?`
+/// followed by whitespace, or at a blank line, so headings and list items
+/// without punctuation don't run into the next paragraph.
+fn sentences(text: &str) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut start = None;
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let next = chars.peek().map(|&(_, next)| next);
+        let sentence_start = match start {
+            Some(s) => s,
+            None if c.is_whitespace() => continue,
+            None => *start.insert(i),
+        };
+        let end = match c {
+            '.' | '!' | '?' if next.map_or(true, char::is_whitespace) => i + c.len_utf8(),
+            '\n' if next == Some('\n') => i,
+            _ => continue,
+        };
+        spans.push((sentence_start, trim_end(text, sentence_start, end)));
+        start = None;
+    }
+    if let Some(s) = start {
+        spans.push((s, trim_end(text, s, text.len())));
+    }
+    spans
+}
+
+/// Byte spans of the markdown sections in `text`, each starting at its
+/// heading. Lines inside fenced code blocks are never taken for headings.
+fn sections(text: &str) -> Vec<(usize, usize)> {
+    let mut starts = vec![0];
+    let mut in_fence = false;
+    let mut offset = 0;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+        } else if !in_fence && offset > 0 && is_heading(line) {
+            starts.push(offset);
+        }
+        offset += line.len();
+    }
+    starts.push(text.len());
+    starts.windows(2).map(|w| (w[0], w[1])).collect()
+}
+
+/// Whether `line` is an ATX heading such as `## Setup`.
+fn is_heading(line: &str) -> bool {
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    let rest = &line[indent..];
+    let level = rest.len() - rest.trim_start_matches('#').len();
+    indent <= 3
+        && (1..=6).contains(&level)
+        && rest[level..]
+            .chars()
+            .next()
+            .map_or(true, char::is_whitespace)
+}
+
+/// Groups `units` into runs of `size`, each starting `size - overlap` units
+/// after the previous one, and returns the byte span each run covers.
+fn windows(units: &[(usize, usize)], size: usize, overlap: usize) -> Vec<(usize, usize)> {
+    let mut spans = Vec::new();
+    let mut first = 0;
+    while first < units.len() {
+        let last = (first + size).min(units.len()) - 1;
+        spans.push((units[first].0, units[last].1));
+        if last + 1 == units.len() {
+            break;
+        }
+        first += size - overlap;
+    }
+    spans
+}
+
+fn trim_end(text: &str, start: usize, end: usize) -> usize {
+    start + text[start..end].trim_end().len()
+}
+
+impl fmt::Display for InvalidChunking {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ZeroSize => write!(f, "chunk size must be at least 1"),
+            Self::OverlapTooLarge => write!(f, "chunk overlap must be smaller than the chunk size"),
+        }
+    }
+}
+
+impl std::error::Error for InvalidChunking {}
+
+/// Stored in the catalog as JSON.
+impl ToSql for Chunking {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        serde_json::to_string(self)
+            .map(ToSqlOutput::from)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))
+    }
+}
+
+impl FromSql for Chunking {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        serde_json::from_str(value.as_str()?).map_err(|e| FromSqlError::Other(e.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunking(strategy: Strategy, size: usize, overlap: usize) -> Chunking {
+        Chunking {
+            strategy,
+            size,
+            overlap,
+        }
+    }
+
+    #[test]
+    fn test_words_with_overlap() {
+        let text = "one two  three four\nfive six seven";
+        assert_eq!(
+            chunking(Strategy::Words, 3, 1).split(text, &[]),
+            vec!["one two  three", "three four\nfive", "five six seven"]
+        );
+        assert_eq!(
+            chunking(Strategy::Words, 4, 0).split(text, &[]),
+            vec!["one two  three four", "five six seven"]
+        );
+        assert_eq!(
+            chunking(Strategy::Words, 10, 2).split(text, &[]),
+            vec![text]
+        );
+        assert_eq!(chunking(Strategy::Words, 3, 0).split("  ", &[]), vec!["  "]);
+    }
+
+    #[test]
+    fn test_tokens_with_overlap() {
+        let text = "unbelievable results";
+        let tokens = [(0, 2), (2, 6), (6, 12), (13, 20)];
+        assert_eq!(
+            chunking(Strategy::Tokens, 2, 1).split(text, &tokens),
+            vec!["unbeli", "believable", "evable results"]
+        );
+        assert_eq!(chunking(Strategy::Tokens, 3, 0).split("", &[]), vec![""]);
+    }
+
+    #[test]
+    fn test_sentences() {
+        let text = "Rust is fast. Is it safe? Yes!\n\nA heading\n\nVersion 1.5 shipped.";
+        assert_eq!(
+            chunking(Strategy::Sentences, 1, 0).split(text, &[]),
+            vec![
+                "Rust is fast.",
+                "Is it safe?",
+                "Yes!",
+                "A heading",
+                "Version 1.5 shipped."
+            ]
+        );
+        assert_eq!(
+            chunking(Strategy::Sentences, 2, 1).split(text, &[]),
+            vec![
+                "Rust is fast. Is it safe?",
+                "Is it safe? Yes!",
+                "Yes!\n\nA heading",
+                "A heading\n\nVersion 1.5 shipped."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_headings() {
+        let text = "Intro text.\n# Setup\nInstall it.\n```sh\n# not a heading\n```\n\
+                    ## Usage\none two three four five\n#hashtag\n";
+        assert_eq!(
+            chunking(Strategy::Headings, 100, 0).split(text, &[]),
+            vec![
+                "Intro text.",
+                "# Setup\nInstall it.\n```sh\n# not a heading\n```",
+                "## Usage\none two three four five\n#hashtag"
+            ]
+        );
+        assert_eq!(
+            chunking(Strategy::Headings, 4, 1).split("# A\none two three four five", &[]),
+            vec!["# A\none two", "two three four five"]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(chunking(Strategy::Words, 3, 2).validate(), Ok(()));
+        assert_eq!(
+            chunking(Strategy::Words, 0, 0).validate(),
+            Err(InvalidChunking::ZeroSize)
+        );
+        assert_eq!(
+            chunking(Strategy::Sentences, 2, 2).validate(),
+            Err(InvalidChunking::OverlapTooLarge)
+        );
+    }
+}
@@ -0,0 +1,718 @@
+use rusqlite::{Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+use crate::chunk::Chunking;
+use crate::embedding::Templates;
+use crate::metric::Metric;
+use crate::storage::Storage;
+
+/// Internal table recording every collection and how it was created.
+pub(crate) const CATALOG_TABLE: &str = "_rusticle_collections";
+
+/// Prefix reserved for rusticle's own tables.
+const INTERNAL_PREFIX: &str = "_rusticle";
+
+const MAX_NAME_LEN: usize = 64;
+
+/// Suffixes of the shadow tables sqlite-vec creates next to a `vec0` table,
+/// the last three followed by a number. A collection named like one could
+/// clash with another collection's.
+const SHADOW_SUFFIXES: &[&str] = &[
+    "_chunks",
+    "_rowids",
+    "_info",
+    "_auxiliary",
+    "_vector_chunks",
+    "_metadatachunks",
+    "_metadatatext",
+];
+
+/// A collection name checked against the identifier grammar: an ASCII letter
+/// followed by up to 63 ASCII letters, digits, `_` or `-`. Names are always
+/// quoted when they are put into SQL.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub(crate) struct CollectionName(String);
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum InvalidCollectionName {
+    Empty,
+    TooLong,
+    BadStart(char),
+    BadCharacter(char),
+    Reserved,
+    ShadowSuffix(&'static str),
+}
+
+impl CollectionName {
+    pub(crate) fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The name as a quoted SQL identifier.
+    pub(crate) fn quoted(&self) -> String {
+        quote_identifier(&self.0)
+    }
+
+    /// Quoted name of the side table holding each row's document id and
+    /// metadata. Keeping these out of the `vec0` table means large payloads
+    /// don't slow down the KNN scan.
+    pub(crate) fn docs_table(&self) -> String {
+        quote_identifier(&self.side_name("docs"))
+    }
+
+    /// Quoted name of the index finding a document's chunks in the docs
+    /// table.
+    fn parent_index(&self) -> String {
+        quote_identifier(&self.side_name("parent"))
+    }
+
+    /// Quoted name of the optional FTS5 table used for keyword search.
+    pub(crate) fn fts_table(&self) -> String {
+        quote_identifier(&self.side_name("fts"))
+    }
+
+    /// Quoted name of the optional inverted index of sparse vectors, see
+    /// [`crate::sparse`].
+    pub(crate) fn sparse_table(&self) -> String {
+        quote_identifier(&self.side_name("sparse"))
+    }
+
+    /// Quoted name of the table keeping the float32 originals of a quantized
+    /// collection's vectors, see [`crate::storage`].
+    pub(crate) fn originals_table(&self) -> String {
+        quote_identifier(&self.side_name("originals"))
+    }
+
+    /// Quoted name of the index finding a row's entries in the sparse table.
+    pub(crate) fn sparse_row_index(&self) -> String {
+        quote_identifier(&self.side_name("sparserow"))
+    }
+
+    /// Name of one of the collection's side tables or indexes. The name is
+    /// set off by `:`, which collection names can't contain, so neither a
+    /// name nor the shadow tables SQLite adds after it, like FTS5's `_data`,
+    /// can spell another collection's.
+    fn side_name(&self, kind: &str) -> String {
+        format!("{}{}", self.side_prefix(), kind)
+    }
+
+    fn side_prefix(&self) -> String {
+        format!("{}:{}:", INTERNAL_PREFIX, self.0)
+    }
+}
+
+impl FromStr for CollectionName {
+    type Err = InvalidCollectionName;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        let lower = name.to_ascii_lowercase();
+        if lower.starts_with(INTERNAL_PREFIX) || lower.starts_with("sqlite_") {
+            return Err(InvalidCollectionName::Reserved);
+        }
+
+        let mut chars = name.chars();
+        match chars.next() {
+            None => return Err(InvalidCollectionName::Empty),
+            Some(c) if !c.is_ascii_alphabetic() => return Err(InvalidCollectionName::BadStart(c)),
+            Some(_) => {}
+        }
+        if let Some(c) = chars.find(|c| !(c.is_ascii_alphanumeric() || *c == '_' || *c == '-')) {
+            return Err(InvalidCollectionName::BadCharacter(c));
+        }
+        if name.len() > MAX_NAME_LEN {
+            return Err(InvalidCollectionName::TooLong);
+        }
+        let unnumbered = lower.trim_end_matches(|c: char| c.is_ascii_digit());
+        if let Some(suffix) = SHADOW_SUFFIXES
+            .iter()
+            .find(|suffix| unnumbered.ends_with(*suffix))
+        {
+            return Err(InvalidCollectionName::ShadowSuffix(suffix));
+        }
+
+        Ok(Self(name.to_string()))
+    }
+}
+
+impl TryFrom<String> for CollectionName {
+    type Error = InvalidCollectionName;
+
+    fn try_from(name: String) -> Result<Self, Self::Error> {
+        name.parse()
+    }
+}
+
+impl From<CollectionName> for String {
+    fn from(name: CollectionName) -> Self {
+        name.0
+    }
+}
+
+impl fmt::Display for CollectionName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl fmt::Display for InvalidCollectionName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "collection name must not be empty"),
+            Self::TooLong => write!(
+                f,
+                "collection name must be at most {} characters",
+                MAX_NAME_LEN
+            ),
+            Self::BadStart(c) => {
+                write!(f, "collection name must start with a letter, found {:?}", c)
+            }
+            Self::BadCharacter(c) => write!(
+                f,
+                "collection name may only contain letters, digits, '_' and '-', found {:?}",
+                c
+            ),
+            Self::Reserved => write!(
+                f,
+                "collection names starting with '{}' or 'sqlite_' are reserved",
+                INTERNAL_PREFIX
+            ),
+            Self::ShadowSuffix(suffix) => write!(
+                f,
+                "collection names ending in '{}' are reserved for sqlite-vec's tables",
+                suffix
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InvalidCollectionName {}
+
+impl rusqlite::types::FromSql for CollectionName {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: InvalidCollectionName| rusqlite::types::FromSqlError::Other(e.into()))
+    }
+}
+
+/// Quotes an SQL identifier, doubling any embedded quotes.
+pub(crate) fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/// The settings a collection was created with, as recorded in the catalog.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Schema {
+    pub(crate) dimension: usize,
+    pub(crate) metric: Metric,
+    pub(crate) model: String,
+    /// Whether the source text is also kept in a full-text index.
+    pub(crate) keyword_index: bool,
+    /// How documents are split before embedding, if they are.
+    pub(crate) chunking: Option<Chunking>,
+    /// Query and passage templates replacing the model's own.
+    pub(crate) templates: Option<Templates>,
+    /// Model whose sparse vectors are kept alongside the dense ones, if any.
+    pub(crate) sparse_model: Option<String>,
+    /// Model images are embedded with, in collections of images. Texts are
+    /// embedded with `model`, its paired text encoder.
+    pub(crate) image_model: Option<String>,
+    /// How the vectors are quantized on disk, if they are.
+    pub(crate) storage: Storage,
+}
+
+/// A vector whose length doesn't match the collection it is meant for.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct DimensionMismatch {
+    pub(crate) expected: usize,
+    pub(crate) found: usize,
+}
+
+impl Schema {
+    pub(crate) fn check_dimension(&self, vector: &[f32]) -> Result<(), DimensionMismatch> {
+        if vector.len() == self.dimension {
+            Ok(())
+        } else {
+            Err(DimensionMismatch {
+                expected: self.dimension,
+                found: vector.len(),
+            })
+        }
+    }
+}
+
+impl fmt::Display for DimensionMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "vector has {} dimensions but the collection expects {}",
+            self.found, self.expected
+        )
+    }
+}
+
+impl std::error::Error for DimensionMismatch {}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct CollectionInfo {
+    pub(crate) name: CollectionName,
+    pub(crate) dimension: usize,
+    pub(crate) metric: Metric,
+    pub(crate) model: String,
+    pub(crate) keyword_index: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) chunking: Option<Chunking>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) templates: Option<Templates>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) sparse_model: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) image_model: Option<String>,
+    pub(crate) storage: Storage,
+    /// Number of documents, however many chunks they were split into.
+    pub(crate) count: i64,
+    pub(crate) created_at: String,
+    pub(crate) updated_at: String,
+}
+
+pub(crate) fn init_catalog(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute(
+        &format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                name TEXT PRIMARY KEY COLLATE NOCASE,
+                dimension INTEGER NOT NULL,
+                metric TEXT NOT NULL,
+                model TEXT NOT NULL,
+                keyword_index INTEGER NOT NULL DEFAULT 0,
+                chunking TEXT,
+                templates TEXT,
+                sparse_model TEXT,
+                image_model TEXT,
+                storage TEXT NOT NULL DEFAULT 'float32',
+                created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+                updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+            )",
+            CATALOG_TABLE
+        ),
+        (),
+    )?;
+    crate::cache::init(conn)
+}
+
+/// Indexes the docs table by parent so a document's chunks are found without
+/// a scan.
+fn create_parent_index(conn: &Connection, name: &CollectionName) -> rusqlite::Result<()> {
+    conn.execute(
+        &format!(
+            "CREATE INDEX IF NOT EXISTS {} ON {} (parent)",
+            name.parent_index(),
+            name.docs_table()
+        ),
+        (),
+    )?;
+    Ok(())
+}
+
+/// Creates the `vec0` table and side tables backing a collection and records it
+/// in the catalog.
+pub(crate) fn create(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        &format!(
+            "CREATE VIRTUAL TABLE {} using vec0(key TEXT, {});",
+            name.quoted(),
+            schema.storage.column(schema.dimension, schema.metric)
+        ),
+        (),
+    )?;
+    conn.execute(
+        &format!(
+            "CREATE TABLE {} (
+                rowid INTEGER PRIMARY KEY,
+                id TEXT UNIQUE,
+                metadata TEXT,
+                parent INTEGER,
+                text TEXT
+            )",
+            name.docs_table()
+        ),
+        (),
+    )?;
+    create_parent_index(conn, name)?;
+    if schema.keyword_index {
+        crate::keyword::create(conn, name)?;
+    }
+    if schema.sparse_model.is_some() {
+        crate::sparse::create(conn, name)?;
+    }
+    if schema.storage.quantized() {
+        crate::storage::create(conn, name)?;
+    }
+    register(conn, name, schema)
+}
+
+/// Drops every table backing a collection and removes it from the catalog.
+pub(crate) fn remove(conn: &Connection, name: &CollectionName) -> rusqlite::Result<()> {
+    conn.execute(&format!("DROP TABLE IF EXISTS {}", name.quoted()), ())?;
+    conn.execute(&format!("DROP TABLE IF EXISTS {}", name.docs_table()), ())?;
+    conn.execute(&format!("DROP TABLE IF EXISTS {}", name.fts_table()), ())?;
+    conn.execute(&format!("DROP TABLE IF EXISTS {}", name.sparse_table()), ())?;
+    conn.execute(
+        &format!("DROP TABLE IF EXISTS {}", name.originals_table()),
+        (),
+    )?;
+    unregister(conn, name)
+}
+
+fn register(conn: &Connection, name: &CollectionName, schema: &Schema) -> rusqlite::Result<()> {
+    conn.execute(
+        &format!(
+            "INSERT INTO {} (name, dimension, metric, model, keyword_index, chunking, templates,
+                sparse_model, image_model, storage)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            CATALOG_TABLE
+        ),
+        rusqlite::params![
+            name.as_str(),
+            schema.dimension,
+            schema.metric,
+            schema.model,
+            schema.keyword_index,
+            schema.chunking,
+            schema.templates,
+            schema.sparse_model,
+            schema.image_model,
+            schema.storage
+        ],
+    )?;
+    Ok(())
+}
+
+fn unregister(conn: &Connection, name: &CollectionName) -> rusqlite::Result<()> {
+    conn.execute(
+        &format!("DELETE FROM {} WHERE name = ?1", CATALOG_TABLE),
+        [name.as_str()],
+    )?;
+    Ok(())
+}
+
+/// Reads how a collection was created, or `None` if it isn't in the catalog.
+pub(crate) fn schema(conn: &Connection, name: &CollectionName) -> rusqlite::Result<Option<Schema>> {
+    conn.query_row(
+        &format!(
+            "SELECT dimension, metric, model, keyword_index, chunking, templates, sparse_model,
+                image_model, storage
+            FROM {} WHERE name = ?1",
+            CATALOG_TABLE
+        ),
+        [name.as_str()],
+        |row| {
+            Ok(Schema {
+                dimension: row.get(0)?,
+                metric: row.get(1)?,
+                model: row.get(2)?,
+                keyword_index: row.get(3)?,
+                chunking: row.get(4)?,
+                templates: row.get(5)?,
+                sparse_model: row.get(6)?,
+                image_model: row.get(7)?,
+                storage: row.get(8)?,
+            })
+        },
+    )
+    .optional()
+}
+
+/// Whether `name` is taken, by a collection, by any other table or by side
+/// tables a collection of that name left behind. SQLite table names ignore
+/// case, so neither does this.
+pub(crate) fn exists(conn: &Connection, name: &CollectionName) -> rusqlite::Result<bool> {
+    conn.query_row(
+        &format!(
+            "SELECT EXISTS(SELECT 1 FROM {} WHERE name = ?1)
+                OR EXISTS(SELECT 1 FROM sqlite_master WHERE name = ?1 COLLATE NOCASE
+                    OR substr(name, 1, length(?2)) = ?2 COLLATE NOCASE)",
+            CATALOG_TABLE
+        ),
+        [name.as_str(), &name.side_prefix()],
+        |row| row.get(0),
+    )
+}
+
+/// Bumps `updated_at` after the collection's rows change.
+pub(crate) fn touch(conn: &Connection, name: &CollectionName) -> rusqlite::Result<()> {
+    conn.execute(
+        &format!(
+            "UPDATE {} SET updated_at = CURRENT_TIMESTAMP WHERE name = ?1",
+            CATALOG_TABLE
+        ),
+        [name.as_str()],
+    )?;
+    Ok(())
+}
+
+pub(crate) fn list(conn: &Connection) -> rusqlite::Result<Vec<CollectionInfo>> {
+    let mut stmt = conn.prepare(&format!("SELECT name FROM {} ORDER BY name", CATALOG_TABLE))?;
+    let names = stmt
+        .query_map([], |row| row.get::<_, CollectionName>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    let mut collections = Vec::with_capacity(names.len());
+    for name in names {
+        if let Some(info) = describe(conn, &name)? {
+            collections.push(info);
+        }
+    }
+    Ok(collections)
+}
+
+pub(crate) fn describe(
+    conn: &Connection,
+    name: &CollectionName,
+) -> rusqlite::Result<Option<CollectionInfo>> {
+    let info = conn
+        .query_row(
+            &format!(
+                "SELECT name, dimension, metric, model, keyword_index, chunking, templates,
+                sparse_model, image_model, storage, created_at, updated_at
+                FROM {} WHERE name = ?1",
+                CATALOG_TABLE
+            ),
+            [name.as_str()],
+            |row| {
+                Ok(CollectionInfo {
+                    name: row.get(0)?,
+                    dimension: row.get(1)?,
+                    metric: row.get(2)?,
+                    model: row.get(3)?,
+                    keyword_index: row.get(4)?,
+                    chunking: row.get(5)?,
+                    templates: row.get(6)?,
+                    sparse_model: row.get(7)?,
+                    image_model: row.get(8)?,
+                    storage: row.get(9)?,
+                    count: 0,
+                    created_at: row.get(10)?,
+                    updated_at: row.get(11)?,
+                })
+            },
+        )
+        .optional()?;
+
+    match info {
+        Some(mut info) => {
+            // Chunked collections hold several vectors per document, so their
+            // documents are counted in the docs table instead.
+            let counted = match info.chunking {
+                Some(_) => format!("{} WHERE parent IS NULL", name.docs_table()),
+                None => name.quoted(),
+            };
+            info.count =
+                conn.query_row(&format!("SELECT count(*) FROM {}", counted), [], |row| {
+                    row.get(0)
+                })?;
+            Ok(Some(info))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open() -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        init_catalog(&conn).unwrap();
+        conn
+    }
+
+    fn name(name: &str) -> CollectionName {
+        name.parse().unwrap()
+    }
+
+    fn minilm() -> Schema {
+        Schema {
+            dimension: 384,
+            model: "all-minilm-l6-v2".to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_collection_name_grammar() {
+        assert!("posts".parse::<CollectionName>().is_ok());
+        assert!("blog-posts_2024".parse::<CollectionName>().is_ok());
+
+        assert_eq!(
+            "".parse::<CollectionName>(),
+            Err(InvalidCollectionName::Empty)
+        );
+        assert_eq!(
+            "1posts".parse::<CollectionName>(),
+            Err(InvalidCollectionName::BadStart('1'))
+        );
+        assert_eq!(
+            "x; DROP TABLE y".parse::<CollectionName>(),
+            Err(InvalidCollectionName::BadCharacter(';'))
+        );
+        assert_eq!(
+            "a\"b".parse::<CollectionName>(),
+            Err(InvalidCollectionName::BadCharacter('"'))
+        );
+        assert_eq!(
+            "a".repeat(65).parse::<CollectionName>(),
+            Err(InvalidCollectionName::TooLong)
+        );
+        assert_eq!(
+            CATALOG_TABLE.parse::<CollectionName>(),
+            Err(InvalidCollectionName::Reserved)
+        );
+        assert_eq!(
+            "SQLITE_master".parse::<CollectionName>(),
+            Err(InvalidCollectionName::Reserved)
+        );
+        assert_eq!(
+            "docs_Chunks".parse::<CollectionName>(),
+            Err(InvalidCollectionName::ShadowSuffix("_chunks"))
+        );
+        assert_eq!(
+            "docs_metadatatext00".parse::<CollectionName>(),
+            Err(InvalidCollectionName::ShadowSuffix("_metadatatext"))
+        );
+        assert!("docs_information".parse::<CollectionName>().is_ok());
+        assert!("v2".parse::<CollectionName>().is_ok());
+    }
+
+    #[test]
+    fn test_quote_identifier() {
+        assert_eq!(name("posts").quoted(), "\"posts\"");
+        assert_eq!(quote_identifier("a\"b"), "\"a\"\"b\"");
+    }
+
+    #[test]
+    fn test_register_and_describe() {
+        let conn = open();
+        conn.execute("CREATE TABLE posts (key TEXT)", ()).unwrap();
+        conn.execute("INSERT INTO posts VALUES ('a'), ('b')", ())
+            .unwrap();
+        register(&conn, &name("posts"), &minilm()).unwrap();
+
+        let info = describe(&conn, &name("posts")).unwrap().unwrap();
+        assert_eq!(info.dimension, 384);
+        assert_eq!(info.metric, Metric::Cosine);
+        assert_eq!(info.model, "all-minilm-l6-v2");
+        assert_eq!(info.count, 2);
+
+        let schema = schema(&conn, &name("posts")).unwrap().unwrap();
+        assert_eq!(schema.dimension, 384);
+        assert_eq!(
+            schema.check_dimension(&[0.0; 3]),
+            Err(DimensionMismatch {
+                expected: 384,
+                found: 3
+            })
+        );
+        assert!(schema.check_dimension(&[0.0; 384]).is_ok());
+        assert!(describe(&conn, &name("missing")).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_unregister() {
+        let conn = open();
+        conn.execute("CREATE TABLE posts (key TEXT)", ()).unwrap();
+        register(&conn, &name("posts"), &minilm()).unwrap();
+        assert_eq!(list(&conn).unwrap().len(), 1);
+
+        unregister(&conn, &name("posts")).unwrap();
+        assert!(list(&conn).unwrap().is_empty());
+        assert!(schema(&conn, &name("posts")).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_side_tables_stay_apart() {
+        crate::web::register_sqlite_vec();
+        let conn = open();
+        let schema = Schema {
+            dimension: 2,
+            keyword_index: true,
+            sparse_model: Some("hash".to_string()),
+            ..Default::default()
+        };
+        // FTS5 names its shadow tables after the keyword index, `_data` and
+        // `_idx` among them, and indexes are named after their tables.
+        for collection in ["x", "x_data", "x_idx", "x_parent", "x_docs"] {
+            create(&conn, &name(collection), &schema).unwrap();
+        }
+        remove(&conn, &name("x_data")).unwrap();
+        conn.execute(
+            &format!(
+                "INSERT INTO {} (rowid, text) VALUES (1, 'kept')",
+                name("x").fts_table()
+            ),
+            (),
+        )
+        .unwrap();
+
+        assert!(exists(&conn, &name("X")).unwrap());
+        assert!(!exists(&conn, &name("x_data")).unwrap());
+        // Side tables left without a catalog row still take the name.
+        unregister(&conn, &name("x_idx")).unwrap();
+        assert!(exists(&conn, &name("x_idx")).unwrap());
+    }
+
+    #[test]
+    fn test_init_catalog_keeps_collections() {
+        let conn = open();
+        conn.execute("CREATE TABLE posts (key TEXT)", ()).unwrap();
+        register(&conn, &name("posts"), &minilm()).unwrap();
+
+        init_catalog(&conn).unwrap();
+        let schema = schema(&conn, &name("posts")).unwrap().unwrap();
+        assert_eq!(schema.dimension, 384);
+        assert!(!schema.keyword_index);
+        assert!(schema.chunking.is_none());
+        assert_eq!(schema.storage, Storage::Float32);
+    }
+
+    #[test]
+    fn test_chunking_round_trip() {
+        crate::web::register_sqlite_vec();
+        let conn = open();
+        let chunking = Chunking {
+            strategy: crate::chunk::Strategy::Sentences,
+            size: 3,
+            overlap: 1,
+        };
+        let schema = Schema {
+            dimension: 2,
+            chunking: Some(chunking.clone()),
+            ..Default::default()
+        };
+        create(&conn, &name("posts"), &schema).unwrap();
+
+        let read = super::schema(&conn, &name("posts")).unwrap().unwrap();
+        assert_eq!(read.chunking, Some(chunking));
+        crate::document::insert_chunks(
+            &conn,
+            &name("posts"),
+            &schema,
+            None,
+            "A. B.",
+            &[
+                ("A.".to_string(), vec![1.0, 0.0]),
+                ("B.".to_string(), vec![0.0, 1.0]),
+            ],
+            None,
+        )
+        .unwrap();
+        assert_eq!(describe(&conn, &name("posts")).unwrap().unwrap().count, 1);
+    }
+}
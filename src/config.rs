@@ -1,10 +1,10 @@
-use fastembed;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
-use toml;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, Default)]
 pub(crate) struct Config {
     #[serde(default)]
     pub(crate) server: Server,
@@ -12,6 +12,8 @@ pub(crate) struct Config {
     pub(crate) database: Database,
     #[serde(default)]
     pub(crate) embedding: Embedding,
+    #[serde(default)]
+    pub(crate) rerank: Rerank,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -20,6 +22,9 @@ pub(crate) struct Server {
     pub(crate) host: String,
     #[serde(default = "default_port")]
     pub(crate) port: u16,
+    /// Largest JSON request body accepted, in bytes.
+    #[serde(default = "default_max_body_size")]
+    pub(crate) max_body_size: usize,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -30,8 +35,59 @@ pub(crate) struct Database {
 
 #[derive(Deserialize, Debug, Clone)]
 pub(crate) struct Embedding {
-    #[serde(default = "default_model", deserialize_with = "deserialize_model")]
-    pub(crate) model: fastembed::EmbeddingModel,
+    /// Name of the default model: one of fastembed's, or one of `local`,
+    /// `remote` or `hash`.
+    #[serde(default = "default_model")]
+    pub(crate) model: String,
+    /// Number of texts handed to the model at once.
+    #[serde(default = "default_batch_size")]
+    pub(crate) batch_size: usize,
+    /// Query and passage templates by model name, replacing the built-in
+    /// ones, see [`crate::embedding::Templates`].
+    #[serde(default)]
+    pub(crate) templates: HashMap<String, crate::embedding::Templates>,
+    /// ONNX models loaded from disk by name, see [`crate::embedding::LocalModel`].
+    #[serde(default)]
+    pub(crate) local: HashMap<String, crate::embedding::LocalModel>,
+    /// Models served over HTTP by name, see [`crate::remote::RemoteModel`].
+    #[serde(default)]
+    pub(crate) remote: HashMap<String, crate::remote::RemoteModel>,
+    /// Word-hashing stand-ins for a model by name, see
+    /// [`crate::hash::HashModel`].
+    #[serde(default)]
+    pub(crate) hash: HashMap<String, crate::hash::HashModel>,
+    /// Where downloaded model files are kept.
+    #[serde(default = "default_cache_dir")]
+    pub(crate) cache_dir: PathBuf,
+    /// Never download models. The server refuses to start unless its model is
+    /// already in `cache_dir`, see `rusticle models download`.
+    #[serde(default)]
+    pub(crate) offline: bool,
+    /// Embeddings kept in the database for reuse, see [`crate::cache`].
+    #[serde(default)]
+    pub(crate) cache: Cache,
+    /// How texts from concurrent requests are embedded together, see
+    /// [`crate::batch::Batching`].
+    #[serde(default)]
+    pub(crate) batching: crate::batch::Batching,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Cache {
+    /// Most embeddings kept before the least recently used are evicted. 0
+    /// turns the cache off.
+    #[serde(default = "default_cache_entries")]
+    pub(crate) max_entries: usize,
+}
+
+/// Cross-encoder used when a search asks for its results to be reranked.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct Rerank {
+    #[serde(
+        default = "default_reranker",
+        deserialize_with = "deserialize_reranker"
+    )]
+    pub(crate) model: fastembed::RerankerModel,
 }
 
 fn default_host() -> String {
@@ -42,31 +98,202 @@ fn default_port() -> u16 {
     8080
 }
 
+fn default_max_body_size() -> usize {
+    16 * 1024 * 1024
+}
+
 fn default_db_path() -> String {
     "./data".to_string()
 }
 
-fn default_model() -> fastembed::EmbeddingModel {
-    fastembed::EmbeddingModel::NomicEmbedTextV15Q
+fn default_model() -> String {
+    "nomic-embed-text-v1.5-q".to_string()
 }
 
-fn deserialize_model<'de, D>(deserializer: D) -> Result<fastembed::EmbeddingModel, D::Error>
+fn default_cache_dir() -> PathBuf {
+    PathBuf::from(fastembed::DEFAULT_CACHE_DIR)
+}
+
+fn default_cache_entries() -> usize {
+    100_000
+}
+
+fn default_batch_size() -> usize {
+    256
+}
+
+fn default_reranker() -> fastembed::RerankerModel {
+    fastembed::RerankerModel::BGERerankerBase
+}
+
+/// Model names accepted in config files, paired with the fastembed model they
+/// load. Generated from fastembed's catalogue, so every model it supports can
+/// be named.
+static MODELS: LazyLock<Vec<(String, fastembed::EmbeddingModel)>> = LazyLock::new(|| {
+    fastembed::TextEmbedding::list_supported_models()
+        .into_iter()
+        .map(|info| (catalogue_name(&info), info.model))
+        .collect()
+});
+
+/// Names kept for models that were once accepted under them.
+const ALIASES: &[(&str, fastembed::EmbeddingModel)] = &[
+    ("bge-base-en", fastembed::EmbeddingModel::BGEBaseENV15),
+    ("bge-small-en", fastembed::EmbeddingModel::BGESmallENV15),
+];
+
+/// Derives a model's name from its Hugging Face repository, such as
+/// `bge-small-en-v1.5` for `Xenova/bge-small-en-v1.5`. Quantized models share
+/// a repository with their full-size version or mark it in their own way, so
+/// they are told apart with a `-q` suffix.
+fn catalogue_name(info: &fastembed::ModelInfo<fastembed::EmbeddingModel>) -> String {
+    let repository = info.model_code.rsplit('/').next().unwrap_or_default();
+    let name = repository.to_lowercase();
+    let name = name
+        .strip_suffix("-onnx-q")
+        .or_else(|| name.strip_suffix("-onnx"))
+        .unwrap_or(&name);
+    if format!("{:?}", info.model).ends_with('Q') {
+        format!("{}-q", name)
+    } else {
+        name.to_string()
+    }
+}
+
+/// Looks up one of fastembed's models by its config name.
+pub(crate) fn model(name: &str) -> Option<fastembed::EmbeddingModel> {
+    let name = name.to_lowercase();
+    MODELS
+        .iter()
+        .map(|(known, model)| (known.as_str(), model))
+        .chain(ALIASES.iter().map(|(known, model)| (*known, model)))
+        .find(|(known, _)| *known == name)
+        .map(|(_, model)| model.clone())
+}
+
+/// Reranker names accepted in config files and search requests.
+const RERANKERS: &[(&str, fastembed::RerankerModel)] = &[
+    (
+        "bge-reranker-base",
+        fastembed::RerankerModel::BGERerankerBase,
+    ),
+    (
+        "bge-reranker-v2-m3",
+        fastembed::RerankerModel::BGERerankerV2M3,
+    ),
+    (
+        "jina-reranker-v1-turbo-en",
+        fastembed::RerankerModel::JINARerankerV1TurboEn,
+    ),
+    (
+        "jina-reranker-v2-base-multilingual",
+        fastembed::RerankerModel::JINARerankerV2BaseMultiligual,
+    ),
+];
+
+fn deserialize_reranker<'de, D>(deserializer: D) -> Result<fastembed::RerankerModel, D::Error>
 where
     D: serde::Deserializer<'de>,
 {
     let model_str = String::deserialize(deserializer)?;
-    match model_str.to_lowercase().as_str() {
-        "all-minilm-l6-v2" => Ok(fastembed::EmbeddingModel::AllMiniLML6V2),
-        "bge-small-en" => Ok(fastembed::EmbeddingModel::BGEBaseENV15),
-        "bge-small-en-v1.5" => Ok(fastembed::EmbeddingModel::BGESmallENV15),
-        "bge-base-en" => Ok(fastembed::EmbeddingModel::BGEBaseENV15),
-        "nomic-embed-text-v1.5" => Ok(fastembed::EmbeddingModel::NomicEmbedTextV15),
-        "nomic-embed-text-v1.5-q" => Ok(fastembed::EmbeddingModel::NomicEmbedTextV15Q),
-        _ => Err(serde::de::Error::custom(format!(
-            "Unknown model: {}",
-            model_str
-        ))),
-    }
+    reranker(&model_str)
+        .ok_or_else(|| serde::de::Error::custom(format!("Unknown reranker: {}", model_str)))
+}
+
+/// Looks up a reranker by its config name.
+pub(crate) fn reranker(name: &str) -> Option<fastembed::RerankerModel> {
+    RERANKERS
+        .iter()
+        .find(|(known, _)| *known == name.to_lowercase())
+        .map(|(_, model)| model.clone())
+}
+
+/// Returns the config name for a reranker.
+pub(crate) fn reranker_name(model: &fastembed::RerankerModel) -> &'static str {
+    RERANKERS
+        .iter()
+        .find(|(_, m)| m == model)
+        .map(|(name, _)| *name)
+        .unwrap_or("unknown")
+}
+
+/// Returns the config name for a model, as it would be written in `config.toml`.
+pub(crate) fn model_name(model: &fastembed::EmbeddingModel) -> &'static str {
+    MODELS
+        .iter()
+        .find(|(_, m)| m == model)
+        .map(|(name, _)| name.as_str())
+        .unwrap_or("unknown")
+}
+
+/// Sparse model names paired with the repository fastembed downloads each
+/// from, such as `splade-pp-en-v1` for `Qdrant/Splade_PP_en_v1`. fastembed
+/// doesn't export its sparse model type, so they are told apart by name.
+static SPARSE_MODELS: LazyLock<Vec<(String, String)>> = LazyLock::new(|| {
+    fastembed::SparseTextEmbedding::list_supported_models()
+        .into_iter()
+        .map(|info| {
+            let repository = info.model_code.rsplit('/').next().unwrap_or_default();
+            (repository.to_lowercase().replace('_', "-"), info.model_code)
+        })
+        .collect()
+});
+
+/// Looks up one of fastembed's sparse models by its config name, returning
+/// its name as it is spelled in the catalogue.
+pub(crate) fn sparse_model(name: &str) -> Option<&'static str> {
+    let name = name.to_lowercase();
+    SPARSE_MODELS
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(known, _)| known.as_str())
+}
+
+/// Every sparse model fastembed supports, by name.
+pub(crate) fn sparse_models() -> impl Iterator<Item = &'static str> {
+    SPARSE_MODELS.iter().map(|(name, _)| name.as_str())
+}
+
+/// The repository a sparse model is downloaded from.
+pub(crate) fn sparse_repository(name: &str) -> Option<&'static str> {
+    SPARSE_MODELS
+        .iter()
+        .find(|(known, _)| known == name)
+        .map(|(_, repository)| repository.as_str())
+}
+
+/// Image model names accepted in collection settings, derived from their
+/// repository like text models, such as `clip-vit-b-32-vision` for
+/// `Qdrant/clip-ViT-B-32-vision`.
+static IMAGE_MODELS: LazyLock<Vec<(String, fastembed::ImageEmbeddingModel)>> =
+    LazyLock::new(|| {
+        fastembed::ImageEmbedding::list_supported_models()
+            .into_iter()
+            .map(|info| {
+                let repository = info.model_code.rsplit('/').next().unwrap_or_default();
+                let name = repository.to_lowercase();
+                let name = name.strip_suffix("-onnx").unwrap_or(&name).to_string();
+                (name, info.model)
+            })
+            .collect()
+    });
+
+/// Looks up one of fastembed's image models by its config name.
+pub(crate) fn image_model(name: &str) -> Option<fastembed::ImageEmbeddingModel> {
+    let name = name.to_lowercase();
+    IMAGE_MODELS
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, model)| model.clone())
+}
+
+/// Returns the config name for an image model.
+pub(crate) fn image_model_name(model: &fastembed::ImageEmbeddingModel) -> &'static str {
+    IMAGE_MODELS
+        .iter()
+        .find(|(_, m)| m == model)
+        .map(|(name, _)| name.as_str())
+        .unwrap_or("unknown")
 }
 
 impl Default for Server {
@@ -74,6 +301,7 @@ impl Default for Server {
         Self {
             host: default_host(),
             port: default_port(),
+            max_body_size: default_max_body_size(),
         }
     }
 }
@@ -90,6 +318,31 @@ impl Default for Embedding {
     fn default() -> Self {
         Self {
             model: default_model(),
+            batch_size: default_batch_size(),
+            templates: HashMap::new(),
+            local: HashMap::new(),
+            remote: HashMap::new(),
+            hash: HashMap::new(),
+            cache_dir: default_cache_dir(),
+            offline: false,
+            cache: Cache::default(),
+            batching: crate::batch::Batching::default(),
+        }
+    }
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self {
+            max_entries: default_cache_entries(),
+        }
+    }
+}
+
+impl Default for Rerank {
+    fn default() -> Self {
+        Self {
+            model: default_reranker(),
         }
     }
 }
@@ -101,14 +354,17 @@ impl Config {
             .and_then(|content| toml::from_str(&content).ok())
             .unwrap_or_default()
     }
-}
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            server: Server::default(),
-            database: Database::default(),
-            embedding: Embedding::default(),
-        }
+    /// The default config with texts embedded by a 768-dimensional hash
+    /// model named `hash`, so tests don't download or run a real one.
+    #[cfg(test)]
+    pub(crate) fn hermetic() -> Self {
+        let mut config = Self::default();
+        config.embedding.model = "hash".to_string();
+        config.embedding.hash.insert(
+            "hash".to_string(),
+            crate::hash::HashModel { dimension: 768 },
+        );
+        config
     }
 }
@@ -0,0 +1,563 @@
+use rusqlite::{Connection, OptionalExtension};
+use zerocopy::IntoBytes;
+
+use crate::collection::{CollectionName, Schema};
+use crate::keyword;
+use crate::sparse;
+use crate::storage;
+
+/// Arbitrary JSON object stored alongside a vector.
+pub(crate) type Metadata = serde_json::Map<String, serde_json::Value>;
+
+/// A stored document as seen through its caller-supplied id.
+pub(crate) struct Document {
+    pub(crate) rowid: i64,
+    pub(crate) text: String,
+}
+
+/// The vectors a document is written with.
+pub(crate) enum Embedded {
+    /// One vector for the whole text, kept under the document's own rowid.
+    Whole(Vec<f32>),
+    /// One vector per chunk, in collections created with chunking. Each chunk
+    /// gets a row of its own pointing back at the document through `parent`.
+    Chunks(Vec<(String, Vec<f32>)>),
+}
+
+fn metadata_value(metadata: Option<&Metadata>) -> serde_json::Value {
+    metadata
+        .map(|m| serde_json::Value::Object(m.clone()))
+        .unwrap_or_default()
+}
+
+/// Looks up the document stored under `id`.
+pub(crate) fn find(
+    conn: &Connection,
+    name: &CollectionName,
+    id: &str,
+) -> rusqlite::Result<Option<Document>> {
+    // Chunked documents keep their full text in the docs table, the others
+    // only as the key of their single vector.
+    conn.query_row(
+        &format!(
+            "SELECT d.rowid, coalesce(d.text, v.key) FROM {} d
+            LEFT JOIN {} v ON v.rowid = d.rowid WHERE d.id = ?1",
+            name.docs_table(),
+            name.quoted()
+        ),
+        [id],
+        |row| {
+            Ok(Document {
+                rowid: row.get(0)?,
+                text: row.get(1)?,
+            })
+        },
+    )
+    .optional()
+}
+
+/// Reads the stored vectors of the document with the given id, one per chunk
+/// for chunked documents, along with its rowid.
+pub(crate) fn find_vectors(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+    id: &str,
+) -> rusqlite::Result<Option<(i64, Vec<Vec<f32>>)>> {
+    let Some(rowid) = find_rowid(conn, name, id)? else {
+        return Ok(None);
+    };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT v.vec FROM {} d JOIN {} v ON v.rowid = d.rowid
+        WHERE d.rowid = ?1 OR d.parent = ?1 ORDER BY d.rowid",
+        name.docs_table(),
+        storage::originals(name, schema.storage)
+    ))?;
+    let vectors = stmt
+        .query_map([rowid], |row| {
+            Ok(vector_from_blob(&row.get::<_, Vec<u8>>(0)?))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    if vectors.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some((rowid, vectors)))
+}
+
+fn find_rowid(conn: &Connection, name: &CollectionName, id: &str) -> rusqlite::Result<Option<i64>> {
+    conn.query_row(
+        &format!("SELECT rowid FROM {} WHERE id = ?1", name.docs_table()),
+        [id],
+        |row| row.get(0),
+    )
+    .optional()
+}
+
+/// Decodes a `float[N]` column as stored by sqlite-vec.
+pub(crate) fn vector_from_blob(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+/// Inserts a new row. Fails with a constraint violation if `id` is already taken.
+pub(crate) fn insert(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+    id: Option<&str>,
+    text: &str,
+    vector: &[f32],
+    metadata: Option<&Metadata>,
+) -> rusqlite::Result<i64> {
+    // The side table hands out rowids so both tables share the same key.
+    conn.execute(
+        &format!(
+            "INSERT INTO {} (id, metadata) VALUES (?1, ?2)",
+            name.docs_table()
+        ),
+        rusqlite::params![id, metadata_value(metadata)],
+    )?;
+    let rowid = conn.last_insert_rowid();
+    write_vector(conn, name, schema, rowid, text, vector)?;
+    keyword::index(conn, name, schema, rowid, text)?;
+    Ok(rowid)
+}
+
+/// Replaces the text and vector of an existing row, keeping its rowid and id.
+pub(crate) fn replace(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+    rowid: i64,
+    text: &str,
+    vector: &[f32],
+) -> rusqlite::Result<()> {
+    conn.execute(
+        &format!("DELETE FROM {} WHERE rowid = ?1", name.quoted()),
+        [rowid],
+    )?;
+    write_vector(conn, name, schema, rowid, text, vector)?;
+    keyword::index(conn, name, schema, rowid, text)?;
+    Ok(())
+}
+
+/// Inserts a chunked document: a docs row holding its id, metadata and full
+/// text, plus one row per chunk. Returns the document's rowid.
+pub(crate) fn insert_chunks(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+    id: Option<&str>,
+    text: &str,
+    chunks: &[(String, Vec<f32>)],
+    metadata: Option<&Metadata>,
+) -> rusqlite::Result<i64> {
+    conn.execute(
+        &format!(
+            "INSERT INTO {} (id, metadata, text) VALUES (?1, ?2, ?3)",
+            name.docs_table()
+        ),
+        rusqlite::params![id, metadata_value(metadata), text],
+    )?;
+    let rowid = conn.last_insert_rowid();
+    write_chunks(conn, name, schema, rowid, chunks)?;
+    Ok(rowid)
+}
+
+/// Replaces the text and chunks of an existing chunked document.
+pub(crate) fn replace_chunks(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+    rowid: i64,
+    text: &str,
+    chunks: &[(String, Vec<f32>)],
+) -> rusqlite::Result<()> {
+    remove_vectors(conn, name, schema, rowid)?;
+    conn.execute(
+        &format!(
+            "UPDATE {} SET text = ?2 WHERE rowid = ?1",
+            name.docs_table()
+        ),
+        rusqlite::params![rowid, text],
+    )?;
+    write_chunks(conn, name, schema, rowid, chunks)
+}
+
+fn write_chunks(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+    parent: i64,
+    chunks: &[(String, Vec<f32>)],
+) -> rusqlite::Result<()> {
+    for (text, vector) in chunks {
+        conn.execute(
+            &format!("INSERT INTO {} (parent) VALUES (?1)", name.docs_table()),
+            [parent],
+        )?;
+        let rowid = conn.last_insert_rowid();
+        write_vector(conn, name, schema, rowid, text, vector)?;
+        keyword::index(conn, name, schema, rowid, text)?;
+    }
+    Ok(())
+}
+
+/// Adds a row to the `vec0` table, quantizing its vector the way the
+/// collection stores them and keeping the original if it is quantized.
+fn write_vector(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+    rowid: i64,
+    key: &str,
+    vector: &[f32],
+) -> rusqlite::Result<()> {
+    let storage = schema.storage;
+    if storage.quantized() {
+        conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO {} (rowid, vec) VALUES (?1, ?2)",
+                name.originals_table()
+            ),
+            rusqlite::params![rowid, vector.as_bytes()],
+        )?;
+    }
+    conn.execute(
+        &format!(
+            "INSERT INTO {} (rowid, key, vec) VALUES (?1, ?2, {})",
+            name.quoted(),
+            storage.param("?3")
+        ),
+        rusqlite::params![rowid, key, storage.encode(vector)],
+    )?;
+    Ok(())
+}
+
+/// Writes a document over the row at `existing`, or inserts it as a new row
+/// when there is none.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn upsert(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+    existing: Option<i64>,
+    id: Option<&str>,
+    text: &str,
+    embedded: &Embedded,
+    metadata: Option<&Metadata>,
+) -> rusqlite::Result<i64> {
+    match (existing, embedded) {
+        (Some(rowid), embedded) => {
+            match embedded {
+                Embedded::Whole(vector) => replace(conn, name, schema, rowid, text, vector)?,
+                Embedded::Chunks(chunks) => {
+                    replace_chunks(conn, name, schema, rowid, text, chunks)?
+                }
+            }
+            set_metadata(conn, name, rowid, metadata)?;
+            Ok(rowid)
+        }
+        (None, Embedded::Whole(vector)) => insert(conn, name, schema, id, text, vector, metadata),
+        (None, Embedded::Chunks(chunks)) => {
+            insert_chunks(conn, name, schema, id, text, chunks, metadata)
+        }
+    }
+}
+
+/// Deletes the document stored under `id` along with all of its chunks.
+/// Returns whether there was such a document.
+pub(crate) fn delete(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+    id: &str,
+) -> rusqlite::Result<bool> {
+    let Some(rowid) = find_rowid(conn, name, id)? else {
+        return Ok(false);
+    };
+    remove_vectors(conn, name, schema, rowid)?;
+    conn.execute(
+        &format!("DELETE FROM {} WHERE rowid = ?1", name.docs_table()),
+        [rowid],
+    )?;
+    Ok(true)
+}
+
+/// Removes every vector stored for the document at `rowid`, whether its own
+/// or its chunks', and their keyword and sparse index entries.
+fn remove_vectors(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+    rowid: i64,
+) -> rusqlite::Result<()> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT rowid FROM {} WHERE parent = ?1",
+        name.docs_table()
+    ))?;
+    let mut rowids = stmt
+        .query_map([rowid], |row| row.get::<_, i64>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    rowids.push(rowid);
+    for rowid in rowids {
+        conn.execute(
+            &format!("DELETE FROM {} WHERE rowid = ?1", name.quoted()),
+            [rowid],
+        )?;
+        if schema.storage.quantized() {
+            conn.execute(
+                &format!("DELETE FROM {} WHERE rowid = ?1", name.originals_table()),
+                [rowid],
+            )?;
+        }
+        keyword::remove(conn, name, schema, rowid)?;
+        sparse::remove(conn, name, schema, rowid)?;
+    }
+    conn.execute(
+        &format!("DELETE FROM {} WHERE parent = ?1", name.docs_table()),
+        [rowid],
+    )?;
+    Ok(())
+}
+
+/// Replaces the metadata of an existing row.
+pub(crate) fn set_metadata(
+    conn: &Connection,
+    name: &CollectionName,
+    rowid: i64,
+    metadata: Option<&Metadata>,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        &format!(
+            "UPDATE {} SET metadata = ?2 WHERE rowid = ?1",
+            name.docs_table()
+        ),
+        rusqlite::params![rowid, metadata_value(metadata)],
+    )?;
+    Ok(())
+}
+
+/// Whether the error came from inserting an id that already exists, as
+/// opposed to breaking any other constraint.
+pub(crate) fn is_duplicate(err: &rusqlite::Error) -> bool {
+    matches!(
+        err,
+        rusqlite::Error::SqliteFailure(e, _) if matches!(
+            e.extended_code,
+            rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE | rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY
+        )
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zerocopy::IntoBytes;
+
+    fn open() -> (Connection, CollectionName, Schema) {
+        crate::web::register_sqlite_vec();
+        let conn = Connection::open_in_memory().unwrap();
+        crate::collection::init_catalog(&conn).unwrap();
+        let name: CollectionName = "posts".parse().unwrap();
+        let schema = Schema {
+            dimension: 2,
+            ..Default::default()
+        };
+        crate::collection::create(&conn, &name, &schema).unwrap();
+        (conn, name, schema)
+    }
+
+    #[test]
+    fn test_insert_and_find() {
+        let (conn, name, schema) = open();
+        let rowid = insert(
+            &conn,
+            &name,
+            &schema,
+            Some("hello-world"),
+            "Hello",
+            &[1.0, 0.0],
+            None,
+        )
+        .unwrap();
+        insert(&conn, &name, &schema, None, "Anonymous", &[0.0, 1.0], None).unwrap();
+        insert(&conn, &name, &schema, None, "Anonymous", &[0.0, 1.0], None).unwrap();
+
+        let doc = find(&conn, &name, "hello-world").unwrap().unwrap();
+        assert_eq!(doc.rowid, rowid);
+        assert_eq!(doc.text, "Hello");
+        assert!(find(&conn, &name, "missing").unwrap().is_none());
+
+        let err = insert(
+            &conn,
+            &name,
+            &schema,
+            Some("hello-world"),
+            "Again",
+            &[1.0, 0.0],
+            None,
+        )
+        .unwrap_err();
+        assert!(is_duplicate(&err));
+
+        conn.execute("CREATE TABLE required (x NOT NULL)", ())
+            .unwrap();
+        let err = conn
+            .execute("INSERT INTO required VALUES (NULL)", ())
+            .unwrap_err();
+        assert!(!is_duplicate(&err));
+    }
+
+    #[test]
+    fn test_replace_keeps_rowid() {
+        let (conn, name, schema) = open();
+        let rowid = insert(
+            &conn,
+            &name,
+            &schema,
+            Some("post"),
+            "Old",
+            &[1.0, 0.0],
+            None,
+        )
+        .unwrap();
+        replace(&conn, &name, &schema, rowid, "New", &[0.0, 1.0]).unwrap();
+
+        let doc = find(&conn, &name, "post").unwrap().unwrap();
+        assert_eq!(doc.rowid, rowid);
+        assert_eq!(doc.text, "New");
+
+        let (count, vector): (i64, Vec<u8>) = conn
+            .query_row("SELECT count(*), vec FROM posts", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(vector, [0.0f32, 1.0].as_bytes());
+
+        let (found_rowid, vectors) = find_vectors(&conn, &name, &schema, "post")
+            .unwrap()
+            .unwrap();
+        assert_eq!(found_rowid, rowid);
+        assert_eq!(vectors, vec![vec![0.0, 1.0]]);
+    }
+
+    #[test]
+    fn test_quantized_keeps_originals() {
+        let (conn, _, _) = open();
+        let name: CollectionName = "quantized".parse().unwrap();
+        let schema = Schema {
+            dimension: 2,
+            storage: crate::storage::Storage::Int8,
+            ..Default::default()
+        };
+        crate::collection::create(&conn, &name, &schema).unwrap();
+        let rowid = insert(
+            &conn,
+            &name,
+            &schema,
+            Some("post"),
+            "Old",
+            &[0.3, 0.7],
+            None,
+        )
+        .unwrap();
+        replace(&conn, &name, &schema, rowid, "New", &[0.123, -0.456]).unwrap();
+
+        let (_, vectors) = find_vectors(&conn, &name, &schema, "post")
+            .unwrap()
+            .unwrap();
+        assert_eq!(vectors, vec![vec![0.123, -0.456]]);
+
+        assert!(delete(&conn, &name, &schema, "post").unwrap());
+        let originals: i64 = conn
+            .query_row(
+                &format!("SELECT count(*) FROM {}", name.originals_table()),
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(originals, 0);
+    }
+
+    #[test]
+    fn test_metadata_round_trip() {
+        let (conn, name, schema) = open();
+        let metadata: Metadata =
+            serde_json::from_str(r#"{"title": "Hello", "tags": ["rust"], "views": 3}"#).unwrap();
+        let rowid = insert(
+            &conn,
+            &name,
+            &schema,
+            None,
+            "Hello",
+            &[1.0, 0.0],
+            Some(&metadata),
+        )
+        .unwrap();
+
+        let read = |conn: &Connection| -> serde_json::Value {
+            conn.query_row(
+                &format!(
+                    "SELECT metadata FROM {} WHERE rowid = ?1",
+                    name.docs_table()
+                ),
+                [rowid],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        assert_eq!(read(&conn), serde_json::Value::Object(metadata));
+
+        set_metadata(&conn, &name, rowid, None).unwrap();
+        assert_eq!(read(&conn), serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_chunks_follow_their_document() {
+        let (conn, name, schema) = open();
+        let chunks = |texts: &[&str]| -> Vec<(String, Vec<f32>)> {
+            texts
+                .iter()
+                .enumerate()
+                .map(|(i, text)| (text.to_string(), vec![1.0, i as f32]))
+                .collect()
+        };
+        let count = |conn: &Connection, table: String| -> i64 {
+            conn.query_row(&format!("SELECT count(*) FROM {}", table), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+        };
+
+        let rowid = insert_chunks(
+            &conn,
+            &name,
+            &schema,
+            Some("post"),
+            "One. Two. Three.",
+            &chunks(&["One.", "Two.", "Three."]),
+            None,
+        )
+        .unwrap();
+        let doc = find(&conn, &name, "post").unwrap().unwrap();
+        assert_eq!(doc.rowid, rowid);
+        assert_eq!(doc.text, "One. Two. Three.");
+        let (_, vectors) = find_vectors(&conn, &name, &schema, "post")
+            .unwrap()
+            .unwrap();
+        assert_eq!(vectors.len(), 3);
+
+        replace_chunks(&conn, &name, &schema, rowid, "Four.", &chunks(&["Four."])).unwrap();
+        assert_eq!(find(&conn, &name, "post").unwrap().unwrap().text, "Four.");
+        assert_eq!(count(&conn, name.quoted()), 1);
+        assert_eq!(count(&conn, name.docs_table()), 2);
+
+        assert!(delete(&conn, &name, &schema, "post").unwrap());
+        assert!(!delete(&conn, &name, &schema, "post").unwrap());
+        assert_eq!(count(&conn, name.quoted()), 0);
+        assert_eq!(count(&conn, name.docs_table()), 0);
+    }
+}
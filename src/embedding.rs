@@ -1,33 +1,1038 @@
-use fastembed::{InitOptions, TextEmbedding};
+use actix_web::web;
+use log::info;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 
-// #[cfg(not(test))]
+use fastembed::{
+    EmbeddingModel, ImageEmbedding, ImageInitOptions, InitOptions, InitOptionsUserDefined,
+    RerankInitOptions, RerankResult, RerankerModel, TextEmbedding, TextRerank, TokenizerFiles,
+    UserDefinedEmbeddingModel,
+};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+
+use crate::batch::{Batcher, Batching};
+use crate::hash::{HashEmbedder, HashModel};
+use crate::image::{FastembedImage, ImageEmbedder, ImageModel};
+use crate::models::Remote;
+use crate::remote::{RemoteEmbedder, RemoteModel};
+use crate::sparse::{FastembedSparse, SparseEmbedder, SparseModel};
+
+/// Placeholder replaced by the text in a template.
+const TEXT_PLACEHOLDER: &str = "{text}";
+
+/// Turns texts into vectors, one per text and in the same order.
+pub(crate) trait Embedder: Send + Sync {
+    fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, fastembed::Error>;
+
+    /// Byte spans of the tokens the model splits `text` into, for chunking
+    /// by tokens. Only models that tokenize in-process can tell.
+    fn tokens(&self, _text: &str) -> Result<Vec<(usize, usize)>, fastembed::Error> {
+        Err(fastembed::Error::msg("Model has no tokenizer to chunk by"))
+    }
+}
+
+/// A model run in-process by fastembed.
+struct Fastembed {
+    model: TextEmbedding,
+    /// The model's tokenizer without its truncation, so long documents are
+    /// tokenized whole.
+    tokenizer: tokenizers::Tokenizer,
+    batch_size: usize,
+}
+
+impl Fastembed {
+    fn new(model: TextEmbedding, batch_size: usize) -> Result<Self, fastembed::Error> {
+        let mut tokenizer = model.tokenizer.clone();
+        tokenizer
+            .with_truncation(None)
+            .map_err(fastembed::Error::msg)?;
+        Ok(Self {
+            model,
+            tokenizer,
+            batch_size,
+        })
+    }
+}
+
+impl Embedder for Fastembed {
+    fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, fastembed::Error> {
+        self.model.embed(texts, Some(self.batch_size))
+    }
+
+    fn tokens(&self, text: &str) -> Result<Vec<(usize, usize)>, fastembed::Error> {
+        let encoding = self
+            .tokenizer
+            .encode(text, false)
+            .map_err(fastembed::Error::msg)?;
+        Ok(encoding
+            .get_offsets()
+            .iter()
+            .copied()
+            .filter(|(start, end)| start < end)
+            .collect())
+    }
+}
+
+/// A loaded text model. Neither kind holds up an actix worker while it runs:
+/// a batched model runs on its batcher's thread, any other on the blocking
+/// thread pool.
+pub(crate) enum TextModel {
+    Direct(Arc<dyn Embedder>),
+    Batched(Arc<dyn Embedder>, Batcher),
+}
+
+impl TextModel {
+    pub(crate) async fn embed(
+        &self,
+        texts: Vec<String>,
+    ) -> Result<Vec<Vec<f32>>, fastembed::Error> {
+        match self {
+            Self::Direct(model) => {
+                let model = model.clone();
+                web::block(move || model.embed(texts)).await?
+            }
+            Self::Batched(_, batcher) => batcher.embed(texts).await,
+        }
+    }
+
+    /// Byte spans of the model's tokens in `text`, tokenized on the blocking
+    /// thread pool since long documents take a while.
+    pub(crate) async fn tokens(
+        &self,
+        text: String,
+    ) -> Result<Vec<(usize, usize)>, fastembed::Error> {
+        let model = match self {
+            Self::Direct(model) | Self::Batched(model, _) => model.clone(),
+        };
+        web::block(move || model.tokens(&text)).await?
+    }
+}
+
+/// An embedding model: one fastembed downloads, an ONNX model from
+/// `[embedding.local]`, one served over HTTP from `[embedding.remote]`, or a
+/// stand-in from `[embedding.hash]`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum Model {
+    Fastembed(EmbeddingModel),
+    Local(String),
+    Remote(String),
+    Hash(String),
+}
+
+impl Model {
+    /// The model's config name, as stored with the collections using it.
+    pub(crate) fn name(&self) -> &str {
+        match self {
+            Model::Fastembed(model) => crate::config::model_name(model),
+            Model::Local(name) | Model::Remote(name) | Model::Hash(name) => name,
+        }
+    }
+}
+
+/// An ONNX model exported from Hugging Face, read from a directory holding
+/// `model.onnx`, `tokenizer.json`, `config.json`, `special_tokens_map.json`
+/// and `tokenizer_config.json`. Nothing is downloaded to load it.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct LocalModel {
+    pub(crate) path: PathBuf,
+    /// Length of the vectors the model produces.
+    pub(crate) dimension: usize,
+    /// How token embeddings are pooled into one vector, per the model card.
+    #[serde(default)]
+    pub(crate) pooling: Pooling,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Pooling {
+    Cls,
+    #[default]
+    Mean,
+}
+
+impl LocalModel {
+    pub(crate) fn load(&self) -> Result<TextEmbedding, fastembed::Error> {
+        let read = |file: &str| {
+            let path = self.path.join(file);
+            std::fs::read(&path).map_err(|e| {
+                fastembed::Error::msg(format!("Failed to read {}: {}", path.display(), e))
+            })
+        };
+        let tokenizer_files = TokenizerFiles {
+            tokenizer_file: read("tokenizer.json")?,
+            config_file: read("config.json")?,
+            special_tokens_map_file: read("special_tokens_map.json")?,
+            tokenizer_config_file: read("tokenizer_config.json")?,
+        };
+        let pooling = match self.pooling {
+            Pooling::Cls => fastembed::Pooling::Cls,
+            Pooling::Mean => fastembed::Pooling::Mean,
+        };
+        let model = UserDefinedEmbeddingModel::new(read("model.onnx")?, tokenizer_files)
+            .with_pooling(pooling);
+        TextEmbedding::try_new_from_user_defined(model, InitOptionsUserDefined::new())
+    }
+}
+
+/// What a text is embedded as. Instruction-tuned models expect search queries
+/// and the passages they should find to be worded differently.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Role {
+    Query,
+    Passage,
+}
+
+/// How texts are wrapped before they reach the model, such as
+/// `search_query: {text}`. `{text}` is replaced by the text, and a template
+/// without it is used as a prefix. A missing template leaves the text as is.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq, Default)]
+pub(crate) struct Templates {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) query: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) passage: Option<String>,
+}
+
+impl Templates {
+    fn new(query: &str, passage: &str) -> Self {
+        let template = |t: &str| (t != TEXT_PLACEHOLDER).then(|| t.to_string());
+        Self {
+            query: template(query),
+            passage: template(passage),
+        }
+    }
+
+    /// The templates the model was trained with, per its model card. Local
+    /// models have none unless configured.
+    pub(crate) fn for_model(model: &Model) -> Self {
+        use EmbeddingModel::*;
+        const BGE_QUERY: &str = "Represent this sentence for searching relevant passages: {text}";
+        let Model::Fastembed(model) = model else {
+            return Self::default();
+        };
+        match model {
+            NomicEmbedTextV1 | NomicEmbedTextV15 | NomicEmbedTextV15Q => {
+                Self::new("search_query: {text}", "search_document: {text}")
+            }
+            BGEBaseENV15 | BGEBaseENV15Q | BGELargeENV15 | BGELargeENV15Q | BGESmallENV15
+            | BGESmallENV15Q | MxbaiEmbedLargeV1 | MxbaiEmbedLargeV1Q => {
+                Self::new(BGE_QUERY, TEXT_PLACEHOLDER)
+            }
+            BGESmallZHV15 => Self::new(
+                "为这个句子生成表示以用于检索相关文章：{text}",
+                TEXT_PLACEHOLDER,
+            ),
+            MultilingualE5Small | MultilingualE5Base | MultilingualE5Large => {
+                Self::new("query: {text}", "passage: {text}")
+            }
+            _ => Self::default(),
+        }
+    }
+
+    /// Takes each template from `overrides` where it sets one. Overriding
+    /// with a bare `{text}` switches a template off.
+    pub(crate) fn merged(&self, overrides: &Templates) -> Self {
+        Self {
+            query: overrides.query.clone().or_else(|| self.query.clone()),
+            passage: overrides.passage.clone().or_else(|| self.passage.clone()),
+        }
+    }
+
+    /// The template for `role`, empty if texts are embedded as they are.
+    pub(crate) fn template(&self, role: Role) -> &str {
+        let template = match role {
+            Role::Query => &self.query,
+            Role::Passage => &self.passage,
+        };
+        template.as_deref().unwrap_or_default()
+    }
+
+    pub(crate) fn apply(&self, role: Role, text: &str) -> String {
+        match Some(self.template(role)).filter(|t| !t.is_empty()) {
+            Some(t) if t.contains(TEXT_PLACEHOLDER) => t.replace(TEXT_PLACEHOLDER, text),
+            Some(prefix) => format!("{}{}", prefix, text),
+            None => text.to_string(),
+        }
+    }
+}
+
+/// Stored in the catalog as JSON.
+impl ToSql for Templates {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        serde_json::to_string(self)
+            .map(ToSqlOutput::from)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(e.into()))
+    }
+}
+
+impl FromSql for Templates {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        serde_json::from_str(value.as_str()?).map_err(|e| FromSqlError::Other(e.into()))
+    }
+}
+
+/// Keeps loaded embedding models alive for the lifetime of the server so that
+/// every actix worker shares the same ONNX session instead of reloading it on
+/// each request.
+/// Models of one kind, each loaded on first use. Only the model being loaded
+/// is locked meanwhile, so requests for models already loaded, or for other
+/// ones, don't wait on a download.
+struct Loaded<K, V> {
+    // A list since `RerankerModel` isn't `Hash`, and only a handful of models
+    // are ever loaded.
+    slots: Mutex<Vec<(K, Arc<Slot<V>>)>>,
+}
+
+struct Slot<V> {
+    value: OnceLock<V>,
+    /// Held while loading, so concurrent first requests don't each load
+    /// their own copy of the model.
+    loading: Mutex<()>,
+}
+
+impl<K, V> Default for Loaded<K, V> {
+    fn default() -> Self {
+        Self {
+            slots: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl<K: PartialEq + Clone, V: Clone> Loaded<K, V> {
+    /// The model, if it is loaded already.
+    fn get(&self, key: &K) -> Option<V> {
+        self.slot(key).value.get().cloned()
+    }
+
+    /// The model, loaded with `load` unless it is already. A failed load
+    /// isn't kept, so the next request tries again.
+    fn get_or_load(
+        &self,
+        key: &K,
+        load: impl FnOnce() -> Result<V, fastembed::Error>,
+    ) -> Result<V, fastembed::Error> {
+        let slot = self.slot(key);
+        if let Some(value) = slot.value.get() {
+            return Ok(value.clone());
+        }
+        let _loading = slot.loading.lock().unwrap();
+        if let Some(value) = slot.value.get() {
+            return Ok(value.clone());
+        }
+        let value = load()?;
+        Ok(slot.value.get_or_init(|| value).clone())
+    }
+
+    fn slot(&self, key: &K) -> Arc<Slot<V>> {
+        let mut slots = self.slots.lock().unwrap();
+        if let Some((_, slot)) = slots.iter().find(|(k, _)| k == key) {
+            return slot.clone();
+        }
+        let slot = Arc::new(Slot {
+            value: OnceLock::new(),
+            loading: Mutex::new(()),
+        });
+        slots.push((key.clone(), slot.clone()));
+        slot
+    }
+}
+
+pub(crate) struct ModelRegistry {
+    default_model: Model,
+    batch_size: usize,
+    local: HashMap<String, LocalModel>,
+    remote: HashMap<String, RemoteModel>,
+    hash: HashMap<String, HashModel>,
+    cache_dir: PathBuf,
+    /// Whether models missing from `cache_dir` fail to load instead of being
+    /// downloaded.
+    offline: bool,
+    batching: Batching,
+    models: Loaded<Model, Arc<TextModel>>,
+    sparse: Loaded<SparseModel, Arc<dyn SparseEmbedder>>,
+    images: Loaded<ImageModel, Arc<dyn ImageEmbedder>>,
+    /// Templates from `[embedding.templates]`, over the built-in ones.
+    templates: HashMap<Model, Templates>,
+    default_reranker: RerankerModel,
+    rerankers: Loaded<RerankerModel, Arc<TextRerank>>,
+    loads: AtomicUsize,
+}
+
+impl ModelRegistry {
+    /// Fails if the configured default model, or one `[embedding.templates]`
+    /// are given for, is neither one of fastembed's nor a configured one.
+    pub(crate) fn new(config: &crate::config::Config) -> Result<Self, fastembed::Error> {
+        let mut registry = Self {
+            default_model: Model::Local(String::new()),
+            batch_size: config.embedding.batch_size,
+            local: config.embedding.local.clone(),
+            remote: config.embedding.remote.clone(),
+            hash: config.embedding.hash.clone(),
+            cache_dir: config.embedding.cache_dir.clone(),
+            offline: config.embedding.offline,
+            batching: config.embedding.batching.clone(),
+            models: Loaded::default(),
+            sparse: Loaded::default(),
+            images: Loaded::default(),
+            templates: HashMap::new(),
+            default_reranker: config.rerank.model.clone(),
+            rerankers: Loaded::default(),
+            loads: AtomicUsize::new(0),
+        };
+        registry.default_model = registry.resolve(&config.embedding.model).ok_or_else(|| {
+            fastembed::Error::msg(format!("Unknown model: {}", config.embedding.model))
+        })?;
+        registry.templates = config
+            .embedding
+            .templates
+            .iter()
+            .map(|(name, templates)| {
+                let model = registry.resolve(name).ok_or_else(|| {
+                    fastembed::Error::msg(format!("Templates given for unknown model: {}", name))
+                })?;
+                let merged = Templates::for_model(&model).merged(templates);
+                Ok((model, merged))
+            })
+            .collect::<Result<_, fastembed::Error>>()?;
+        Ok(registry)
+    }
+
+    pub(crate) fn default_model(&self) -> &Model {
+        &self.default_model
+    }
+
+    /// Looks up a model by its config name. Configured models are looked up
+    /// first, so one can stand in for a fastembed model of the same name.
+    pub(crate) fn resolve(&self, name: &str) -> Option<Model> {
+        if self.local.contains_key(name) {
+            return Some(Model::Local(name.to_string()));
+        }
+        if self.remote.contains_key(name) {
+            return Some(Model::Remote(name.to_string()));
+        }
+        if self.hash.contains_key(name) {
+            return Some(Model::Hash(name.to_string()));
+        }
+        crate::config::model(name).map(Model::Fastembed)
+    }
+
+    /// Number of dimensions of the vectors `model` produces.
+    pub(crate) fn dimension(&self, model: &Model) -> Option<usize> {
+        match model {
+            Model::Fastembed(model) => TextEmbedding::get_model_info(model)
+                .ok()
+                .map(|info| info.dim),
+            Model::Local(name) => self.local.get(name).map(|local| local.dimension),
+            Model::Remote(name) => self.remote.get(name).map(|remote| remote.dimension),
+            Model::Hash(name) => self.hash.get(name).map(|hash| hash.dimension),
+        }
+    }
+
+    /// Describes the configuration `model`'s vectors depend on, beyond its
+    /// name and dimension, so cached vectors aren't reused once it changes.
+    pub(crate) fn fingerprint(&self, model: &Model) -> String {
+        match model {
+            Model::Fastembed(_) | Model::Hash(_) => String::new(),
+            Model::Local(name) => self
+                .local
+                .get(name)
+                .map(|local| format!("local {} {:?}", local.path.display(), local.pooling))
+                .unwrap_or_default(),
+            Model::Remote(name) => self
+                .remote
+                .get(name)
+                .map(|remote| {
+                    let served = remote.model.as_deref().unwrap_or(name);
+                    format!("remote {} {}", remote.url, served)
+                })
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The templates texts for `model` are wrapped in, with a collection's
+    /// own templates taking precedence.
+    pub(crate) fn templates(&self, model: &Model, collection: Option<&Templates>) -> Templates {
+        let model = match self.templates.get(model) {
+            Some(configured) => configured.clone(),
+            None => Templates::for_model(model),
+        };
+        match collection {
+            Some(overrides) => model.merged(overrides),
+            None => model,
+        }
+    }
+
+    /// Returns the configured model, loading it on first use.
+    pub(crate) fn get(&self) -> Result<Arc<TextModel>, fastembed::Error> {
+        self.get_model(&self.default_model)
+    }
+
+    /// Returns a model, loading it on first use. Blocks while the model
+    /// downloads and loads, which [`ModelRegistry::text_model`] doesn't.
+    pub(crate) fn get_model(&self, model: &Model) -> Result<Arc<TextModel>, fastembed::Error> {
+        self.models.get_or_load(model, || self.load_model(model))
+    }
+
+    fn load_model(&self, model: &Model) -> Result<Arc<TextModel>, fastembed::Error> {
+        let unknown = |name: &str| fastembed::Error::msg(format!("Unknown model: {}", name));
+        let loaded: Arc<dyn Embedder> = match model {
+            Model::Fastembed(model) => {
+                self.fetch(Remote::Embedding(model.clone()))?;
+                let model = TextEmbedding::try_new(
+                    InitOptions::new(model.clone())
+                        .with_cache_dir(self.cache_dir.clone())
+                        .with_show_download_progress(false),
+                )?;
+                Arc::new(Fastembed::new(model, self.batch_size)?)
+            }
+            Model::Local(name) => {
+                let model = self.local.get(name).ok_or_else(|| unknown(name))?.load()?;
+                Arc::new(Fastembed::new(model, self.batch_size)?)
+            }
+            Model::Remote(name) => {
+                let remote = self.remote.get(name).ok_or_else(|| unknown(name))?;
+                Arc::new(RemoteEmbedder::new(name, remote))
+            }
+            Model::Hash(name) => {
+                let hash = self.hash.get(name).ok_or_else(|| unknown(name))?;
+                Arc::new(HashEmbedder::new(hash))
+            }
+        };
+        let loaded = Arc::new(if self.batching.enabled() {
+            TextModel::Batched(loaded.clone(), Batcher::new(loaded, &self.batching))
+        } else {
+            TextModel::Direct(loaded)
+        });
+        self.loads.fetch_add(1, Ordering::Relaxed);
+        Ok(loaded)
+    }
+
+    /// Looks up a sparse model by its config name, hash models first.
+    pub(crate) fn resolve_sparse(&self, name: &str) -> Option<SparseModel> {
+        if self.hash.contains_key(name) {
+            return Some(SparseModel::Hash(name.to_string()));
+        }
+        crate::config::sparse_model(name).map(SparseModel::Fastembed)
+    }
+
+    /// Returns a sparse model, loading it on first use.
+    pub(crate) fn get_sparse(
+        &self,
+        model: &SparseModel,
+    ) -> Result<Arc<dyn SparseEmbedder>, fastembed::Error> {
+        self.sparse.get_or_load(model, || self.load_sparse(model))
+    }
+
+    fn load_sparse(
+        &self,
+        model: &SparseModel,
+    ) -> Result<Arc<dyn SparseEmbedder>, fastembed::Error> {
+        let loaded: Arc<dyn SparseEmbedder> = match model {
+            SparseModel::Fastembed(name) => {
+                self.fetch(Remote::Sparse(name))?;
+                Arc::new(FastembedSparse {
+                    model: crate::models::load_sparse(name, &self.cache_dir, false)?,
+                    batch_size: self.batch_size,
+                })
+            }
+            SparseModel::Hash(name) => {
+                let hash = self.hash.get(name).ok_or_else(|| {
+                    fastembed::Error::msg(format!("Unknown sparse model: {}", name))
+                })?;
+                Arc::new(HashEmbedder::new(hash))
+            }
+        };
+        self.loads.fetch_add(1, Ordering::Relaxed);
+        Ok(loaded)
+    }
+
+    /// Looks up an image model by its config name, hash models first.
+    pub(crate) fn resolve_image(&self, name: &str) -> Option<ImageModel> {
+        if self.hash.contains_key(name) {
+            return Some(ImageModel::Hash(name.to_string()));
+        }
+        crate::config::image_model(name).map(ImageModel::Fastembed)
+    }
+
+    /// Number of dimensions of the vectors `model` produces.
+    pub(crate) fn image_dimension(&self, model: &ImageModel) -> Option<usize> {
+        match model {
+            ImageModel::Fastembed(model) => Some(ImageEmbedding::get_model_info(model).dim),
+            ImageModel::Hash(name) => self.hash.get(name).map(|hash| hash.dimension),
+        }
+    }
+
+    /// Returns an image model, loading it on first use.
+    pub(crate) fn get_image(
+        &self,
+        model: &ImageModel,
+    ) -> Result<Arc<dyn ImageEmbedder>, fastembed::Error> {
+        self.images.get_or_load(model, || self.load_image(model))
+    }
+
+    fn load_image(&self, model: &ImageModel) -> Result<Arc<dyn ImageEmbedder>, fastembed::Error> {
+        let loaded: Arc<dyn ImageEmbedder> = match model {
+            ImageModel::Fastembed(model) => {
+                self.fetch(Remote::Image(model.clone()))?;
+                let model = ImageEmbedding::try_new(
+                    ImageInitOptions::new(model.clone())
+                        .with_cache_dir(self.cache_dir.clone())
+                        .with_show_download_progress(false),
+                )?;
+                Arc::new(FastembedImage {
+                    model,
+                    batch_size: self.batch_size,
+                })
+            }
+            ImageModel::Hash(name) => {
+                let hash = self.hash.get(name).ok_or_else(|| {
+                    fastembed::Error::msg(format!("Unknown image model: {}", name))
+                })?;
+                Arc::new(HashEmbedder::new(hash))
+            }
+        };
+        self.loads.fetch_add(1, Ordering::Relaxed);
+        Ok(loaded)
+    }
+
+    pub(crate) fn default_reranker(&self) -> &RerankerModel {
+        &self.default_reranker
+    }
+
+    /// Returns a reranker, loading it on first use.
+    pub(crate) fn get_reranker(
+        &self,
+        model: &RerankerModel,
+    ) -> Result<Arc<TextRerank>, fastembed::Error> {
+        self.rerankers
+            .get_or_load(model, || self.load_reranker(model))
+    }
+
+    fn load_reranker(&self, model: &RerankerModel) -> Result<Arc<TextRerank>, fastembed::Error> {
+        self.fetch(Remote::Reranker(model.clone()))?;
+        let loaded = Arc::new(TextRerank::try_new(
+            RerankInitOptions::new(model.clone())
+                .with_cache_dir(self.cache_dir.clone())
+                .with_show_download_progress(false),
+        )?);
+        self.loads.fetch_add(1, Ordering::Relaxed);
+        Ok(loaded)
+    }
+
+    /// Checks that `remote` can be loaded before fastembed downloads whatever
+    /// is missing from the cache, which offline it must not.
+    fn fetch(&self, remote: Remote) -> Result<(), fastembed::Error> {
+        let missing = remote.missing(&self.cache_dir);
+        if missing.is_empty() {
+            return Ok(());
+        }
+        if self.offline {
+            return Err(fastembed::Error::msg(format!(
+                "Model {} is not in {} and downloads are disabled, \
+                 run `rusticle models download {}` first",
+                remote.name(),
+                self.cache_dir.display(),
+                remote.name()
+            )));
+        }
+        info!(
+            "Downloading model {} to {}",
+            remote.name(),
+            self.cache_dir.display()
+        );
+        Ok(())
+    }
+
+    /// Like [`ModelRegistry::get_model`], but a model that isn't loaded yet
+    /// is loaded on the blocking thread pool rather than an actix worker.
+    pub(crate) async fn text_model(
+        self: &Arc<Self>,
+        model: &Model,
+    ) -> Result<Arc<TextModel>, fastembed::Error> {
+        if let Some(loaded) = self.models.get(model) {
+            return Ok(loaded);
+        }
+        let (registry, model) = (self.clone(), model.clone());
+        web::block(move || registry.get_model(&model)).await?
+    }
+
+    /// Like [`ModelRegistry::text_model`], for sparse models.
+    pub(crate) async fn sparse_model(
+        self: &Arc<Self>,
+        model: &SparseModel,
+    ) -> Result<Arc<dyn SparseEmbedder>, fastembed::Error> {
+        if let Some(loaded) = self.sparse.get(model) {
+            return Ok(loaded);
+        }
+        let (registry, model) = (self.clone(), model.clone());
+        web::block(move || registry.get_sparse(&model)).await?
+    }
+
+    /// Like [`ModelRegistry::text_model`], for image models.
+    pub(crate) async fn image_model(
+        self: &Arc<Self>,
+        model: &ImageModel,
+    ) -> Result<Arc<dyn ImageEmbedder>, fastembed::Error> {
+        if let Some(loaded) = self.images.get(model) {
+            return Ok(loaded);
+        }
+        let (registry, model) = (self.clone(), model.clone());
+        web::block(move || registry.get_image(&model)).await?
+    }
+
+    /// Like [`ModelRegistry::text_model`], for rerankers.
+    pub(crate) async fn reranker(
+        self: &Arc<Self>,
+        model: &RerankerModel,
+    ) -> Result<Arc<TextRerank>, fastembed::Error> {
+        if let Some(loaded) = self.rerankers.get(model) {
+            return Ok(loaded);
+        }
+        let (registry, model) = (self.clone(), model.clone());
+        web::block(move || registry.get_reranker(&model)).await?
+    }
+
+    /// Number of times a model or reranker has been loaded.
+    #[cfg(test)]
+    pub(crate) fn load_count(&self) -> usize {
+        self.loads.load(Ordering::Relaxed)
+    }
+}
+
+/// Embeds `documents` with `model` as queries or passages, wrapping each in
+/// its template first.
 pub(crate) async fn embed(
-    config: &crate::config::Config,
+    registry: &Arc<ModelRegistry>,
+    model: &Model,
+    templates: &Templates,
+    role: Role,
     documents: Vec<&str>,
 ) -> Result<Vec<Vec<f32>>, fastembed::Error> {
-    let model = TextEmbedding::try_new(
-        InitOptions::new(config.embedding.model.clone()).with_show_download_progress(true),
-    )?;
+    let model = registry.text_model(model).await?;
 
-    let embeddings = model.embed(documents, None)?;
+    let documents: Vec<_> = documents
+        .into_iter()
+        .map(|text| templates.apply(role, text))
+        .collect();
+    let embeddings = model.embed(documents).await?;
 
     Ok(embeddings)
 }
 
-// #[cfg(test)]
-// pub(crate) async fn embed(
-//     _config: &crate::config::Config,
-// ) -> Result<Vec<Vec<f32>>, fastembed::Error> {
-//     Ok(vec![vec![0.0, 0.1, 0.2, 0.3]])
-// }
+/// Byte spans of `text`'s tokens under `model`'s tokenizer.
+pub(crate) async fn tokens(
+    registry: &Arc<ModelRegistry>,
+    model: &Model,
+    text: &str,
+) -> Result<Vec<(usize, usize)>, fastembed::Error> {
+    registry
+        .text_model(model)
+        .await?
+        .tokens(text.to_string())
+        .await
+}
+
+/// Embeds encoded images with an image model.
+pub(crate) async fn embed_images(
+    registry: &Arc<ModelRegistry>,
+    model: &ImageModel,
+    images: Vec<Vec<u8>>,
+) -> Result<Vec<Vec<f32>>, fastembed::Error> {
+    let model = registry.image_model(model).await?;
+
+    let embeddings = web::block(move || model.embed_images(images)).await??;
+
+    Ok(embeddings)
+}
+
+/// Scores each document against `query`, best match first. Each result's
+/// `index` points back into `documents`.
+pub(crate) async fn rerank(
+    registry: &Arc<ModelRegistry>,
+    model: &RerankerModel,
+    query: &str,
+    documents: Vec<&str>,
+) -> Result<Vec<RerankResult>, fastembed::Error> {
+    let reranker = registry.reranker(model).await?;
+
+    let query = query.to_string();
+    let documents: Vec<_> = documents.into_iter().map(String::from).collect();
+    let batch_size = registry.batch_size;
+    let results =
+        web::block(move || reranker.rerank(query, documents, false, Some(batch_size))).await??;
+
+    Ok(results)
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[actix_web::test]
+    async fn test_embed() {
+        let config = crate::config::Config::hermetic();
+        let registry = Arc::new(ModelRegistry::new(&config).unwrap());
+        let model = registry.default_model().clone();
+        let templates = registry.templates(&model, None);
+        let embeddings = embed(
+            &registry,
+            &model,
+            &templates,
+            Role::Passage,
+            vec!["hello", "world"],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(embeddings.len(), 2);
+
+        embed(&registry, &model, &templates, Role::Query, vec!["again"])
+            .await
+            .unwrap();
+        assert_eq!(embeddings[0].len(), registry.dimension(&model).unwrap());
+        assert_eq!(registry.load_count(), 1);
+    }
+
+    #[actix_web::test]
+    #[ignore = "downloads the default model"]
+    async fn test_embed_fastembed() {
+        let registry = Arc::new(ModelRegistry::new(&crate::config::Config::default()).unwrap());
+        let model = registry.default_model().clone();
+        let templates = registry.templates(&model, None);
+        let embeddings = embed(
+            &registry,
+            &model,
+            &templates,
+            Role::Passage,
+            vec!["hello", "world"],
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(embeddings.len(), 2);
+        assert_eq!(embeddings[0].len(), registry.dimension(&model).unwrap());
+    }
+
+    #[test]
+    fn test_templates() {
+        let nomic = Templates::for_model(&Model::Fastembed(EmbeddingModel::NomicEmbedTextV15Q));
+        assert_eq!(nomic.apply(Role::Query, "rust"), "search_query: rust");
+        assert_eq!(nomic.apply(Role::Passage, "rust"), "search_document: rust");
+
+        let bge = Templates::for_model(&Model::Fastembed(EmbeddingModel::BGESmallENV15));
+        assert_eq!(bge.passage, None);
+        assert_eq!(bge.apply(Role::Passage, "rust"), "rust");
+        let minilm = Templates::for_model(&Model::Fastembed(EmbeddingModel::AllMiniLML6V2));
+        assert_eq!(minilm.apply(Role::Query, "rust"), "rust");
+
+        let overrides = Templates {
+            query: Some("{text}".to_string()),
+            passage: Some("title: ".to_string()),
+        };
+        let merged = nomic.merged(&overrides);
+        assert_eq!(merged.apply(Role::Query, "rust"), "rust");
+        assert_eq!(merged.apply(Role::Passage, "rust"), "title: rust");
+        assert_eq!(nomic.merged(&Templates::default()), nomic);
+    }
+
+    #[test]
+    fn test_model_names() {
+        let registry = ModelRegistry::new(&crate::config::Config::default()).unwrap();
+        for info in TextEmbedding::list_supported_models() {
+            let model = Model::Fastembed(info.model);
+            assert_eq!(registry.resolve(model.name()), Some(model));
+        }
+
+        let resolve = |name| match registry.resolve(name) {
+            Some(Model::Fastembed(model)) => Some(model),
+            _ => None,
+        };
+        assert_eq!(resolve("bge-small-en"), Some(EmbeddingModel::BGESmallENV15));
+        assert_eq!(
+            resolve("bge-base-en-v1.5-q"),
+            Some(EmbeddingModel::BGEBaseENV15Q)
+        );
+        assert_eq!(
+            resolve("multilingual-e5-large"),
+            Some(EmbeddingModel::MultilingualE5Large)
+        );
+        assert_eq!(
+            resolve("mxbai-embed-large-v1-q"),
+            Some(EmbeddingModel::MxbaiEmbedLargeV1Q)
+        );
+        assert_eq!(resolve("word2vec"), None);
+
+        let minilm = registry.resolve("all-minilm-l6-v2").unwrap();
+        assert_eq!(registry.dimension(&minilm), Some(384));
+        assert_eq!(registry.dimension(registry.default_model()), Some(768));
+    }
+
+    #[test]
+    fn test_loaded() {
+        let loaded = Arc::new(Loaded::<&str, usize>::default());
+        let offline = || Err(fastembed::Error::msg("offline"));
+        assert!(loaded.get_or_load(&"a", offline).is_err());
+        assert_eq!(loaded.get(&"a"), None);
+
+        // Another model loads while "a" is still loading.
+        let (started, wait) = std::sync::mpsc::channel();
+        let (release, released) = std::sync::mpsc::channel::<()>();
+        let loader = {
+            let loaded = loaded.clone();
+            std::thread::spawn(move || {
+                loaded.get_or_load(&"a", || {
+                    started.send(()).unwrap();
+                    released.recv().unwrap();
+                    Ok(1)
+                })
+            })
+        };
+        wait.recv().unwrap();
+        assert_eq!(loaded.get(&"a"), None);
+        assert_eq!(loaded.get_or_load(&"b", || Ok(2)).unwrap(), 2);
+        release.send(()).unwrap();
+        assert_eq!(loader.join().unwrap().unwrap(), 1);
+        assert_eq!(loaded.get_or_load(&"a", || Ok(3)).unwrap(), 1);
+    }
+
     #[test]
-    fn test_embed() {
-        let config = crate::config::Config::default();
-        embed(&config, vec!["hello", "world"]);
+    fn test_offline() {
+        let mut config = crate::config::Config::default();
+        config.embedding.cache_dir = std::env::temp_dir().join("rusticle-offline-empty");
+        config.embedding.offline = true;
+        let registry = ModelRegistry::new(&config).unwrap();
+
+        let error = registry.get().err().unwrap().to_string();
+        assert!(
+            error.contains("rusticle models download nomic-embed-text-v1.5-q"),
+            "{}",
+            error
+        );
+        let error = registry
+            .get_reranker(registry.default_reranker())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(error.contains("bge-reranker-base"), "{}", error);
+        assert_eq!(registry.load_count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_remote_models() {
+        let (url, received) = crate::remote::tests::mock_server(vec![Some((200, ""))]);
+        let config: crate::config::Config = toml::from_str(&format!(
+            r#"
+            [embedding]
+            model = "served"
+
+            [embedding.remote.served]
+            url = "{}"
+            model = "nomic-embed-text"
+            dimension = 2
+
+            [embedding.templates.served]
+            query = "search_query: "
+            "#,
+            url
+        ))
+        .unwrap();
+        let registry = Arc::new(ModelRegistry::new(&config).unwrap());
+
+        let model = registry.default_model().clone();
+        assert_eq!(model, Model::Remote("served".to_string()));
+        assert_eq!(registry.dimension(&model), Some(2));
+        let templates = registry.templates(&model, None);
+        let embeddings = embed(&registry, &model, &templates, Role::Query, vec!["rust"])
+            .await
+            .unwrap();
+        assert_eq!(embeddings, vec![vec![0.0, 18.0]]);
+
+        let received = received.lock().unwrap();
+        assert_eq!(received[0].body["model"], "nomic-embed-text");
+        assert_eq!(
+            received[0].body["input"],
+            serde_json::json!(["search_query: rust"])
+        );
+        assert_eq!(registry.load_count(), 1);
+    }
+
+    #[test]
+    fn test_local_models() {
+        let config: crate::config::Config = toml::from_str(
+            r#"
+            [embedding]
+            model = "in-house"
+
+            [embedding.local.in-house]
+            path = "/nonexistent/in-house"
+            dimension = 512
+            pooling = "cls"
+
+            [embedding.templates.in-house]
+            query = "query: "
+            "#,
+        )
+        .unwrap();
+        let registry = ModelRegistry::new(&config).unwrap();
+
+        let model = registry.default_model().clone();
+        assert_eq!(model, Model::Local("in-house".to_string()));
+        assert_eq!(model.name(), "in-house");
+        assert_eq!(registry.dimension(&model), Some(512));
+        let templates = registry.templates(&model, None);
+        assert_eq!(templates.apply(Role::Query, "rust"), "query: rust");
+        assert_eq!(templates.apply(Role::Passage, "rust"), "rust");
+
+        let error = registry.get().err().unwrap().to_string();
+        assert!(error.contains("/nonexistent/in-house"), "{}", error);
+        assert_eq!(registry.load_count(), 0);
+
+        let config: crate::config::Config =
+            toml::from_str("[embedding]\nmodel = \"elsewhere\"").unwrap();
+        assert!(ModelRegistry::new(&config).is_err());
+    }
+
+    #[test]
+    fn test_configured_templates() {
+        let config: crate::config::Config = toml::from_str(
+            r#"
+            [embedding]
+            model = "bge-small-en-v1.5"
+
+            [embedding.templates."bge-small-en-v1.5"]
+            passage = "passage: {text}"
+            "#,
+        )
+        .unwrap();
+        let registry = ModelRegistry::new(&config).unwrap();
+
+        let model = registry.default_model().clone();
+        let templates = registry.templates(&model, None);
+        assert_eq!(
+            templates.apply(Role::Query, "rust"),
+            "Represent this sentence for searching relevant passages: rust"
+        );
+        assert_eq!(templates.apply(Role::Passage, "rust"), "passage: rust");
+
+        let collection = Templates {
+            query: Some("{text}".to_string()),
+            passage: None,
+        };
+        let templates = registry.templates(&model, Some(&collection));
+        assert_eq!(templates.apply(Role::Query, "rust"), "rust");
+        assert_eq!(templates.apply(Role::Passage, "rust"), "passage: rust");
+
+        let config: crate::config::Config = toml::from_str(
+            r#"
+            [embedding.templates."bge-smol"]
+            passage = "passage: {text}"
+            "#,
+        )
+        .unwrap();
+        let error = ModelRegistry::new(&config).err().unwrap().to_string();
+        assert!(error.contains("bge-smol"), "{}", error);
     }
 }
@@ -0,0 +1,618 @@
+//! Metadata filter expressions for search requests, such as
+//! `tag = "rust" AND published_at > "2024-01-01" AND lang IN ["en", "de"]`.
+//!
+//! Comparisons read fields from the document's metadata. When a field holds an
+//! array, a comparison matches if any element matches, so `tags = "rust"` finds
+//! documents tagged with `rust`. `!=` and `NOT IN` are the negation of `=` and
+//! `IN`, so they also match documents that don't have the field at all.
+
+use rusqlite::types::Value;
+use std::fmt;
+use std::str::FromStr;
+
+/// Deepest nesting of parentheses and `NOT`s accepted in a filter.
+const MAX_DEPTH: usize = 32;
+
+/// Most comparisons accepted in a filter. Chains of `AND` and `OR` nest one
+/// level per comparison once rendered, and SQLite caps how deep an
+/// expression may go.
+const MAX_COMPARISONS: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Compare {
+        field: Vec<String>,
+        op: CompareOp,
+        value: Literal,
+    },
+    In {
+        field: Vec<String>,
+        values: Vec<Literal>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Literal {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    Null,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct InvalidFilter {
+    message: String,
+    position: usize,
+}
+
+impl fmt::Display for InvalidFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "invalid filter at position {}: {}",
+            self.position, self.message
+        )
+    }
+}
+
+impl std::error::Error for InvalidFilter {}
+
+impl CompareOp {
+    fn as_sql(self) -> &'static str {
+        match self {
+            Self::Eq => "=",
+            Self::Ne => "!=",
+            Self::Lt => "<",
+            Self::Le => "<=",
+            Self::Gt => ">",
+            Self::Ge => ">=",
+        }
+    }
+}
+
+impl Literal {
+    fn to_sql(&self) -> Value {
+        match self {
+            Self::String(s) => Value::Text(s.clone()),
+            Self::Integer(i) => Value::Integer(*i),
+            Self::Float(f) => Value::Real(*f),
+            Self::Bool(b) => Value::Integer(*b as i64),
+            Self::Null => Value::Null,
+        }
+    }
+}
+
+impl Filter {
+    /// Renders the filter as an SQL condition over the JSON column `metadata`,
+    /// returning the condition and the values to bind to its `?` placeholders
+    /// in order.
+    pub(crate) fn to_sql(&self, metadata: &str) -> (String, Vec<Value>) {
+        let mut params = Vec::new();
+        let sql = self.write_sql(metadata, &mut params);
+        (sql, params)
+    }
+
+    fn write_sql(&self, metadata: &str, params: &mut Vec<Value>) -> String {
+        match self {
+            Self::And(lhs, rhs) => format!(
+                "({} AND {})",
+                lhs.write_sql(metadata, params),
+                rhs.write_sql(metadata, params)
+            ),
+            Self::Or(lhs, rhs) => format!(
+                "({} OR {})",
+                lhs.write_sql(metadata, params),
+                rhs.write_sql(metadata, params)
+            ),
+            Self::Not(inner) => format!("NOT {}", inner.write_sql(metadata, params)),
+            Self::Compare {
+                field,
+                op: op @ (CompareOp::Eq | CompareOp::Ne),
+                value: Literal::Null,
+            } => {
+                params.push(Value::Text(json_path(field)));
+                let missing = format!("coalesce(json_type({}, ?), 'null') = 'null'", metadata);
+                if *op == CompareOp::Eq {
+                    missing
+                } else {
+                    format!("NOT {}", missing)
+                }
+            }
+            Self::Compare {
+                field,
+                op: CompareOp::Ne,
+                value,
+            } => format!(
+                "NOT {}",
+                Self::Compare {
+                    field: field.clone(),
+                    op: CompareOp::Eq,
+                    value: value.clone(),
+                }
+                .write_sql(metadata, params)
+            ),
+            Self::Compare { field, op, value } => {
+                params.push(Value::Text(json_path(field)));
+                params.push(value.to_sql());
+                format!(
+                    "EXISTS (SELECT 1 FROM json_each({}, ?) WHERE value {} ?)",
+                    metadata,
+                    op.as_sql()
+                )
+            }
+            Self::In { values, .. } if values.is_empty() => "0".to_string(),
+            Self::In { field, values } => {
+                params.push(Value::Text(json_path(field)));
+                params.extend(values.iter().map(Literal::to_sql));
+                format!(
+                    "EXISTS (SELECT 1 FROM json_each({}, ?) WHERE value IN ({}))",
+                    metadata,
+                    vec!["?"; values.len()].join(", ")
+                )
+            }
+        }
+    }
+}
+
+fn json_path(field: &[String]) -> String {
+    let mut path = String::from("$");
+    for segment in field {
+        // Segments are restricted to identifier characters, so quoting them
+        // needs no escaping.
+        path.push_str(&format!(".\"{}\"", segment));
+    }
+    path
+}
+
+impl FromStr for Filter {
+    type Err = InvalidFilter;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(source)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            end: source.len(),
+            comparisons: 0,
+        };
+        let filter = parser.parse_or(0)?;
+        match parser.peek() {
+            None => Ok(filter),
+            Some((_, position)) => Err(InvalidFilter {
+                message: "unexpected trailing input".to_string(),
+                position,
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Literal(Literal),
+    Op(CompareOp),
+    And,
+    Or,
+    Not,
+    In,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Comma,
+    Dot,
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, InvalidFilter> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        let error = |message: &str| InvalidFilter {
+            message: message.to_string(),
+            position: start,
+        };
+        let token = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '(' | ')' | '[' | ']' | ',' | '.' => {
+                chars.next();
+                match c {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    '[' => Token::LBracket,
+                    ']' => Token::RBracket,
+                    ',' => Token::Comma,
+                    _ => Token::Dot,
+                }
+            }
+            '=' => {
+                chars.next();
+                Token::Op(CompareOp::Eq)
+            }
+            '!' => {
+                chars.next();
+                match chars.next() {
+                    Some((_, '=')) => Token::Op(CompareOp::Ne),
+                    _ => return Err(error("expected '!='")),
+                }
+            }
+            '<' | '>' => {
+                chars.next();
+                let or_equal = chars.next_if(|&(_, c)| c == '=').is_some();
+                match (c, or_equal) {
+                    ('<', false) => Token::Op(CompareOp::Lt),
+                    ('<', true) => Token::Op(CompareOp::Le),
+                    ('>', false) => Token::Op(CompareOp::Gt),
+                    _ => Token::Op(CompareOp::Ge),
+                }
+            }
+            '"' | '\'' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, q)) if q == c => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, escaped)) => value.push(escaped),
+                            None => return Err(error("unterminated string")),
+                        },
+                        Some((_, other)) => value.push(other),
+                        None => return Err(error("unterminated string")),
+                    }
+                }
+                Token::Literal(Literal::String(value))
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut number = String::new();
+                while let Some((_, c)) =
+                    chars.next_if(|&(_, c)| c.is_ascii_alphanumeric() || "-+.".contains(c))
+                {
+                    number.push(c);
+                }
+                if let Ok(i) = number.parse::<i64>() {
+                    Token::Literal(Literal::Integer(i))
+                } else if let Ok(f) = number.parse::<f64>() {
+                    Token::Literal(Literal::Float(f))
+                } else {
+                    return Err(error("invalid number"));
+                }
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let mut word = String::new();
+                while let Some((_, c)) =
+                    chars.next_if(|&(_, c)| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                {
+                    word.push(c);
+                }
+                match word.to_ascii_uppercase().as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    "IN" => Token::In,
+                    "TRUE" => Token::Literal(Literal::Bool(true)),
+                    "FALSE" => Token::Literal(Literal::Bool(false)),
+                    "NULL" => Token::Literal(Literal::Null),
+                    _ => Token::Ident(word),
+                }
+            }
+            other => return Err(error(&format!("unexpected character {:?}", other))),
+        };
+        tokens.push((token, start));
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    end: usize,
+    comparisons: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<(&Token, usize)> {
+        self.tokens.get(self.pos).map(|(t, p)| (t, *p))
+    }
+
+    fn next(&mut self) -> Option<(Token, usize)> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn position(&self) -> usize {
+        self.peek().map(|(_, p)| p).unwrap_or(self.end)
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, InvalidFilter> {
+        Err(InvalidFilter {
+            message: message.to_string(),
+            position: self.position(),
+        })
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek().is_some_and(|(t, _)| t == token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_or(&mut self, depth: usize) -> Result<Filter, InvalidFilter> {
+        let mut lhs = self.parse_and(depth)?;
+        while self.eat(&Token::Or) {
+            let rhs = self.parse_and(depth)?;
+            lhs = Filter::Or(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self, depth: usize) -> Result<Filter, InvalidFilter> {
+        let mut lhs = self.parse_unary(depth)?;
+        while self.eat(&Token::And) {
+            let rhs = self.parse_unary(depth)?;
+            lhs = Filter::And(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self, depth: usize) -> Result<Filter, InvalidFilter> {
+        if depth >= MAX_DEPTH {
+            return self.error("filter is nested too deeply");
+        }
+        if self.eat(&Token::Not) {
+            return Ok(Filter::Not(Box::new(self.parse_unary(depth + 1)?)));
+        }
+        if self.eat(&Token::LParen) {
+            let inner = self.parse_or(depth + 1)?;
+            if !self.eat(&Token::RParen) {
+                return self.error("expected ')'");
+            }
+            return Ok(inner);
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> Result<Filter, InvalidFilter> {
+        if self.comparisons == MAX_COMPARISONS {
+            return self.error("filter has too many comparisons");
+        }
+        self.comparisons += 1;
+        let field = self.parse_field()?;
+        match self.next() {
+            Some((Token::Op(op), _)) => {
+                let value = self.parse_literal()?;
+                if value == Literal::Null && !matches!(op, CompareOp::Eq | CompareOp::Ne) {
+                    return self.error("null can only be compared with '=' or '!='");
+                }
+                Ok(Filter::Compare { field, op, value })
+            }
+            Some((Token::In, _)) => self.parse_in(field),
+            Some((Token::Not, _)) if self.eat(&Token::In) => {
+                Ok(Filter::Not(Box::new(self.parse_in(field)?)))
+            }
+            _ => {
+                self.pos -= 1;
+                self.error("expected a comparison operator or IN")
+            }
+        }
+    }
+
+    fn parse_in(&mut self, field: Vec<String>) -> Result<Filter, InvalidFilter> {
+        if !self.eat(&Token::LBracket) {
+            return self.error("expected '[' after IN");
+        }
+        let mut values = Vec::new();
+        if !self.eat(&Token::RBracket) {
+            loop {
+                values.push(self.parse_literal()?);
+                if self.eat(&Token::RBracket) {
+                    break;
+                }
+                if !self.eat(&Token::Comma) {
+                    return self.error("expected ',' or ']'");
+                }
+            }
+        }
+        Ok(Filter::In { field, values })
+    }
+
+    fn parse_field(&mut self) -> Result<Vec<String>, InvalidFilter> {
+        let mut field = Vec::new();
+        loop {
+            match self.next() {
+                Some((Token::Ident(segment), _)) => field.push(segment),
+                _ => {
+                    self.pos -= 1;
+                    return self.error("expected a field name");
+                }
+            }
+            if !self.eat(&Token::Dot) {
+                return Ok(field);
+            }
+        }
+    }
+
+    fn parse_literal(&mut self) -> Result<Literal, InvalidFilter> {
+        match self.next() {
+            Some((Token::Literal(literal), _)) => Ok(literal),
+            _ => {
+                self.pos -= 1;
+                self.error("expected a string, number, true, false or null")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusqlite::Connection;
+
+    fn field(name: &str) -> Vec<String> {
+        name.split('.').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        let filter: Filter = "a = 1 OR b = 2 AND NOT c IN [\"x\", 'y']".parse().unwrap();
+        assert_eq!(
+            filter,
+            Filter::Or(
+                Box::new(Filter::Compare {
+                    field: field("a"),
+                    op: CompareOp::Eq,
+                    value: Literal::Integer(1),
+                }),
+                Box::new(Filter::And(
+                    Box::new(Filter::Compare {
+                        field: field("b"),
+                        op: CompareOp::Eq,
+                        value: Literal::Integer(2),
+                    }),
+                    Box::new(Filter::Not(Box::new(Filter::In {
+                        field: field("c"),
+                        values: vec![
+                            Literal::String("x".to_string()),
+                            Literal::String("y".to_string()),
+                        ],
+                    }))),
+                )),
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_literals_and_fields() {
+        let filter: Filter = "author.name != \"O\\\"Neil\" AND score >= -1.5 AND draft = false"
+            .parse()
+            .unwrap();
+        let Filter::And(lhs, draft) = filter else {
+            panic!("expected AND");
+        };
+        let Filter::And(name, score) = *lhs else {
+            panic!("expected AND");
+        };
+        assert_eq!(
+            *name,
+            Filter::Compare {
+                field: field("author.name"),
+                op: CompareOp::Ne,
+                value: Literal::String("O\"Neil".to_string()),
+            }
+        );
+        assert_eq!(
+            *score,
+            Filter::Compare {
+                field: field("score"),
+                op: CompareOp::Ge,
+                value: Literal::Float(-1.5),
+            }
+        );
+        assert_eq!(
+            *draft,
+            Filter::Compare {
+                field: field("draft"),
+                op: CompareOp::Eq,
+                value: Literal::Bool(false),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        for source in [
+            "",
+            "tag",
+            "tag =",
+            "tag = \"rust",
+            "tag == 1",
+            "tag IN \"rust\"",
+            "tag IN [1,",
+            "(tag = 1",
+            "tag = 1 tag = 2",
+            "tag > null",
+            "1 = tag",
+            "tag = 1; DROP TABLE posts",
+        ] {
+            assert!(source.parse::<Filter>().is_err(), "{:?}", source);
+        }
+
+        let deep = format!("{}a = 1{}", "(".repeat(100), ")".repeat(100));
+        assert!(deep.parse::<Filter>().is_err());
+        let long = vec!["a = 1"; 65].join(" AND ");
+        assert!(long.parse::<Filter>().is_err());
+        let long = vec!["a = 1"; 64].join(" OR ");
+        assert!(long.parse::<Filter>().is_ok());
+
+        let err = "tag = 1 )".parse::<Filter>().unwrap_err();
+        assert_eq!(err.position, 8);
+    }
+
+    fn matching(filter: &str) -> Vec<i64> {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            r#"CREATE TABLE docs (rowid INTEGER PRIMARY KEY, metadata TEXT);
+            INSERT INTO docs VALUES
+                (1, '{"tag": "rust", "published_at": "2024-03-01", "lang": "en", "tags": ["rust", "sqlite"], "views": 10}'),
+                (2, '{"tag": "rust", "published_at": "2023-12-01", "lang": "de", "draft": true, "views": 2.5}'),
+                (3, '{"tag": "go", "published_at": "2024-06-01", "lang": "fr", "author": {"name": "Ann"}}'),
+                (4, NULL);"#,
+        )
+        .unwrap();
+
+        let filter: Filter = filter.parse().unwrap();
+        let (sql, params) = filter.to_sql("metadata");
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT rowid FROM docs WHERE {} ORDER BY rowid",
+                sql
+            ))
+            .unwrap();
+        stmt.query_map(rusqlite::params_from_iter(params), |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_filter_sql() {
+        assert_eq!(
+            matching(r#"tag = "rust" AND published_at > "2024-01-01" AND lang IN ["en","de"]"#),
+            vec![1]
+        );
+        assert_eq!(matching(r#"tags = "sqlite""#), vec![1]);
+        assert_eq!(
+            matching(&vec![r#"tag != "go""#; 64].join(" AND ")),
+            vec![1, 2, 4]
+        );
+        assert_eq!(matching("draft != true"), vec![1, 3, 4]);
+        assert_eq!(matching("draft = null"), vec![1, 3, 4]);
+        assert_eq!(matching("draft != null"), vec![2]);
+        assert_eq!(matching("views > 3"), vec![1]);
+        assert_eq!(matching("views <= 2.5"), vec![2]);
+        assert_eq!(matching(r#"author.name = "Ann""#), vec![3]);
+        assert_eq!(matching(r#"lang NOT IN ["en", "de"]"#), vec![3, 4]);
+        assert_eq!(matching("lang IN []"), Vec::<i64>::new());
+        assert_eq!(matching(r#"NOT (tag = "rust" OR lang = "fr")"#), vec![4]);
+    }
+}
@@ -0,0 +1,193 @@
+//! A stand-in for a real model that hashes words into vectors, for tests that
+//! have to run offline and fast. Texts sharing words, or parts of words, get
+//! similar vectors, so searches still find what they should.
+//!
+//! A hash model also stands in for a sparse model, giving the non-zero
+//! buckets of its vectors as their terms, and for an image model, reading an
+//! image's bytes as if they were text.
+
+use serde::Deserialize;
+
+use crate::embedding::Embedder;
+use crate::image::ImageEmbedder;
+use crate::sparse::{SparseEmbedder, SparseVector};
+
+/// A hashing model, as configured under `[embedding.hash]`.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct HashModel {
+    /// Length of the vectors produced.
+    pub(crate) dimension: usize,
+}
+
+pub(crate) struct HashEmbedder {
+    dimension: usize,
+}
+
+impl HashEmbedder {
+    pub(crate) fn new(config: &HashModel) -> Self {
+        Self {
+            dimension: config.dimension.max(1),
+        }
+    }
+
+    /// Sums a signed one-hot vector per word and per trigram of each word,
+    /// then normalizes the sum. Text without words hashes as a whole, so no
+    /// vector is all zeros.
+    fn vector(&self, text: &str) -> Vec<f32> {
+        let mut vector = vec![0.0; self.dimension];
+        let text = text.to_lowercase();
+        let mut words = text
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .peekable();
+        if words.peek().is_none() {
+            self.add(&mut vector, text.as_bytes(), 1.0);
+        }
+        for word in words {
+            self.add(&mut vector, word.as_bytes(), 1.0);
+            let padded: Vec<char> = format!(" {} ", word).chars().collect();
+            for trigram in padded.windows(3) {
+                let trigram: String = trigram.iter().collect();
+                self.add(&mut vector, trigram.as_bytes(), 0.5);
+            }
+        }
+        let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+        vector.iter().map(|x| x / norm).collect()
+    }
+
+    fn add(&self, vector: &mut [f32], feature: &[u8], weight: f32) {
+        let hash = fnv1a(feature);
+        let sign = if hash >> 63 == 0 { 1.0 } else { -1.0 };
+        vector[(hash % self.dimension as u64) as usize] += sign * weight;
+    }
+}
+
+impl Embedder for HashEmbedder {
+    fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, fastembed::Error> {
+        Ok(texts.iter().map(|text| self.vector(text)).collect())
+    }
+
+    /// The words [`HashEmbedder::vector`] hashes are its tokens.
+    fn tokens(&self, text: &str) -> Result<Vec<(usize, usize)>, fastembed::Error> {
+        let mut spans = Vec::new();
+        let mut start = None;
+        for (i, c) in text.char_indices() {
+            match (c.is_alphanumeric(), start) {
+                (true, None) => start = Some(i),
+                (false, Some(s)) => {
+                    spans.push((s, i));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(s) = start {
+            spans.push((s, text.len()));
+        }
+        Ok(spans)
+    }
+}
+
+impl SparseEmbedder for HashEmbedder {
+    fn embed_sparse(&self, texts: Vec<String>) -> Result<Vec<SparseVector>, fastembed::Error> {
+        Ok(texts
+            .iter()
+            .map(|text| {
+                let terms = self
+                    .vector(text)
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, weight)| *weight != 0.0)
+                    .map(|(term, weight)| (term as u32, weight))
+                    .collect();
+                SparseVector(terms)
+            })
+            .collect())
+    }
+}
+
+impl ImageEmbedder for HashEmbedder {
+    fn embed_images(&self, images: Vec<Vec<u8>>) -> Result<Vec<Vec<f32>>, fastembed::Error> {
+        Ok(images
+            .iter()
+            .map(|image| self.vector(&String::from_utf8_lossy(image)))
+            .collect())
+    }
+}
+
+/// 64-bit FNV-1a, which unlike the standard library's hasher gives the same
+/// hash on every platform and release.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metric::Metric;
+
+    #[test]
+    fn test_vectors() {
+        let embedder = HashEmbedder::new(&HashModel { dimension: 64 });
+        let texts = [
+            "Rust is fast",
+            "rust, IS fast!",
+            "Rust is safe",
+            "Bread recipes",
+            "",
+            "!!",
+        ];
+        let vectors = embedder
+            .embed(texts.iter().map(|text| text.to_string()).collect())
+            .unwrap();
+
+        assert!(vectors.iter().all(|vector| vector.len() == 64));
+        for vector in &vectors {
+            let norm = vector.iter().map(|x| x * x).sum::<f32>().sqrt();
+            assert!((norm - 1.0).abs() < 1e-5, "{:?}", vector);
+        }
+        assert_eq!(vectors[0], vectors[1]);
+        assert_eq!(vectors[0], embedder.vector("Rust is fast"));
+        assert_ne!(vectors[4], vectors[5]);
+
+        let distance = |a: usize, b: usize| Metric::Cosine.distance(&vectors[a], &vectors[b]);
+        assert!(distance(0, 2) < distance(0, 3));
+
+        let sparse = embedder
+            .embed_sparse(vec!["Rust is fast".to_string()])
+            .unwrap();
+        let dense: Vec<_> = vectors[0]
+            .iter()
+            .enumerate()
+            .filter(|(_, weight)| **weight != 0.0)
+            .map(|(term, weight)| (term as u32, *weight))
+            .collect();
+        assert_eq!(sparse[0].0, dense);
+
+        let images = embedder
+            .embed_images(vec![b"Rust is fast".to_vec()])
+            .unwrap();
+        assert_eq!(images[0], vectors[0]);
+    }
+
+    #[test]
+    fn test_stable_across_releases() {
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+        // Vectors stored with a hash model must keep matching the same texts.
+        let embedder = HashEmbedder::new(&HashModel { dimension: 4 });
+        let part = 1.0 / 6f32.sqrt();
+        let expected = [0.0, -part, -2.0 * part, -part];
+        let vector = embedder.vector("rust");
+        assert!(
+            vector
+                .iter()
+                .zip(expected)
+                .all(|(x, y)| (x - y).abs() < 1e-6),
+            "{:?}",
+            vector
+        );
+    }
+}
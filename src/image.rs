@@ -0,0 +1,125 @@
+//! Image embeddings, for collections of pictures searched by image or by
+//! text.
+//!
+//! An image model such as CLIP's comes with a text encoder trained to put a
+//! caption next to the pictures it describes. An image collection stores the
+//! image model's vectors and embeds search texts with that encoder, so its
+//! vectors can be searched either way.
+
+use std::io::Write;
+
+use base64::Engine;
+use fastembed::{EmbeddingModel, ImageEmbedding, ImageEmbeddingModel};
+
+use crate::embedding::Model;
+
+/// Turns encoded images, such as the bytes of PNG or JPEG files, into
+/// vectors, one per image and in the same order.
+pub(crate) trait ImageEmbedder: Send + Sync {
+    fn embed_images(&self, images: Vec<Vec<u8>>) -> Result<Vec<Vec<f32>>, fastembed::Error>;
+}
+
+/// An image model: one fastembed downloads, or a stand-in from
+/// `[embedding.hash]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ImageModel {
+    Fastembed(ImageEmbeddingModel),
+    Hash(String),
+}
+
+impl ImageModel {
+    /// The model's config name, as stored with the collections using it.
+    pub(crate) fn name(&self) -> &str {
+        match self {
+            ImageModel::Fastembed(model) => crate::config::image_model_name(model),
+            ImageModel::Hash(name) => name,
+        }
+    }
+
+    /// The text model trained alongside this one, whose vectors of a text
+    /// are close to those of the images it describes. Models trained on
+    /// images alone have none.
+    pub(crate) fn text_model(&self) -> Option<Model> {
+        match self {
+            ImageModel::Fastembed(ImageEmbeddingModel::ClipVitB32) => {
+                Some(Model::Fastembed(EmbeddingModel::ClipVitB32))
+            }
+            ImageModel::Fastembed(ImageEmbeddingModel::NomicEmbedVisionV15) => {
+                Some(Model::Fastembed(EmbeddingModel::NomicEmbedTextV15))
+            }
+            ImageModel::Fastembed(_) => None,
+            ImageModel::Hash(name) => Some(Model::Hash(name.clone())),
+        }
+    }
+}
+
+/// An image model run in-process by fastembed.
+pub(crate) struct FastembedImage {
+    pub(crate) model: ImageEmbedding,
+    pub(crate) batch_size: usize,
+}
+
+impl ImageEmbedder for FastembedImage {
+    fn embed_images(&self, images: Vec<Vec<u8>>) -> Result<Vec<Vec<f32>>, fastembed::Error> {
+        // fastembed only reads images from files, and tells their format by
+        // extension.
+        let files = images
+            .iter()
+            .map(|image| {
+                let format = ::image::guess_format(image)?;
+                let extension = format.extensions_str().first().copied().unwrap_or("img");
+                let mut file = tempfile::Builder::new()
+                    .prefix("rusticle-image-")
+                    .suffix(&format!(".{}", extension))
+                    .tempfile()?;
+                file.write_all(image)?;
+                Ok(file)
+            })
+            .collect::<Result<Vec<_>, fastembed::Error>>()?;
+        let paths: Vec<_> = files.iter().map(|file| file.path()).collect();
+        self.model.embed(paths, Some(self.batch_size))
+    }
+}
+
+/// Whether `bytes` start like an image in a format that can be decoded.
+pub(crate) fn is_image(bytes: &[u8]) -> bool {
+    ::image::guess_format(bytes).is_ok()
+}
+
+/// Decodes an image sent as base64, bare or as a `data:` URL.
+pub(crate) fn decode_base64(encoded: &str) -> Result<Vec<u8>, base64::DecodeError> {
+    let data = match encoded.strip_prefix("data:") {
+        Some(url) => url.split_once(',').map_or(url, |(_, data)| data),
+        None => encoded,
+    };
+    base64::engine::general_purpose::STANDARD.decode(data.trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_base64() {
+        assert_eq!(decode_base64("aGVsbG8=").unwrap(), b"hello");
+        assert_eq!(
+            decode_base64("data:image/png;base64,aGVsbG8=").unwrap(),
+            b"hello"
+        );
+        assert!(decode_base64("not base64!").is_err());
+
+        assert!(is_image(b"\x89PNG\r\n\x1a\nrest of the file"));
+        assert!(!is_image(b"hello"));
+    }
+
+    #[test]
+    fn test_text_models() {
+        let clip = ImageModel::Fastembed(ImageEmbeddingModel::ClipVitB32);
+        assert_eq!(clip.name(), "clip-vit-b-32-vision");
+        assert_eq!(clip.text_model().unwrap().name(), "clip-vit-b-32-text");
+        assert_eq!(
+            ImageModel::Fastembed(ImageEmbeddingModel::Resnet50).text_model(),
+            None
+        );
+    }
+}
@@ -0,0 +1,131 @@
+//! Optional full-text index over a collection's source text, used by keyword
+//! and hybrid search.
+//!
+//! The index is a contentless FTS5 table sharing rowids with the `vec0`
+//! table, so it ranks rows without storing a second copy of the text.
+
+use rusqlite::Connection;
+
+use crate::collection::{CollectionName, Schema};
+
+pub(crate) fn create(conn: &Connection, name: &CollectionName) -> rusqlite::Result<()> {
+    conn.execute(
+        &format!(
+            "CREATE VIRTUAL TABLE {} USING fts5(text, content='', contentless_delete=1)",
+            name.fts_table()
+        ),
+        (),
+    )?;
+    Ok(())
+}
+
+/// Indexes `text` under `rowid`, replacing whatever was indexed there before.
+/// Does nothing for collections created without a keyword index.
+pub(crate) fn index(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+    rowid: i64,
+    text: &str,
+) -> rusqlite::Result<()> {
+    if !schema.keyword_index {
+        return Ok(());
+    }
+    conn.execute(
+        &format!("DELETE FROM {} WHERE rowid = ?1", name.fts_table()),
+        [rowid],
+    )?;
+    conn.execute(
+        &format!(
+            "INSERT INTO {} (rowid, text) VALUES (?1, ?2)",
+            name.fts_table()
+        ),
+        rusqlite::params![rowid, text],
+    )?;
+    Ok(())
+}
+
+/// Drops whatever is indexed under `rowid`. Does nothing for collections
+/// created without a keyword index.
+pub(crate) fn remove(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+    rowid: i64,
+) -> rusqlite::Result<()> {
+    if !schema.keyword_index {
+        return Ok(());
+    }
+    conn.execute(
+        &format!("DELETE FROM {} WHERE rowid = ?1", name.fts_table()),
+        [rowid],
+    )?;
+    Ok(())
+}
+
+/// Turns free text into an FTS5 query matching any of its words.
+///
+/// Each whitespace-separated word becomes a quoted phrase, so punctuation in
+/// queries such as `C++` or `vec_distance_l2` can't be read as FTS5 syntax;
+/// the tokenizer still splits identifiers into their parts and matches them
+/// as a phrase. Returns `None` when there is nothing to search for.
+pub(crate) fn match_query(text: &str) -> Option<String> {
+    let phrases: Vec<_> = text
+        .split_whitespace()
+        .filter(|word| word.chars().any(char::is_alphanumeric))
+        .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+        .collect();
+    if phrases.is_empty() {
+        None
+    } else {
+        Some(phrases.join(" OR "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collection;
+
+    #[test]
+    fn test_match_query() {
+        assert_eq!(match_query("rust sqlite").unwrap(), r#""rust" OR "sqlite""#);
+        assert_eq!(
+            match_query(r#"C++ say "hi" -- ***"#).unwrap(),
+            r#""C++" OR "say" OR """hi""""#
+        );
+        assert_eq!(match_query("  ... !!"), None);
+        assert_eq!(match_query(""), None);
+    }
+
+    #[test]
+    fn test_index_replaces_text() {
+        crate::web::register_sqlite_vec();
+        let conn = Connection::open_in_memory().unwrap();
+        collection::init_catalog(&conn).unwrap();
+        let name: CollectionName = "posts".parse().unwrap();
+        let schema = collection::Schema {
+            dimension: 2,
+            keyword_index: true,
+            ..Default::default()
+        };
+        collection::create(&conn, &name, &schema).unwrap();
+
+        index(&conn, &name, &schema, 1, "ownership in rust").unwrap();
+        index(&conn, &name, &schema, 1, "goroutines in go").unwrap();
+
+        let count = |query: &str| -> i64 {
+            conn.query_row(
+                &format!(
+                    "SELECT count(*) FROM {0} WHERE {0} MATCH ?1",
+                    name.fts_table()
+                ),
+                [match_query(query).unwrap()],
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        assert_eq!(count("rust"), 0);
+        assert_eq!(count("goroutines"), 1);
+    }
+}
@@ -1,5 +1,20 @@
+mod batch;
+mod cache;
+mod chunk;
+mod collection;
 mod config;
+mod document;
 mod embedding;
+mod filter;
+mod hash;
+mod image;
+mod keyword;
+mod metric;
+mod models;
+mod remote;
+mod search;
+mod sparse;
+mod storage;
 mod web;
 pub use crate::web::web_entry;
 
@@ -26,6 +41,56 @@ fn cli() -> Command {
                         .default_value("config.toml"),
                 ),
         )
+        .subcommand(
+            Command::new("models")
+                .about("Manages the models in the download cache")
+                .subcommand_required(true)
+                .arg_required_else_help(true)
+                .arg(
+                    arg!(--"config" <PATH>)
+                        .short('c')
+                        .default_value("config.toml")
+                        .global(true),
+                )
+                .subcommand(
+                    Command::new("list").about("Lists models and whether they are downloaded"),
+                )
+                .subcommand(
+                    Command::new("download")
+                        .about("Downloads a model into the cache")
+                        .arg(arg!(<NAME> "Model to download")),
+                )
+                .subcommand(
+                    Command::new("verify").about(
+                        "Checks that the cached and configured models are complete and load",
+                    ),
+                )
+                .subcommand(
+                    Command::new("remove")
+                        .about("Deletes a model from the cache")
+                        .arg(arg!(<NAME> "Model to delete")),
+                ),
+        )
+        .subcommand(
+            Command::new("bench")
+                .about("Measures embedding throughput with and without batching")
+                .arg(
+                    arg!(--"config" <PATH>)
+                        .short('c')
+                        .default_value("config.toml"),
+                )
+                .arg(arg!(--"model" <NAME> "Model to measure, the default one if not given"))
+                .arg(
+                    arg!(--"clients" <INTEGER> "Threads sending searches at once")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("8"),
+                )
+                .arg(
+                    arg!(--"requests" <INTEGER> "Searches sent by each thread, one at a time")
+                        .value_parser(clap::value_parser!(usize))
+                        .default_value("50"),
+                ),
+        )
 }
 
 fn main() {
@@ -40,6 +105,45 @@ fn main() {
             let config = config::Config::from_file(config_file);
             web_entry(config).unwrap();
         }
+        Some(("models", sub_m)) => {
+            let config_file = sub_m
+                .get_one::<String>("config")
+                .map(String::as_str)
+                .unwrap_or("config.toml");
+            let config = config::Config::from_file(config_file);
+            let name = |m: &clap::ArgMatches| m.get_one::<String>("NAME").unwrap().clone();
+
+            let mut out = std::io::stdout();
+            let result = match sub_m.subcommand() {
+                Some(("list", _)) => models::list(&config, &mut out),
+                Some(("download", m)) => models::download(&config, &name(m), &mut out),
+                Some(("verify", _)) => models::verify(&config, &mut out).and_then(|ok| {
+                    ok.then_some(())
+                        .ok_or_else(|| std::io::Error::other("Some models failed verification"))
+                }),
+                Some(("remove", m)) => models::remove(&config, &name(m), &mut out),
+                _ => unreachable!("clap requires a subcommand"),
+            };
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        Some(("bench", sub_m)) => {
+            let config_file = sub_m.get_one::<String>("config").unwrap();
+            let config = config::Config::from_file(config_file);
+            let result = batch::bench(
+                &config,
+                sub_m.get_one::<String>("model").map(String::as_str),
+                *sub_m.get_one::<usize>("clients").unwrap(),
+                *sub_m.get_one::<usize>("requests").unwrap(),
+                &mut std::io::stdout(),
+            );
+            if let Err(e) = result {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
         _ => {
             println!("No subcommand found");
         }
@@ -92,4 +196,48 @@ mod tests {
         app.try_get_matches_from(vec!["rusticle", "serve", "--port", "999999"])
             .unwrap();
     }
+
+    #[test]
+    fn test_cli_models() {
+        let matches = cli()
+            .try_get_matches_from(vec![
+                "rusticle",
+                "models",
+                "download",
+                "bge-small-en-v1.5",
+                "-c",
+                "image.toml",
+            ])
+            .unwrap();
+
+        let Some(("models", sub_m)) = matches.subcommand() else {
+            panic!("expected the models subcommand");
+        };
+        assert_eq!(sub_m.get_one::<String>("config").unwrap(), "image.toml");
+        let Some(("download", download)) = sub_m.subcommand() else {
+            panic!("expected the download subcommand");
+        };
+        assert_eq!(
+            download.get_one::<String>("NAME").unwrap(),
+            "bge-small-en-v1.5"
+        );
+
+        assert!(cli()
+            .try_get_matches_from(vec!["rusticle", "models", "remove"])
+            .is_err());
+    }
+
+    #[test]
+    fn test_cli_bench() {
+        let matches = cli()
+            .try_get_matches_from(vec!["rusticle", "bench", "--clients", "16"])
+            .unwrap();
+
+        let Some(("bench", sub_m)) = matches.subcommand() else {
+            panic!("expected the bench subcommand");
+        };
+        assert_eq!(sub_m.get_one::<usize>("clients"), Some(&16));
+        assert_eq!(sub_m.get_one::<usize>("requests"), Some(&50));
+        assert_eq!(sub_m.get_one::<String>("model"), None);
+    }
 }
@@ -0,0 +1,174 @@
+//! Distance metrics a collection can be created with.
+//!
+//! Searches report a `similarity` where higher always means closer, derived
+//! from the raw distance as follows:
+//!
+//! | metric   | distance               | similarity                          |
+//! |----------|------------------------|-------------------------------------|
+//! | `cosine` | `1 - cos(a, b)`        | `cos(a, b)`, in `[-1, 1]`           |
+//! | `l2`     | Euclidean distance     | `1 / (1 + distance)`, in `(0, 1]`   |
+//! | `l1`     | Manhattan distance     | `1 / (1 + distance)`, in `(0, 1]`   |
+//! | `dot`    | `-(a · b)`             | `a · b`, unbounded                  |
+//!
+//! sqlite-vec has no dot product index, so `dot` collections are stored in an
+//! L2 `vec0` table and searched with an exhaustive scan.
+
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Metric {
+    #[default]
+    Cosine,
+    L2,
+    L1,
+    Dot,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct UnknownMetric(String);
+
+impl Metric {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Cosine => "cosine",
+            Self::L2 => "l2",
+            Self::L1 => "l1",
+            Self::Dot => "dot",
+        }
+    }
+
+    /// The `distance_metric` the backing `vec0` table is created with.
+    pub(crate) fn vec0_metric(self) -> &'static str {
+        match self {
+            Self::Dot => "l2",
+            metric => metric.as_str(),
+        }
+    }
+
+    /// Distance between two vectors as sqlite-vec would report it in a search.
+    pub(crate) fn distance(self, a: &[f32], b: &[f32]) -> f32 {
+        let pairs = a.iter().zip(b);
+        match self {
+            Self::Cosine => {
+                let dot: f32 = pairs.map(|(x, y)| x * y).sum();
+                let norms = norm(a) * norm(b);
+                if norms == 0.0 {
+                    1.0
+                } else {
+                    1.0 - dot / norms
+                }
+            }
+            Self::L2 => pairs.map(|(x, y)| (x - y) * (x - y)).sum::<f32>().sqrt(),
+            Self::L1 => pairs.map(|(x, y)| (x - y).abs()).sum(),
+            Self::Dot => -pairs.map(|(x, y)| x * y).sum::<f32>(),
+        }
+    }
+
+    /// Turns a distance as returned by a search into a similarity score.
+    pub(crate) fn similarity(self, distance: f32) -> f32 {
+        match self {
+            Self::Cosine => 1.0 - distance,
+            Self::L2 | Self::L1 => 1.0 / (1.0 + distance),
+            Self::Dot => -distance,
+        }
+    }
+}
+
+fn norm(v: &[f32]) -> f32 {
+    v.iter().map(|x| x * x).sum::<f32>().sqrt()
+}
+
+impl FromStr for Metric {
+    type Err = UnknownMetric;
+
+    fn from_str(metric: &str) -> Result<Self, Self::Err> {
+        match metric {
+            "cosine" => Ok(Self::Cosine),
+            "l2" => Ok(Self::L2),
+            "l1" => Ok(Self::L1),
+            "dot" => Ok(Self::Dot),
+            _ => Err(UnknownMetric(metric.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Metric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for UnknownMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown metric {:?}, expected cosine, l2, l1 or dot",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownMetric {}
+
+impl ToSql for Metric {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+impl FromSql for Metric {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: UnknownMetric| FromSqlError::Other(e.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        for metric in [Metric::Cosine, Metric::L2, Metric::L1, Metric::Dot] {
+            assert_eq!(metric.as_str().parse::<Metric>(), Ok(metric));
+        }
+        assert_eq!(
+            "hamming".parse::<Metric>(),
+            Err(UnknownMetric("hamming".to_string()))
+        );
+        assert_eq!(Metric::Dot.vec0_metric(), "l2");
+        assert_eq!(Metric::L1.vec0_metric(), "l1");
+    }
+
+    #[test]
+    fn test_similarity_orders_like_distance() {
+        for metric in [Metric::Cosine, Metric::L2, Metric::L1, Metric::Dot] {
+            assert!(
+                metric.similarity(0.5) > metric.similarity(1.5),
+                "{}",
+                metric
+            );
+        }
+        assert_eq!(Metric::Cosine.similarity(0.0), 1.0);
+        assert_eq!(Metric::L2.similarity(0.0), 1.0);
+        assert_eq!(Metric::L2.similarity(1.0), 0.5);
+        assert_eq!(Metric::Dot.similarity(-3.0), 3.0);
+    }
+
+    #[test]
+    fn test_distance() {
+        let (a, b) = ([3.0, 0.0], [0.0, 4.0]);
+        assert_eq!(Metric::Cosine.distance(&a, &b), 1.0);
+        assert_eq!(Metric::Cosine.distance(&a, &[1.0, 0.0]), 0.0);
+        assert_eq!(Metric::Cosine.distance(&a, &[0.0, 0.0]), 1.0);
+        assert_eq!(Metric::L2.distance(&a, &b), 5.0);
+        assert_eq!(Metric::L1.distance(&a, &b), 7.0);
+        assert_eq!(Metric::Dot.distance(&a, &[2.0, 1.0]), -6.0);
+    }
+}
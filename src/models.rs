@@ -0,0 +1,531 @@
+//! Model files in the download cache, and the `rusticle models` commands that
+//! fetch, check and delete them ahead of time, so a server can start from a
+//! container image without reaching Hugging Face.
+//!
+//! fastembed downloads through hf-hub, which keeps each repository under
+//! `models--{owner}--{name}` in the cache directory, with `refs/main` naming
+//! the snapshot directory its files are in.
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use fastembed::{
+    EmbeddingModel, ImageEmbedding, ImageEmbeddingModel, ImageInitOptions, InitOptions,
+    RerankInitOptions, RerankerModel, SparseInitOptions, SparseTextEmbedding, TextEmbedding,
+    TextRerank,
+};
+
+use crate::config::Config;
+
+/// File fastembed reads next to an image model to resize and normalize
+/// images for it.
+const PREPROCESSOR_FILE: &str = "preprocessor_config.json";
+
+/// Files fastembed reads next to the ONNX model to build its tokenizer.
+const TOKENIZER_FILES: &[&str] = &[
+    "tokenizer.json",
+    "config.json",
+    "special_tokens_map.json",
+    "tokenizer_config.json",
+];
+
+/// A model fastembed downloads from Hugging Face.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Remote {
+    Embedding(EmbeddingModel),
+    Image(ImageEmbeddingModel),
+    /// A sparse model by name, see [`crate::config::sparse_model`].
+    Sparse(&'static str),
+    Reranker(RerankerModel),
+}
+
+impl Remote {
+    /// Every model that can be downloaded, text embedding models first, then
+    /// image, sparse ones and rerankers, each sorted by name.
+    fn all() -> Vec<Self> {
+        let mut embedding: Vec<_> = TextEmbedding::list_supported_models()
+            .into_iter()
+            .map(|info| Remote::Embedding(info.model))
+            .collect();
+        embedding.sort_by_key(Remote::name);
+        let mut images: Vec<_> = ImageEmbedding::list_supported_models()
+            .into_iter()
+            .map(|info| Remote::Image(info.model))
+            .collect();
+        images.sort_by_key(Remote::name);
+        embedding.extend(images);
+        let mut sparse: Vec<_> = crate::config::sparse_models().map(Remote::Sparse).collect();
+        sparse.sort_by_key(Remote::name);
+        embedding.extend(sparse);
+        let mut rerankers: Vec<_> = TextRerank::list_supported_models()
+            .into_iter()
+            .map(|info| Remote::Reranker(info.model))
+            .collect();
+        rerankers.sort_by_key(Remote::name);
+        embedding.extend(rerankers);
+        embedding
+    }
+
+    /// Looks up a model by its config name.
+    fn resolve(name: &str) -> Option<Self> {
+        crate::config::model(name)
+            .map(Remote::Embedding)
+            .or_else(|| crate::config::image_model(name).map(Remote::Image))
+            .or_else(|| crate::config::sparse_model(name).map(Remote::Sparse))
+            .or_else(|| crate::config::reranker(name).map(Remote::Reranker))
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Remote::Embedding(model) => crate::config::model_name(model),
+            Remote::Image(model) => crate::config::image_model_name(model),
+            Remote::Sparse(name) => name,
+            Remote::Reranker(model) => crate::config::reranker_name(model),
+        }
+    }
+
+    /// The Hugging Face repository the model is downloaded from.
+    fn repository(&self) -> String {
+        match self {
+            Remote::Embedding(model) => model.to_string(),
+            Remote::Image(model) => model.to_string(),
+            Remote::Sparse(name) => crate::config::sparse_repository(name)
+                .unwrap_or_default()
+                .to_string(),
+            Remote::Reranker(model) => model.to_string(),
+        }
+    }
+
+    /// Files the model is loaded from, relative to its snapshot.
+    fn files(&self) -> Vec<String> {
+        let mut files = match self {
+            Remote::Embedding(model) => {
+                let mut files = TextEmbedding::get_model_info(model)
+                    .map(|info| vec![info.model_file.clone()])
+                    .unwrap_or_default();
+                // Its weights are too large for a single ONNX file.
+                if *model == EmbeddingModel::MultilingualE5Large {
+                    files.push("model.onnx_data".to_string());
+                }
+                files
+            }
+            // Images are preprocessed rather than tokenized.
+            Remote::Image(model) => {
+                let info = ImageEmbedding::get_model_info(model);
+                return vec![info.model_file, PREPROCESSOR_FILE.to_string()];
+            }
+            Remote::Sparse(_) => {
+                let repository = self.repository();
+                SparseTextEmbedding::list_supported_models()
+                    .into_iter()
+                    .filter(|info| info.model_code == repository)
+                    .map(|info| info.model_file)
+                    .collect()
+            }
+            Remote::Reranker(model) => {
+                let info = TextRerank::get_model_info(model);
+                let mut files = vec![info.model_file];
+                files.extend(info.additional_files);
+                files
+            }
+        };
+        files.extend(TOKENIZER_FILES.iter().map(|file| file.to_string()));
+        files
+    }
+
+    /// Where the model's repository is kept in `cache_dir`.
+    fn path(&self, cache_dir: &Path) -> PathBuf {
+        cache_dir.join(format!("models--{}", self.repository().replace('/', "--")))
+    }
+
+    /// The model's files that aren't in `cache_dir` yet.
+    pub(crate) fn missing(&self, cache_dir: &Path) -> Vec<String> {
+        let path = self.path(cache_dir);
+        let snapshot = std::fs::read_to_string(path.join("refs").join("main"))
+            .map(|commit| path.join("snapshots").join(commit.trim()));
+        self.files()
+            .into_iter()
+            .filter(|file| match &snapshot {
+                Ok(snapshot) => !snapshot.join(file).exists(),
+                Err(_) => true,
+            })
+            .collect()
+    }
+
+    fn status(&self, cache_dir: &Path) -> &'static str {
+        if !self.path(cache_dir).exists() {
+            "-"
+        } else if self.missing(cache_dir).is_empty() {
+            "cached"
+        } else {
+            "incomplete"
+        }
+    }
+
+    /// Loads the model from `cache_dir`, downloading whatever is missing.
+    fn load(&self, cache_dir: &Path, show_download_progress: bool) -> Result<(), fastembed::Error> {
+        match self {
+            Remote::Embedding(model) => TextEmbedding::try_new(
+                InitOptions::new(model.clone())
+                    .with_cache_dir(cache_dir.to_path_buf())
+                    .with_show_download_progress(show_download_progress),
+            )
+            .map(drop),
+            Remote::Image(model) => ImageEmbedding::try_new(
+                ImageInitOptions::new(model.clone())
+                    .with_cache_dir(cache_dir.to_path_buf())
+                    .with_show_download_progress(show_download_progress),
+            )
+            .map(drop),
+            Remote::Sparse(name) => load_sparse(name, cache_dir, show_download_progress).map(drop),
+            Remote::Reranker(model) => TextRerank::try_new(
+                RerankInitOptions::new(model.clone())
+                    .with_cache_dir(cache_dir.to_path_buf())
+                    .with_show_download_progress(show_download_progress),
+            )
+            .map(drop),
+        }
+    }
+}
+
+/// Loads one of fastembed's sparse models, downloading whatever is missing
+/// from `cache_dir`.
+pub(crate) fn load_sparse(
+    name: &str,
+    cache_dir: &Path,
+    show_download_progress: bool,
+) -> Result<SparseTextEmbedding, fastembed::Error> {
+    let repository = crate::config::sparse_repository(name)
+        .ok_or_else(|| fastembed::Error::msg(format!("Unknown sparse model: {}", name)))?;
+    let info = SparseTextEmbedding::list_supported_models()
+        .into_iter()
+        .find(|info| info.model_code == repository)
+        .ok_or_else(|| fastembed::Error::msg(format!("Unknown sparse model: {}", name)))?;
+    SparseTextEmbedding::try_new(
+        SparseInitOptions::new(info.model)
+            .with_cache_dir(cache_dir.to_path_buf())
+            .with_show_download_progress(show_download_progress),
+    )
+}
+
+/// Writes every model that can be used, whether it is downloaded, and which
+/// ones the server uses by default.
+pub(crate) fn list(config: &Config, out: &mut impl Write) -> io::Result<()> {
+    let cache_dir = &config.embedding.cache_dir;
+    writeln!(out, "{:<44} {:>9}  STATUS", "NAME", "DIMENSION")?;
+    for remote in Remote::all() {
+        let dimension = match &remote {
+            Remote::Embedding(model) => TextEmbedding::get_model_info(model)
+                .map(|info| info.dim.to_string())
+                .unwrap_or_default(),
+            Remote::Image(model) => ImageEmbedding::get_model_info(model).dim.to_string(),
+            Remote::Sparse(_) => "sparse".to_string(),
+            Remote::Reranker(_) => "-".to_string(),
+        };
+        writeln!(
+            out,
+            "{:<44} {:>9}  {}{}",
+            remote.name(),
+            dimension,
+            remote.status(cache_dir),
+            if is_default(config, &remote) {
+                " (default)"
+            } else {
+                ""
+            }
+        )?;
+    }
+    for (name, dimension, source) in configured(config) {
+        writeln!(
+            out,
+            "{:<44} {:>9}  {}{}",
+            name,
+            dimension,
+            source,
+            if name == config.embedding.model {
+                " (default)"
+            } else {
+                ""
+            }
+        )?;
+    }
+    Ok(())
+}
+
+/// Downloads a model into the cache, and checks that it loads.
+pub(crate) fn download(config: &Config, name: &str, out: &mut impl Write) -> io::Result<()> {
+    if let Some((_, _, source)) = configured(config).into_iter().find(|(n, ..)| *n == name) {
+        return Err(io::Error::other(format!(
+            "{} is configured as {}, there is nothing to download",
+            name, source
+        )));
+    }
+    let remote = resolve(name)?;
+    let cache_dir = &config.embedding.cache_dir;
+    remote
+        .load(cache_dir, true)
+        .map_err(|e| io::Error::other(format!("Failed to download {}: {}", remote.name(), e)))?;
+    writeln!(
+        out,
+        "Downloaded {} to {}",
+        remote.name(),
+        cache_dir.display()
+    )
+}
+
+/// Checks that every model in the cache, the default one and each local one
+/// is complete and loads, so a server starting from it won't need to
+/// download anything. Returns whether they all do.
+pub(crate) fn verify(config: &Config, out: &mut impl Write) -> io::Result<bool> {
+    let cache_dir = &config.embedding.cache_dir;
+    let mut ok = true;
+    for remote in Remote::all() {
+        // The reranker is only loaded once a search asks for it, but the
+        // server can't start without its embedding model.
+        let required = matches!(remote, Remote::Embedding(_)) && is_default(config, &remote);
+        if !required && !remote.path(cache_dir).exists() {
+            continue;
+        }
+        let missing = remote.missing(cache_dir);
+        let problem = if !missing.is_empty() {
+            Some(format!("missing {}", missing.join(", ")))
+        } else {
+            remote
+                .load(cache_dir, false)
+                .err()
+                .map(|e| format!("failed to load: {}", e))
+        };
+        ok &= report(out, remote.name(), problem)?;
+    }
+    let mut local: Vec<_> = config.embedding.local.iter().collect();
+    local.sort_by_key(|(name, _)| *name);
+    for (name, model) in local {
+        let problem = model.load().err().map(|e| e.to_string());
+        ok &= report(out, name, problem)?;
+    }
+    Ok(ok)
+}
+
+/// Deletes a model's repository from the cache. Models sharing the
+/// repository, such as a quantized variant, are deleted with it.
+pub(crate) fn remove(config: &Config, name: &str, out: &mut impl Write) -> io::Result<()> {
+    let remote = resolve(name)?;
+    let path = remote.path(&config.embedding.cache_dir);
+    if !path.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not downloaded", remote.name()),
+        ));
+    }
+    std::fs::remove_dir_all(&path)?;
+    writeln!(out, "Removed {} from {}", remote.name(), path.display())?;
+    let shared: Vec<_> = Remote::all()
+        .into_iter()
+        .filter(|other| *other != remote && other.repository() == remote.repository())
+        .map(|other| other.name())
+        .collect();
+    if !shared.is_empty() {
+        writeln!(
+            out,
+            "Also removed {}, stored alongside it",
+            shared.join(", ")
+        )?;
+    }
+    Ok(())
+}
+
+/// The models configured in `config.toml` rather than downloaded, by name,
+/// with their dimension and where their vectors come from.
+fn configured(config: &Config) -> Vec<(&str, usize, String)> {
+    let embedding = &config.embedding;
+    let local = embedding.local.iter().map(|(name, model)| {
+        let source = format!("local {}", model.path.display());
+        (name.as_str(), model.dimension, source)
+    });
+    let served = embedding.remote.iter().map(|(name, model)| {
+        let source = format!("remote {}", model.url);
+        (name.as_str(), model.dimension, source)
+    });
+    let hashed = embedding
+        .hash
+        .iter()
+        .map(|(name, model)| (name.as_str(), model.dimension, "hash".to_string()));
+    let mut configured: Vec<_> = local.chain(served).chain(hashed).collect();
+    configured.sort_by_key(|(name, ..)| *name);
+    configured
+}
+
+fn resolve(name: &str) -> io::Result<Remote> {
+    Remote::resolve(name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Unknown model: {}", name)))
+}
+
+fn is_default(config: &Config, remote: &Remote) -> bool {
+    match remote {
+        Remote::Embedding(model) => {
+            crate::config::model(&config.embedding.model).as_ref() == Some(model)
+                && !configured(config)
+                    .iter()
+                    .any(|(name, ..)| *name == config.embedding.model)
+        }
+        Remote::Image(_) | Remote::Sparse(_) => false,
+        Remote::Reranker(model) => *model == config.rerank.model,
+    }
+}
+
+/// Writes how a model fared in `verify`, returning whether it passed.
+fn report(out: &mut impl Write, name: &str, problem: Option<String>) -> io::Result<bool> {
+    match problem {
+        None => writeln!(out, "{}: ok", name).map(|_| true),
+        Some(problem) => writeln!(out, "{}: {}", name, problem).map(|_| false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cache directory unique to the test, removed when it ends.
+    struct Cache(PathBuf);
+
+    impl Cache {
+        fn new(test: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("rusticle-{}-{}", test, std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        /// Lays out `files` of `remote` the way hf-hub stores a download.
+        fn add(&self, remote: &Remote, files: &[String]) {
+            let path = remote.path(&self.0);
+            std::fs::create_dir_all(path.join("refs")).unwrap();
+            std::fs::write(path.join("refs").join("main"), "abc123\n").unwrap();
+            for file in files {
+                let file = path.join("snapshots").join("abc123").join(file);
+                std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+                std::fs::write(file, b"not a model").unwrap();
+            }
+        }
+
+        fn config(&self) -> Config {
+            let mut config = Config::default();
+            config.embedding.model = "all-minilm-l6-v2".to_string();
+            config.embedding.cache_dir = self.0.clone();
+            config
+        }
+    }
+
+    impl Drop for Cache {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_missing_files() {
+        let cache = Cache::new("missing");
+        let minilm = Remote::Embedding(EmbeddingModel::AllMiniLML6V2);
+        assert_eq!(minilm.missing(&cache.0), minilm.files());
+        assert_eq!(minilm.status(&cache.0), "-");
+
+        let files = minilm.files();
+        cache.add(&minilm, &files[..2]);
+        assert_eq!(minilm.missing(&cache.0), files[2..].to_vec());
+        assert_eq!(minilm.status(&cache.0), "incomplete");
+
+        cache.add(&minilm, &files);
+        assert!(minilm.missing(&cache.0).is_empty());
+        assert_eq!(minilm.status(&cache.0), "cached");
+        assert!(files.contains(&"model.onnx".to_string()));
+        assert!(minilm
+            .path(&cache.0)
+            .ends_with("models--Qdrant--all-MiniLM-L6-v2-onnx"));
+
+        let splade = Remote::resolve("SPLADE-PP-en-v1").unwrap();
+        assert_eq!(splade.name(), "splade-pp-en-v1");
+        assert_eq!(splade.files()[0], "model.onnx");
+        assert!(splade
+            .path(&cache.0)
+            .ends_with("models--Qdrant--Splade_PP_en_v1"));
+
+        let clip = Remote::resolve("clip-vit-b-32-vision").unwrap();
+        assert_eq!(clip.files(), vec!["model.onnx", "preprocessor_config.json"]);
+        assert!(clip
+            .path(&cache.0)
+            .ends_with("models--Qdrant--clip-ViT-B-32-vision"));
+    }
+
+    #[test]
+    fn test_list() {
+        let cache = Cache::new("list");
+        let minilm = Remote::Embedding(EmbeddingModel::AllMiniLML6V2);
+        cache.add(&minilm, &minilm.files());
+
+        let mut out = Vec::new();
+        list(&cache.config(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let line = |name: &str| {
+            out.lines()
+                .find(|line| line.split_whitespace().next() == Some(name))
+                .unwrap()
+                .split_whitespace()
+                .skip(1)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(line("all-minilm-l6-v2"), vec!["384", "cached", "(default)"]);
+        assert_eq!(line("bge-small-en-v1.5"), vec!["384", "-"]);
+        assert_eq!(line("splade-pp-en-v1"), vec!["sparse", "-"]);
+        assert_eq!(line("clip-vit-b-32-vision"), vec!["512", "-"]);
+        assert_eq!(line("resnet50"), vec!["2048", "-"]);
+        assert_eq!(line("bge-reranker-base"), vec!["-", "-", "(default)"]);
+    }
+
+    #[test]
+    fn test_verify() {
+        let cache = Cache::new("verify");
+        let mut out = Vec::new();
+        assert!(!verify(&cache.config(), &mut out).unwrap());
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.starts_with("all-minilm-l6-v2: missing model.onnx"),
+            "{}",
+            out
+        );
+
+        // Files that are all there but can't be loaded fail as well.
+        let minilm = Remote::Embedding(EmbeddingModel::AllMiniLML6V2);
+        cache.add(&minilm, &minilm.files());
+        let mut out = Vec::new();
+        assert!(!verify(&cache.config(), &mut out).unwrap());
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.starts_with("all-minilm-l6-v2: failed to load"),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn test_remove() {
+        let cache = Cache::new("remove");
+        let nomic = Remote::Embedding(EmbeddingModel::NomicEmbedTextV15);
+        cache.add(&nomic, &nomic.files());
+
+        let mut out = Vec::new();
+        remove(&cache.config(), "nomic-embed-text-v1.5", &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.contains("Also removed nomic-embed-text-v1.5-q"),
+            "{}",
+            out
+        );
+        assert!(!nomic.path(&cache.0).exists());
+
+        let error = remove(&cache.config(), "nomic-embed-text-v1.5", &mut Vec::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        let error = remove(&cache.config(), "word2vec", &mut Vec::new()).unwrap_err();
+        assert_eq!(error.to_string(), "Unknown model: word2vec");
+    }
+}
@@ -0,0 +1,405 @@
+//! Embeddings from an HTTP server speaking OpenAI's `/v1/embeddings` API, such
+//! as OpenAI itself, llama.cpp's server or Ollama.
+//!
+//! Requests block, retries included, so callers embed on the blocking thread
+//! pool rather than on an actix worker.
+
+use std::thread;
+use std::time::Duration;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::embedding::Embedder;
+
+/// Wait before the first retry, doubled before each following one.
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+/// A model served over HTTP, as configured under `[embedding.remote]`.
+#[derive(Deserialize, Debug, Clone)]
+pub(crate) struct RemoteModel {
+    /// Endpoint embeddings are requested from, such as
+    /// `http://localhost:11434/v1/embeddings`.
+    pub(crate) url: String,
+    /// Model name sent to the server. Defaults to the name the model is
+    /// configured under.
+    #[serde(default)]
+    pub(crate) model: Option<String>,
+    /// Length of the vectors the model produces.
+    pub(crate) dimension: usize,
+    /// Sent as a bearer token.
+    #[serde(default)]
+    pub(crate) api_key: Option<String>,
+    /// How long a request may take, in seconds, before it is retried.
+    #[serde(default = "default_timeout")]
+    pub(crate) timeout: u64,
+    /// How often a request that timed out or failed on the server's side is
+    /// retried before giving up.
+    #[serde(default = "default_retries")]
+    pub(crate) retries: u32,
+    /// Number of texts sent in one request.
+    #[serde(default = "default_batch_size")]
+    pub(crate) batch_size: usize,
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+fn default_retries() -> u32 {
+    2
+}
+
+fn default_batch_size() -> usize {
+    64
+}
+
+#[derive(Serialize)]
+struct EmbeddingsRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct EmbeddingsResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    embedding: Vec<f32>,
+    index: usize,
+}
+
+pub(crate) struct RemoteEmbedder {
+    agent: ureq::Agent,
+    url: String,
+    model: String,
+    api_key: Option<String>,
+    dimension: usize,
+    retries: u32,
+    batch_size: usize,
+}
+
+impl RemoteEmbedder {
+    pub(crate) fn new(name: &str, config: &RemoteModel) -> Self {
+        Self {
+            agent: ureq::AgentBuilder::new()
+                .timeout(Duration::from_secs(config.timeout))
+                .build(),
+            url: config.url.clone(),
+            model: config.model.clone().unwrap_or_else(|| name.to_string()),
+            api_key: config.api_key.clone(),
+            dimension: config.dimension,
+            retries: config.retries,
+            batch_size: config.batch_size.max(1),
+        }
+    }
+
+    /// Embeds one batch, retrying failures that may not happen again.
+    fn request(&self, input: &[String]) -> Result<Vec<Vec<f32>>, fastembed::Error> {
+        let mut backoff = RETRY_BACKOFF;
+        let mut attempt = 0;
+        loop {
+            let mut request = self.agent.post(&self.url);
+            if let Some(key) = &self.api_key {
+                request = request.set("Authorization", &format!("Bearer {}", key));
+            }
+            let body = EmbeddingsRequest {
+                model: &self.model,
+                input,
+            };
+            let error = match request.send_json(&body) {
+                Ok(response) => return self.parse(response, input.len()),
+                Err(e) => e,
+            };
+            // Requests the server turned down for what they are fail the
+            // same way however often they are sent.
+            let retryable = match &error {
+                ureq::Error::Status(status, _) => *status == 429 || *status >= 500,
+                ureq::Error::Transport(_) => true,
+            };
+            if !retryable || attempt == self.retries {
+                return Err(fastembed::Error::msg(describe(error, &self.url)));
+            }
+            attempt += 1;
+            warn!(
+                "Embedding request to {} failed, retrying ({}/{}): {}",
+                self.url, attempt, self.retries, error
+            );
+            thread::sleep(backoff);
+            backoff *= 2;
+        }
+    }
+
+    fn parse(
+        &self,
+        response: ureq::Response,
+        expected: usize,
+    ) -> Result<Vec<Vec<f32>>, fastembed::Error> {
+        let mut response: EmbeddingsResponse = response.into_json().map_err(|e| {
+            fastembed::Error::msg(format!("Invalid response from {}: {}", self.url, e))
+        })?;
+        if response.data.len() != expected {
+            return Err(fastembed::Error::msg(format!(
+                "{} returned {} embeddings for {} texts",
+                self.url,
+                response.data.len(),
+                expected
+            )));
+        }
+        // Servers may answer out of order, with each embedding's index
+        // pointing back at its text.
+        response.data.sort_by_key(|data| data.index);
+        if response
+            .data
+            .iter()
+            .enumerate()
+            .any(|(i, data)| data.index != i)
+        {
+            return Err(fastembed::Error::msg(format!(
+                "{} returned embeddings whose indices don't match its texts",
+                self.url
+            )));
+        }
+        if let Some(data) = response
+            .data
+            .iter()
+            .find(|data| data.embedding.len() != self.dimension)
+        {
+            return Err(fastembed::Error::msg(format!(
+                "{} returned {} dimensions for model {}, expected {}",
+                self.url,
+                data.embedding.len(),
+                self.model,
+                self.dimension
+            )));
+        }
+        Ok(response
+            .data
+            .into_iter()
+            .map(|data| data.embedding)
+            .collect())
+    }
+}
+
+impl Embedder for RemoteEmbedder {
+    fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, fastembed::Error> {
+        let mut embeddings = Vec::with_capacity(texts.len());
+        for batch in texts.chunks(self.batch_size) {
+            embeddings.extend(self.request(batch)?);
+        }
+        Ok(embeddings)
+    }
+}
+
+/// Describes a failed request, with the server's own explanation if it
+/// gave one.
+fn describe(error: ureq::Error, url: &str) -> String {
+    match error {
+        ureq::Error::Status(status, response) => {
+            let body = response.into_string().unwrap_or_default();
+            format!("{} responded with {}: {}", url, status, body.trim())
+        }
+        ureq::Error::Transport(transport) => format!("Request to {} failed: {}", url, transport),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    /// A request as the mock server received it.
+    pub(crate) struct Received {
+        authorization: Option<String>,
+        pub(crate) body: serde_json::Value,
+    }
+
+    /// Serves each connection with the next of `responses`, given as a status
+    /// and a body, or `None` to stall until the client gives up. A 200 without
+    /// a body answers with embeddings in reverse order, each
+    /// `[index, length of its text]`.
+    pub(crate) fn mock_server(
+        responses: Vec<Option<(u16, &'static str)>>,
+    ) -> (String, Arc<Mutex<Vec<Received>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/v1/embeddings", listener.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = received.clone();
+        thread::spawn(move || {
+            for (stream, response) in listener.incoming().zip(responses) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                let mut authorization = None;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    let (name, value) = line.split_once(": ").unwrap_or((line, ""));
+                    match name.to_lowercase().as_str() {
+                        "content-length" => length = value.parse().unwrap(),
+                        "authorization" => authorization = Some(value.to_string()),
+                        _ => {}
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+                let (status, content) = match response {
+                    Some((200, "")) => {
+                        let data: Vec<_> = body["input"]
+                            .as_array()
+                            .unwrap()
+                            .iter()
+                            .enumerate()
+                            .rev()
+                            .map(|(i, text)| {
+                                let length = text.as_str().unwrap().len();
+                                serde_json::json!({"embedding": [i, length], "index": i})
+                            })
+                            .collect();
+                        (200, serde_json::json!({"data": data}).to_string())
+                    }
+                    Some((status, content)) => (status, content.to_string()),
+                    None => {
+                        thread::sleep(Duration::from_millis(1500));
+                        (200, String::new())
+                    }
+                };
+                log.lock().unwrap().push(Received {
+                    authorization,
+                    body,
+                });
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    content.len(),
+                    content
+                );
+            }
+        });
+        (url, received)
+    }
+
+    fn model(url: &str) -> RemoteModel {
+        RemoteModel {
+            url: url.to_string(),
+            model: None,
+            dimension: 2,
+            api_key: Some("secret".to_string()),
+            timeout: 1,
+            retries: 2,
+            batch_size: 2,
+        }
+    }
+
+    fn texts(texts: &[&str]) -> Vec<String> {
+        texts.iter().map(|text| text.to_string()).collect()
+    }
+
+    #[test]
+    fn test_batches_in_order() {
+        let (url, received) = mock_server(vec![Some((200, "")), Some((200, ""))]);
+        let embedder = RemoteEmbedder::new("nomic-embed-text", &model(&url));
+
+        let embeddings = embedder.embed(texts(&["a", "bb", "ccc"])).unwrap();
+        assert_eq!(
+            embeddings,
+            vec![vec![0.0, 1.0], vec![1.0, 2.0], vec![0.0, 3.0]]
+        );
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(received[0].body["model"], "nomic-embed-text");
+        assert_eq!(received[0].body["input"], serde_json::json!(["a", "bb"]));
+        assert_eq!(received[1].body["input"], serde_json::json!(["ccc"]));
+        assert_eq!(received[0].authorization.as_deref(), Some("Bearer secret"));
+    }
+
+    #[test]
+    fn test_retries() {
+        let (url, received) =
+            mock_server(vec![Some((503, "loading model")), None, Some((200, ""))]);
+        let embedder = RemoteEmbedder::new("nomic-embed-text", &model(&url));
+
+        let embeddings = embedder.embed(texts(&["a"])).unwrap();
+        assert_eq!(embeddings, vec![vec![0.0, 1.0]]);
+        assert_eq!(received.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_failures() {
+        let (url, received) = mock_server(vec![
+            Some((400, r#"{"error": "input too long"}"#)),
+            Some((500, "out of memory")),
+            Some((500, "out of memory")),
+            Some((500, "out of memory")),
+            Some((
+                200,
+                r#"{"data": [{"embedding": [1.0, 2.0, 3.0], "index": 0}]}"#,
+            )),
+        ]);
+        let mut config = model(&url);
+        config.model = Some("served-name".to_string());
+        let embedder = RemoteEmbedder::new("nomic-embed-text", &config);
+
+        let error = embedder.embed(texts(&["a"])).unwrap_err().to_string();
+        assert!(
+            error.ends_with(r#"responded with 400: {"error": "input too long"}"#),
+            "{}",
+            error
+        );
+        assert_eq!(received.lock().unwrap().len(), 1);
+
+        let error = embedder.embed(texts(&["a"])).unwrap_err().to_string();
+        assert!(
+            error.ends_with("responded with 500: out of memory"),
+            "{}",
+            error
+        );
+        assert_eq!(received.lock().unwrap().len(), 4);
+
+        let error = embedder.embed(texts(&["a"])).unwrap_err().to_string();
+        assert!(
+            error.ends_with("returned 3 dimensions for model served-name, expected 2"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn test_rejects_mismatched_indices() {
+        let (url, _) = mock_server(vec![
+            Some((
+                200,
+                r#"{"data": [{"embedding": [1.0, 2.0], "index": 0},
+                             {"embedding": [3.0, 4.0], "index": 0}]}"#,
+            )),
+            Some((
+                200,
+                r#"{"data": [{"embedding": [1.0, 2.0], "index": 1},
+                             {"embedding": [3.0, 4.0], "index": 2}]}"#,
+            )),
+        ]);
+        let embedder = RemoteEmbedder::new("nomic-embed-text", &model(&url));
+
+        for _ in 0..2 {
+            let error = embedder.embed(texts(&["a", "b"])).unwrap_err().to_string();
+            assert!(
+                error.ends_with("returned embeddings whose indices don't match its texts"),
+                "{}",
+                error
+            );
+        }
+    }
+}
@@ -0,0 +1,833 @@
+use rusqlite::types::Value;
+use rusqlite::Connection;
+use std::collections::{HashMap, HashSet};
+use zerocopy::IntoBytes;
+
+use crate::collection::{CollectionName, Schema};
+use crate::document;
+use crate::filter::Filter;
+use crate::keyword;
+use crate::metric::Metric;
+use crate::sparse::SparseVector;
+use crate::storage;
+
+/// Damping constant for reciprocal rank fusion. 60 is the value from the
+/// original paper and keeps one list's top hit from swamping the other list.
+const RRF_K: f32 = 60.0;
+
+/// Most neighbours sqlite-vec returns from a single KNN query.
+pub(crate) const MAX_K: usize = 4096;
+
+/// A row returned by a nearest-neighbour query. In chunked collections a row
+/// is one chunk, reported with its document's id and metadata.
+pub(crate) struct Hit {
+    pub(crate) rowid: i64,
+    /// Rowid of the document the row belongs to: its parent for a chunk,
+    /// otherwise the row itself.
+    pub(crate) document: i64,
+    pub(crate) id: Option<String>,
+    pub(crate) key: String,
+    pub(crate) distance: f32,
+    pub(crate) metadata: serde_json::Value,
+}
+
+/// Runs a KNN query for `vector` and returns the `limit` closest rows, or
+/// [`MAX_K`] of them if `limit` is larger.
+///
+/// The filter is evaluated inside the `vec0` query as a `rowid IN (...)`
+/// constraint, so it narrows the candidates before the top-k cut and a
+/// filtered search still returns `limit` rows when enough of them match.
+pub(crate) fn knn(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+    vector: &[f32],
+    limit: usize,
+    filter: Option<&Filter>,
+) -> rusqlite::Result<Vec<Hit>> {
+    let (metric, storage) = (schema.metric, schema.storage);
+    let mut params = vec![
+        Value::Blob(storage.encode(vector)),
+        Value::Integer(limit.min(MAX_K) as i64),
+    ];
+    let filter = filter.map(|filter| filter_constraint(name, filter));
+    let constraint = filter.as_ref().map(|(sql, _)| sql);
+
+    let candidates = match metric {
+        // sqlite-vec can't index dot products, so every row is scored with
+        // q·x = (|q|² + |x|² - |q - x|²) / 2, taking |x| as the L2 distance
+        // from the origin.
+        Metric::Dot => {
+            params.push(Value::Real(vector.iter().map(|v| v * v).sum::<f32>() as f64));
+            params.push(Value::Blob(vec![0.0f32; vector.len()].as_bytes().to_vec()));
+            format!(
+                "SELECT rowid, key, -(?3 + o * o - d * d) / 2 AS distance FROM (
+                    SELECT rowid, key, vec_distance_l2(vec, ?4) AS o, vec_distance_l2(vec, ?1) AS d
+                    FROM {} {}
+                ) ORDER BY distance LIMIT ?2",
+                name.quoted(),
+                constraint
+                    .map(|c| format!("WHERE {}", c))
+                    .unwrap_or_default()
+            )
+        }
+        _ => format!(
+            "SELECT rowid, key, distance FROM {} WHERE vec MATCH {} AND k = ?2 {}",
+            name.quoted(),
+            storage.param("?1"),
+            constraint.map(|c| format!("AND {}", c)).unwrap_or_default()
+        ),
+    };
+    if let Some((_, filter_params)) = filter {
+        params.extend(filter_params);
+    }
+
+    let query = format!(
+        "WITH knn AS ({})
+        SELECT knn.rowid, coalesce(c.parent, knn.rowid), d.id, knn.key, knn.distance, d.metadata
+        FROM knn
+        LEFT JOIN {1} c ON c.rowid = knn.rowid
+        LEFT JOIN {1} d ON d.rowid = coalesce(c.parent, knn.rowid)
+        ORDER BY knn.distance",
+        candidates,
+        name.docs_table()
+    );
+
+    let mut stmt = conn.prepare(&query)?;
+    let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+        Ok(Hit {
+            rowid: row.get(0)?,
+            document: row.get(1)?,
+            id: row.get(2)?,
+            key: row.get(3)?,
+            distance: storage.distance(metric, row.get(4)?, vector.len()),
+            metadata: row.get(5)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Recomputes the distance of each hit by comparing the query with the
+/// float32 original of its vector, kept beside the quantized one, and
+/// reorders them. Ranks the candidates of an `int8` or `binary` collection as
+/// a `float32` collection would have.
+pub(crate) fn rescore(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+    query: &[f32],
+    hits: &mut [Hit],
+) -> rusqlite::Result<()> {
+    let vectors = read_vectors(conn, name, schema, hits.iter().map(|hit| hit.rowid))?;
+    for (hit, vector) in hits.iter_mut().zip(vectors) {
+        hit.distance = schema.metric.distance(query, &vector);
+    }
+    hits.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    Ok(())
+}
+
+/// Reads the vectors of the given rows at full precision, in the same order.
+fn read_vectors(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+    rowids: impl Iterator<Item = i64>,
+) -> rusqlite::Result<Vec<Vec<f32>>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT vec FROM {} WHERE rowid = ?1",
+        storage::originals(name, schema.storage)
+    ))?;
+    rowids
+        .map(|rowid| {
+            let blob: Vec<u8> = stmt.query_row([rowid], |row| row.get(0))?;
+            Ok(document::vector_from_blob(&blob))
+        })
+        .collect()
+}
+
+/// Ranks rows by BM25 against the collection's keyword index and returns the
+/// best `limit`. A hit's `distance` is its BM25 rank, which like a distance is
+/// lower for better matches.
+pub(crate) fn keyword(
+    conn: &Connection,
+    name: &CollectionName,
+    text: &str,
+    limit: usize,
+    filter: Option<&Filter>,
+) -> rusqlite::Result<Vec<Hit>> {
+    let Some(query) = keyword::match_query(text) else {
+        return Ok(Vec::new());
+    };
+    let mut params = vec![Value::Text(query)];
+    let mut constraint = String::new();
+    if let Some(filter) = filter {
+        let (sql, filter_params) = filter_constraint(name, filter);
+        constraint = format!("AND {}", sql);
+        params.extend(filter_params);
+    }
+    // The filter's placeholders are numbered as they appear, so the limit
+    // goes last.
+    params.push(Value::Integer(limit as i64));
+
+    let query = format!(
+        "WITH matches AS (
+            SELECT rowid, rank FROM {0} WHERE {0} MATCH ?1 {1} ORDER BY rank LIMIT ?
+        )
+        SELECT matches.rowid, coalesce(c.parent, matches.rowid), d.id, v.key, matches.rank,
+            d.metadata
+        FROM matches
+        JOIN {2} v ON v.rowid = matches.rowid
+        LEFT JOIN {3} c ON c.rowid = matches.rowid
+        LEFT JOIN {3} d ON d.rowid = coalesce(c.parent, matches.rowid)
+        ORDER BY matches.rank",
+        name.fts_table(),
+        constraint,
+        name.quoted(),
+        name.docs_table()
+    );
+
+    let mut stmt = conn.prepare(&query)?;
+    let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+        Ok(Hit {
+            rowid: row.get(0)?,
+            document: row.get(1)?,
+            id: row.get(2)?,
+            key: row.get(3)?,
+            distance: row.get(4)?,
+            metadata: row.get(5)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Ranks rows by the dot product of their sparse vectors with `query` and
+/// returns the best `limit`. A hit's `distance` is the negated product, so
+/// like a distance it is lower for better matches. Rows sharing no term with
+/// the query aren't returned.
+pub(crate) fn sparse(
+    conn: &Connection,
+    name: &CollectionName,
+    query: &SparseVector,
+    limit: usize,
+    filter: Option<&Filter>,
+) -> rusqlite::Result<Vec<Hit>> {
+    let mut params = vec![Value::Text(query.to_json())];
+    let mut constraint = String::new();
+    if let Some(filter) = filter {
+        let (sql, filter_params) = filter_constraint(name, filter);
+        constraint = format!("WHERE {}", sql);
+        params.extend(filter_params);
+    }
+    params.push(Value::Integer(limit as i64));
+
+    // The query's terms drive the join, so only the index rows of those terms
+    // are read.
+    let query = format!(
+        "WITH query AS (
+            SELECT CAST(key AS INTEGER) AS term, value AS weight FROM json_each(?1)
+        ),
+        matches AS (
+            SELECT rowid, sum(product) AS score FROM (
+                SELECT s.row AS rowid, s.weight * q.weight AS product
+                FROM query q JOIN {0} s ON s.term = q.term
+            ) {1}
+            GROUP BY rowid ORDER BY score DESC LIMIT ?
+        )
+        SELECT matches.rowid, coalesce(c.parent, matches.rowid), d.id, v.key, -matches.score,
+            d.metadata
+        FROM matches
+        JOIN {2} v ON v.rowid = matches.rowid
+        LEFT JOIN {3} c ON c.rowid = matches.rowid
+        LEFT JOIN {3} d ON d.rowid = coalesce(c.parent, matches.rowid)
+        ORDER BY matches.score DESC",
+        name.sparse_table(),
+        constraint,
+        name.quoted(),
+        name.docs_table()
+    );
+
+    let mut stmt = conn.prepare(&query)?;
+    let rows = stmt.query_map(rusqlite::params_from_iter(params), |row| {
+        Ok(Hit {
+            rowid: row.get(0)?,
+            document: row.get(1)?,
+            id: row.get(2)?,
+            key: row.get(3)?,
+            distance: row.get(4)?,
+            metadata: row.get(5)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// A row ranked by [`fuse`], remembering where each ranking placed it.
+pub(crate) struct Fused {
+    pub(crate) rowid: i64,
+    pub(crate) document: i64,
+    pub(crate) id: Option<String>,
+    pub(crate) key: String,
+    pub(crate) metadata: serde_json::Value,
+    pub(crate) score: f32,
+    /// Distance from the vector search, if it found this row.
+    pub(crate) distance: Option<f32>,
+    /// BM25 rank from the keyword search, or negated dot product from the
+    /// sparse one, if it found this row.
+    pub(crate) rank: Option<f32>,
+}
+
+/// Merges a vector and a keyword or sparse ranking with reciprocal rank
+/// fusion. Each list contributes `weight / (RRF_K + position)`, with
+/// `keyword_weight` going to the keyword list and the rest to the vector list.
+pub(crate) fn fuse(
+    vector: Vec<Hit>,
+    keyword: Vec<Hit>,
+    keyword_weight: f32,
+    limit: usize,
+) -> Vec<Fused> {
+    let mut fused: Vec<Fused> = Vec::new();
+    let mut positions = HashMap::new();
+    let rankings = [
+        (vector, 1.0 - keyword_weight, false),
+        (keyword, keyword_weight, true),
+    ];
+    for (hits, weight, is_keyword) in rankings {
+        for (position, hit) in hits.into_iter().enumerate() {
+            let i = *positions.entry(hit.rowid).or_insert_with(|| {
+                fused.push(Fused {
+                    rowid: hit.rowid,
+                    document: hit.document,
+                    id: hit.id.clone(),
+                    key: hit.key.clone(),
+                    metadata: hit.metadata.clone(),
+                    score: 0.0,
+                    distance: None,
+                    rank: None,
+                });
+                fused.len() - 1
+            });
+            fused[i].score += weight / (RRF_K + position as f32 + 1.0);
+            if is_keyword {
+                fused[i].rank = Some(hit.distance);
+            } else {
+                fused[i].distance = Some(hit.distance);
+            }
+        }
+    }
+
+    // The sort is stable, so ties keep the vector ranking's order.
+    fused.sort_by(|a, b| b.score.total_cmp(&a.score));
+    fused.truncate(limit);
+    fused
+}
+
+/// Picks up to `limit` of the candidate rows by maximal marginal relevance,
+/// returning their positions in `rowids` in the order they were chosen.
+///
+/// Each step takes the candidate maximising
+/// `lambda * sim(query, c) - (1 - lambda) * max(sim(c, s))` over the already
+/// selected `s`, with similarities computed from the stored vectors under the
+/// collection's metric. A `lambda` of 1 keeps the relevance order, lower
+/// values increasingly push near-duplicates down.
+pub(crate) fn diversify(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+    query: &[f32],
+    rowids: &[i64],
+    lambda: f32,
+    limit: usize,
+) -> rusqlite::Result<Vec<usize>> {
+    let vectors = read_vectors(conn, name, schema, rowids.iter().copied())?;
+    Ok(mmr(schema.metric, query, &vectors, lambda, limit))
+}
+
+fn mmr(
+    metric: Metric,
+    query: &[f32],
+    vectors: &[Vec<f32>],
+    lambda: f32,
+    limit: usize,
+) -> Vec<usize> {
+    let similarity = |a: &[f32], b: &[f32]| metric.similarity(metric.distance(a, b));
+    let relevance: Vec<f32> = vectors.iter().map(|v| similarity(query, v)).collect();
+    // Highest similarity of each candidate to anything selected so far.
+    let mut redundancy: Vec<Option<f32>> = vec![None; vectors.len()];
+    let mut remaining: Vec<usize> = (0..vectors.len()).collect();
+    let mut selected = Vec::new();
+
+    while selected.len() < limit && !remaining.is_empty() {
+        let score =
+            |i: usize| lambda * relevance[i] - (1.0 - lambda) * redundancy[i].unwrap_or(0.0);
+        // Ties go to the earlier candidate, so the first stage order breaks them.
+        let mut best = 0;
+        for (position, &i) in remaining.iter().enumerate().skip(1) {
+            if score(i) > score(remaining[best]) {
+                best = position;
+            }
+        }
+        let chosen = remaining.remove(best);
+        for &i in &remaining {
+            let sim = similarity(&vectors[i], &vectors[chosen]);
+            redundancy[i] = Some(redundancy[i].map_or(sim, |r| r.max(sim)));
+        }
+        selected.push(chosen);
+    }
+    selected
+}
+
+/// Builds the `rowid IN (...)` constraint applying a metadata filter. Chunks
+/// are matched against their document's metadata.
+fn filter_constraint(name: &CollectionName, filter: &Filter) -> (String, Vec<Value>) {
+    let (sql, params) = filter.to_sql("coalesce(p.metadata, f.metadata)");
+    (
+        format!(
+            "rowid IN (SELECT f.rowid FROM {0} f LEFT JOIN {0} p ON p.rowid = f.parent WHERE {1})",
+            name.docs_table(),
+            sql
+        ),
+        params,
+    )
+}
+
+/// Finds the neighbours of an already stored document, reusing its vector
+/// instead of embedding anything. A chunked document is represented by the
+/// mean of its chunk vectors. The document itself, chunks included, is left
+/// out of the results. Returns `None` if there is no document with that id.
+pub(crate) fn similar(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+    id: &str,
+    limit: usize,
+    filter: Option<&Filter>,
+) -> rusqlite::Result<Option<Vec<Hit>>> {
+    let Some((rowid, vectors)) = document::find_vectors(conn, name, schema, id)? else {
+        return Ok(None);
+    };
+    let vector = centroid(&vectors);
+
+    // Ask for an extra neighbour per stored vector since the source document
+    // is usually its own nearest match.
+    let mut hits = knn(conn, name, schema, &vector, limit + vectors.len(), filter)?;
+    hits.retain(|hit| hit.document != rowid);
+    hits.truncate(limit);
+    Ok(Some(hits))
+}
+
+/// Element-wise mean of equally sized vectors.
+fn centroid(vectors: &[Vec<f32>]) -> Vec<f32> {
+    let mut sum = vec![0.0; vectors.first().map_or(0, Vec::len)];
+    for vector in vectors {
+        for (total, v) in sum.iter_mut().zip(vector) {
+            *total += v;
+        }
+    }
+    let n = vectors.len() as f32;
+    sum.iter().map(|total| total / n).collect()
+}
+
+/// Keeps the first, and so best ranked, of each document's rows, up to `limit`.
+pub(crate) fn group_by_document<T>(
+    rows: Vec<T>,
+    document: impl Fn(&T) -> i64,
+    limit: usize,
+) -> Vec<T> {
+    let mut seen = HashSet::new();
+    rows.into_iter()
+        .filter(|row| seen.insert(document(row)))
+        .take(limit)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collection::Schema;
+    use crate::document::Metadata;
+
+    fn open() -> (Connection, CollectionName, Schema) {
+        crate::web::register_sqlite_vec();
+        let conn = Connection::open_in_memory().unwrap();
+        crate::collection::init_catalog(&conn).unwrap();
+        let name: CollectionName = "posts".parse().unwrap();
+        let schema = Schema {
+            dimension: 2,
+            ..Default::default()
+        };
+        crate::collection::create(&conn, &name, &schema).unwrap();
+
+        for i in 0..20 {
+            let metadata: Metadata = serde_json::from_value(serde_json::json!({
+                "category": if i % 4 == 0 { "rust" } else { "other" },
+                "draft": i % 8 == 0,
+            }))
+            .unwrap();
+            let id = format!("post-{}", i);
+            let vector = [1.0, i as f32 / 10.0];
+            document::insert(
+                &conn,
+                &name,
+                &schema,
+                Some(&id),
+                "text",
+                &vector,
+                Some(&metadata),
+            )
+            .unwrap();
+        }
+        (conn, name, schema)
+    }
+
+    #[test]
+    fn test_knn_orders_by_distance() {
+        let (conn, name, schema) = open();
+        let hits = knn(&conn, &name, &schema, &[1.0, 0.0], 3, None).unwrap();
+
+        let ids: Vec<_> = hits.iter().map(|h| h.id.clone().unwrap()).collect();
+        assert_eq!(ids, vec!["post-0", "post-1", "post-2"]);
+        assert!(hits.windows(2).all(|w| w[0].distance <= w[1].distance));
+        assert_eq!(hits[0].metadata["category"], "rust");
+    }
+
+    #[test]
+    fn test_knn_filters_before_top_k() {
+        let (conn, name, schema) = open();
+        let filter: Filter = r#"category = "rust" AND draft != true"#.parse().unwrap();
+        let hits = knn(&conn, &name, &schema, &[1.0, 0.0], 2, Some(&filter)).unwrap();
+
+        // Only posts 4 and 12 match, and both are outside the unfiltered top 2.
+        let ids: Vec<_> = hits.iter().map(|h| h.id.clone().unwrap()).collect();
+        assert_eq!(ids, vec!["post-4", "post-12"]);
+    }
+
+    #[test]
+    fn test_similar_excludes_source() {
+        let (conn, name, schema) = open();
+        let hits = similar(&conn, &name, &schema, "post-5", 3, None)
+            .unwrap()
+            .unwrap();
+
+        let ids: Vec<_> = hits.iter().map(|h| h.id.clone().unwrap()).collect();
+        assert_eq!(ids.len(), 3);
+        assert!(!ids.contains(&"post-5".to_string()));
+        assert!(ids.contains(&"post-4".to_string()));
+        assert!(ids.contains(&"post-6".to_string()));
+
+        let filter: Filter = r#"category = "rust""#.parse().unwrap();
+        let hits = similar(&conn, &name, &schema, "post-4", 2, Some(&filter))
+            .unwrap()
+            .unwrap();
+        let ids: Vec<_> = hits.iter().map(|h| h.id.clone().unwrap()).collect();
+        assert_eq!(ids, vec!["post-8", "post-0"]);
+
+        assert!(similar(&conn, &name, &schema, "missing", 3, None)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_knn_metrics() {
+        crate::web::register_sqlite_vec();
+        let conn = Connection::open_in_memory().unwrap();
+        crate::collection::init_catalog(&conn).unwrap();
+
+        // Unit vector along the query versus a longer one at 45 degrees:
+        // cosine prefers the first, dot product the second.
+        let docs = [
+            ("near", [1.0, 0.0]),
+            ("long", [3.0, 3.0]),
+            ("far", [-5.0, 0.0]),
+        ];
+        for metric in [Metric::Cosine, Metric::L2, Metric::L1, Metric::Dot] {
+            let name: CollectionName = metric.as_str().parse().unwrap();
+            let schema = Schema {
+                dimension: 2,
+                metric,
+                ..Default::default()
+            };
+            crate::collection::create(&conn, &name, &schema).unwrap();
+            for (id, vector) in docs {
+                let metadata: Metadata =
+                    serde_json::from_value(serde_json::json!({"far": id == "far"})).unwrap();
+                document::insert(
+                    &conn,
+                    &name,
+                    &schema,
+                    Some(id),
+                    id,
+                    &vector,
+                    Some(&metadata),
+                )
+                .unwrap();
+            }
+
+            let hits = knn(&conn, &name, &schema, &[1.0, 0.0], 3, None).unwrap();
+            let ids: Vec<_> = hits.iter().map(|h| h.id.clone().unwrap()).collect();
+            let expected = match metric {
+                Metric::Dot => vec!["long", "near", "far"],
+                _ => vec!["near", "long", "far"],
+            };
+            assert_eq!(ids, expected, "{}", metric);
+
+            let filter: Filter = "far = false".parse().unwrap();
+            let hits = knn(&conn, &name, &schema, &[1.0, 0.0], 1, Some(&filter)).unwrap();
+            assert_eq!(hits.len(), 1, "{}", metric);
+            assert_eq!(hits[0].id.as_deref(), Some(expected[0]), "{}", metric);
+        }
+
+        let schema = Schema {
+            dimension: 2,
+            metric: Metric::Dot,
+            ..Default::default()
+        };
+        let hits = knn(
+            &conn,
+            &"dot".parse().unwrap(),
+            &schema,
+            &[1.0, 0.0],
+            3,
+            None,
+        )
+        .unwrap();
+        // The dot product is recovered from L2 distances, so allow for rounding.
+        for (hit, expected) in hits.iter().zip([-3.0, -1.0, 5.0]) {
+            assert!((hit.distance - expected).abs() < 1e-4, "{}", hit.distance);
+        }
+    }
+
+    #[test]
+    fn test_keyword_search() {
+        crate::web::register_sqlite_vec();
+        let conn = Connection::open_in_memory().unwrap();
+        crate::collection::init_catalog(&conn).unwrap();
+        let name: CollectionName = "posts".parse().unwrap();
+        let schema = crate::collection::Schema {
+            dimension: 2,
+            keyword_index: true,
+            ..Default::default()
+        };
+        crate::collection::create(&conn, &name, &schema).unwrap();
+
+        let posts = [
+            ("intro", "An introduction to vector search", "guide"),
+            ("l2", "How vec_distance_l2 computes distances", "reference"),
+            (
+                "vectors",
+                "Vector search, vector indexes and vector math",
+                "guide",
+            ),
+        ];
+        for (id, text, category) in posts {
+            let metadata: Metadata =
+                serde_json::from_value(serde_json::json!({"category": category})).unwrap();
+            document::insert(
+                &conn,
+                &name,
+                &schema,
+                Some(id),
+                text,
+                &[1.0, 0.0],
+                Some(&metadata),
+            )
+            .unwrap();
+        }
+        let ids =
+            |hits: Vec<Hit>| -> Vec<String> { hits.into_iter().map(|h| h.id.unwrap()).collect() };
+
+        let hits = keyword(&conn, &name, "vec_distance_l2", 10, None).unwrap();
+        assert_eq!(hits[0].key, "How vec_distance_l2 computes distances");
+        assert_eq!(ids(hits), vec!["l2"]);
+
+        let hits = keyword(&conn, &name, "vector", 10, None).unwrap();
+        assert!(hits.windows(2).all(|w| w[0].distance <= w[1].distance));
+        assert_eq!(ids(hits), vec!["vectors", "intro"]);
+
+        let filter: Filter = r#"category = "reference""#.parse().unwrap();
+        assert!(keyword(&conn, &name, "vector", 10, Some(&filter))
+            .unwrap()
+            .is_empty());
+        assert!(keyword(&conn, &name, "!!", 10, None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sparse_search() {
+        crate::web::register_sqlite_vec();
+        let conn = Connection::open_in_memory().unwrap();
+        crate::collection::init_catalog(&conn).unwrap();
+        let name: CollectionName = "posts".parse().unwrap();
+        let schema = crate::collection::Schema {
+            dimension: 2,
+            sparse_model: Some("hash".to_string()),
+            ..Default::default()
+        };
+        crate::collection::create(&conn, &name, &schema).unwrap();
+
+        let posts = [
+            ("l2", vec![(1, 2.0), (2, 1.0)], "reference"),
+            ("cosine", vec![(1, 1.0), (3, 1.0)], "guide"),
+            ("bread", vec![(9, 5.0)], "guide"),
+        ];
+        for (id, terms, category) in posts {
+            let metadata: Metadata =
+                serde_json::from_value(serde_json::json!({"category": category})).unwrap();
+            let rowid = document::insert(
+                &conn,
+                &name,
+                &schema,
+                Some(id),
+                id,
+                &[1.0, 0.0],
+                Some(&metadata),
+            )
+            .unwrap();
+            crate::sparse::index(&conn, &name, &schema, rowid, &[SparseVector(terms)]).unwrap();
+        }
+        let ids =
+            |hits: Vec<Hit>| -> Vec<String> { hits.into_iter().map(|h| h.id.unwrap()).collect() };
+
+        // l2 scores 2 * 1 + 1 * 0.5 and cosine 1 * 1 + 1 * 2.
+        let query = SparseVector(vec![(1, 1.0), (2, 0.5), (3, 2.0)]);
+        let hits = sparse(&conn, &name, &query, 10, None).unwrap();
+        assert_eq!(hits[0].distance, -3.0);
+        assert_eq!(hits[1].distance, -2.5);
+        assert_eq!(ids(hits), vec!["cosine", "l2"]);
+
+        let hits = sparse(&conn, &name, &query, 1, None).unwrap();
+        assert_eq!(ids(hits), vec!["cosine"]);
+        let filter: Filter = r#"category = "reference""#.parse().unwrap();
+        let hits = sparse(&conn, &name, &query, 10, Some(&filter)).unwrap();
+        assert_eq!(ids(hits), vec!["l2"]);
+        assert!(sparse(&conn, &name, &SparseVector::default(), 10, None)
+            .unwrap()
+            .is_empty());
+
+        document::delete(&conn, &name, &schema, "cosine").unwrap();
+        let hits = sparse(&conn, &name, &query, 10, None).unwrap();
+        assert_eq!(ids(hits), vec!["l2"]);
+    }
+
+    #[test]
+    fn test_fuse() {
+        let hit = |rowid: i64, distance: f32| Hit {
+            rowid,
+            document: rowid,
+            id: Some(rowid.to_string()),
+            key: String::new(),
+            distance,
+            metadata: serde_json::Value::Null,
+        };
+        let by_vector = || vec![hit(1, 0.1), hit(2, 0.2), hit(3, 0.3)];
+        let by_keyword = || vec![hit(3, -5.0), hit(4, -4.0)];
+
+        // Row 3 is found by both rankings, so it wins an even split. Rows 2
+        // and 4 tie and keep the vector ranking's order.
+        let fused = fuse(by_vector(), by_keyword(), 0.5, 4);
+        let rowids: Vec<_> = fused.iter().map(|f| f.rowid).collect();
+        assert_eq!(rowids, vec![3, 1, 2, 4]);
+        assert_eq!(fused[0].distance, Some(0.3));
+        assert_eq!(fused[0].rank, Some(-5.0));
+        assert_eq!(fused[3].distance, None);
+
+        let rowids: Vec<_> = fuse(by_vector(), by_keyword(), 0.0, 10)
+            .iter()
+            .map(|f| f.rowid)
+            .collect();
+        assert_eq!(rowids, vec![1, 2, 3, 4]);
+        let rowids: Vec<_> = fuse(by_vector(), by_keyword(), 1.0, 2)
+            .iter()
+            .map(|f| f.rowid)
+            .collect();
+        assert_eq!(rowids, vec![3, 4]);
+    }
+
+    #[test]
+    fn test_diversify_skips_near_duplicates() {
+        let (conn, _, schema) = open();
+        let name: CollectionName = "series".parse().unwrap();
+        crate::collection::create(&conn, &name, &schema).unwrap();
+        let mut rowids = Vec::new();
+        for vector in [[1.0, 0.05], [1.0, 0.0], [0.5, 0.5]] {
+            rowids.push(
+                document::insert(&conn, &name, &schema, None, "text", &vector, None).unwrap(),
+            );
+        }
+        let query = [1.0, 0.1];
+
+        // Without a redundancy penalty this is plain relevance order.
+        let order = diversify(&conn, &name, &schema, &query, &rowids, 1.0, 3).unwrap();
+        assert_eq!(order, vec![0, 1, 2]);
+
+        // The second post is almost the first one again, so the less relevant
+        // but different third post takes its place.
+        let order = diversify(&conn, &name, &schema, &query, &rowids, 0.3, 2).unwrap();
+        assert_eq!(order, vec![0, 2]);
+    }
+
+    #[test]
+    fn test_chunk_hits_report_their_document() {
+        let (conn, _, schema) = open();
+        let name: CollectionName = "guides".parse().unwrap();
+        crate::collection::create(&conn, &name, &schema).unwrap();
+        let chunks = |vectors: &[[f32; 2]]| -> Vec<(String, Vec<f32>)> {
+            vectors
+                .iter()
+                .enumerate()
+                .map(|(i, v)| (format!("part {}", i), v.to_vec()))
+                .collect()
+        };
+        let metadata = |category: &str| -> Metadata {
+            serde_json::from_value(serde_json::json!({ "category": category })).unwrap()
+        };
+        let long = document::insert_chunks(
+            &conn,
+            &name,
+            &schema,
+            Some("long"),
+            "part 0 part 1",
+            &chunks(&[[1.0, 0.0], [1.0, 0.1]]),
+            Some(&metadata("rust")),
+        )
+        .unwrap();
+        document::insert_chunks(
+            &conn,
+            &name,
+            &schema,
+            Some("short"),
+            "part 0",
+            &chunks(&[[1.0, 0.2]]),
+            Some(&metadata("go")),
+        )
+        .unwrap();
+
+        let hits = knn(&conn, &name, &schema, &[1.0, 0.0], 10, None).unwrap();
+        let found: Vec<_> = hits
+            .iter()
+            .map(|h| (h.id.clone().unwrap(), h.key.as_str()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                ("long".to_string(), "part 0"),
+                ("long".to_string(), "part 1"),
+                ("short".to_string(), "part 0")
+            ]
+        );
+        assert_eq!(hits[1].document, long);
+        assert_eq!(hits[1].metadata["category"], "rust");
+
+        let grouped = group_by_document(hits, |hit| hit.document, 10);
+        let ids: Vec<_> = grouped.iter().map(|h| h.id.clone().unwrap()).collect();
+        assert_eq!(ids, vec!["long", "short"]);
+
+        let filter: Filter = r#"category = "go""#.parse().unwrap();
+        let hits = knn(&conn, &name, &schema, &[1.0, 0.0], 10, Some(&filter)).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].id.as_deref(), Some("short"));
+
+        let hits = similar(&conn, &name, &schema, "long", 10, None)
+            .unwrap()
+            .unwrap();
+        let ids: Vec<_> = hits.iter().map(|h| h.id.clone().unwrap()).collect();
+        assert_eq!(ids, vec!["short"]);
+    }
+}
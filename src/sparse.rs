@@ -0,0 +1,209 @@
+//! Sparse embeddings, such as SPLADE's, kept in an inverted index beside a
+//! collection's dense vectors and used by sparse and hybrid search.
+//!
+//! A sparse vector weighs a few terms out of the model's vocabulary. The
+//! index holds one row per term of each stored vector, so a query only reads
+//! the rows of the terms it has, and a row's score is the dot product of its
+//! vector with the query's.
+
+use rusqlite::Connection;
+
+use crate::collection::{CollectionName, Schema};
+
+/// The non-zero weights of a sparse vector, by term.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub(crate) struct SparseVector(pub(crate) Vec<(u32, f32)>);
+
+impl From<fastembed::SparseEmbedding> for SparseVector {
+    fn from(embedding: fastembed::SparseEmbedding) -> Self {
+        Self(
+            embedding
+                .indices
+                .into_iter()
+                .map(|term| term as u32)
+                .zip(embedding.values)
+                .collect(),
+        )
+    }
+}
+
+impl SparseVector {
+    /// The vector as a JSON object from term to weight, the way queries pass
+    /// it to SQLite.
+    pub(crate) fn to_json(&self) -> String {
+        let terms: serde_json::Map<_, _> = self
+            .0
+            .iter()
+            .map(|(term, weight)| (term.to_string(), serde_json::json!(weight)))
+            .collect();
+        serde_json::Value::Object(terms).to_string()
+    }
+}
+
+/// Turns texts into sparse vectors, one per text and in the same order.
+pub(crate) trait SparseEmbedder: Send + Sync {
+    fn embed_sparse(&self, texts: Vec<String>) -> Result<Vec<SparseVector>, fastembed::Error>;
+}
+
+/// A sparse model: one fastembed downloads, by name, or a stand-in from
+/// `[embedding.hash]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum SparseModel {
+    Fastembed(&'static str),
+    Hash(String),
+}
+
+impl SparseModel {
+    /// The model's config name, as stored with the collections using it.
+    pub(crate) fn name(&self) -> &str {
+        match self {
+            SparseModel::Fastembed(name) => name,
+            SparseModel::Hash(name) => name,
+        }
+    }
+}
+
+/// A sparse model run in-process by fastembed.
+pub(crate) struct FastembedSparse {
+    pub(crate) model: fastembed::SparseTextEmbedding,
+    pub(crate) batch_size: usize,
+}
+
+impl SparseEmbedder for FastembedSparse {
+    fn embed_sparse(&self, texts: Vec<String>) -> Result<Vec<SparseVector>, fastembed::Error> {
+        Ok(self
+            .model
+            .embed(texts, Some(self.batch_size))?
+            .into_iter()
+            .map(SparseVector::from)
+            .collect())
+    }
+}
+
+pub(crate) fn create(conn: &Connection, name: &CollectionName) -> rusqlite::Result<()> {
+    conn.execute_batch(&format!(
+        "CREATE TABLE {0} (
+            term INTEGER NOT NULL,
+            row INTEGER NOT NULL,
+            weight REAL NOT NULL,
+            PRIMARY KEY (term, row)
+        ) WITHOUT ROWID;
+        CREATE INDEX {1} ON {0} (row);",
+        name.sparse_table(),
+        name.sparse_row_index()
+    ))
+}
+
+/// Indexes the sparse vectors of the document at `rowid`, one per row it is
+/// stored as: each of its chunks in order, or else the document itself. Does
+/// nothing for collections without a sparse model.
+pub(crate) fn index(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+    rowid: i64,
+    vectors: &[SparseVector],
+) -> rusqlite::Result<()> {
+    if vectors.is_empty() || schema.sparse_model.is_none() {
+        return Ok(());
+    }
+    let mut stmt = conn.prepare(&format!(
+        "SELECT rowid FROM {} WHERE parent = ?1 ORDER BY rowid",
+        name.docs_table()
+    ))?;
+    let mut rows = stmt
+        .query_map([rowid], |row| row.get::<_, i64>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    if rows.is_empty() {
+        rows.push(rowid);
+    }
+
+    let mut insert = conn.prepare(&format!(
+        "INSERT INTO {} (term, row, weight) VALUES (?1, ?2, ?3)",
+        name.sparse_table()
+    ))?;
+    for (row, vector) in rows.into_iter().zip(vectors) {
+        remove_row(conn, name, row)?;
+        for (term, weight) in &vector.0 {
+            insert.execute(rusqlite::params![term, row, weight])?;
+        }
+    }
+    Ok(())
+}
+
+/// Drops whatever is indexed under `rowid`. Does nothing for collections
+/// without a sparse model.
+pub(crate) fn remove(
+    conn: &Connection,
+    name: &CollectionName,
+    schema: &Schema,
+    rowid: i64,
+) -> rusqlite::Result<()> {
+    if schema.sparse_model.is_none() {
+        return Ok(());
+    }
+    remove_row(conn, name, rowid)
+}
+
+fn remove_row(conn: &Connection, name: &CollectionName, rowid: i64) -> rusqlite::Result<()> {
+    conn.execute(
+        &format!("DELETE FROM {} WHERE row = ?1", name.sparse_table()),
+        [rowid],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collection;
+
+    #[test]
+    fn test_to_json() {
+        let vector = SparseVector(vec![(7, 0.5), (1024, 2.0)]);
+        assert_eq!(vector.to_json(), r#"{"1024":2.0,"7":0.5}"#);
+        assert_eq!(SparseVector::default().to_json(), "{}");
+    }
+
+    #[test]
+    fn test_index_replaces_rows() {
+        crate::web::register_sqlite_vec();
+        let conn = Connection::open_in_memory().unwrap();
+        collection::init_catalog(&conn).unwrap();
+        let name: CollectionName = "posts".parse().unwrap();
+        let schema = collection::Schema {
+            dimension: 2,
+            sparse_model: Some("hash".to_string()),
+            ..Default::default()
+        };
+        collection::create(&conn, &name, &schema).unwrap();
+        // Its sparse table doesn't clash with this one's row index.
+        let row_posts: CollectionName = "row_posts".parse().unwrap();
+        collection::create(&conn, &row_posts, &schema).unwrap();
+        conn.execute(
+            &format!("INSERT INTO {} (rowid) VALUES (1)", name.docs_table()),
+            [],
+        )
+        .unwrap();
+
+        let terms = || -> Vec<(u32, i64)> {
+            let mut stmt = conn
+                .prepare(&format!(
+                    "SELECT term, row FROM {} ORDER BY term",
+                    name.sparse_table()
+                ))
+                .unwrap();
+            stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                .unwrap()
+                .collect::<rusqlite::Result<_>>()
+                .unwrap()
+        };
+        let vectors = [SparseVector(vec![(3, 1.0), (5, 0.5)])];
+        index(&conn, &name, &schema, 1, &vectors).unwrap();
+        index(&conn, &name, &schema, 1, &[SparseVector(vec![(4, 1.0)])]).unwrap();
+        assert_eq!(terms(), vec![(4, 1)]);
+
+        remove(&conn, &name, &schema, 1).unwrap();
+        assert_eq!(terms(), vec![]);
+    }
+}
@@ -0,0 +1,269 @@
+//! How a collection's vectors are stored on disk.
+//!
+//! | storage   | column     | bytes per dimension | metrics              |
+//! |-----------|------------|---------------------|----------------------|
+//! | `float32` | `float[N]` | 4                   | any                  |
+//! | `int8`    | `int8[N]`  | 1                   | `cosine`, `l2`, `l1` |
+//! | `binary`  | `bit[N]`   | 1/8                 | `cosine`             |
+//!
+//! Vectors are quantized as they are written. `int8` maps each component
+//! from `[-1, 1]` onto `[-127, 127]`, clamping anything outside, which suits
+//! the normalized vectors most models produce. `binary` keeps only the sign
+//! of each component and compares vectors by Hamming distance, reported as
+//! the cosine distance between the sign vectors, `2 * hamming / N`.
+//!
+//! Distances are scaled back so similarities stay comparable with a
+//! `float32` collection's. A quantized collection also keeps each vector's
+//! float32 original in a side table keyed by rowid. Searches never scan it,
+//! but may rescore their candidates by comparing the query with the
+//! originals, which recovers the order quantization blurs.
+
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use zerocopy::IntoBytes;
+
+use crate::collection::CollectionName;
+use crate::metric::Metric;
+
+/// Scale between a component in `[-1, 1]` and its `int8` value.
+const INT8_SCALE: f32 = 127.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Storage {
+    #[default]
+    Float32,
+    Int8,
+    Binary,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct UnknownStorage(String);
+
+impl Storage {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Float32 => "float32",
+            Self::Int8 => "int8",
+            Self::Binary => "binary",
+        }
+    }
+
+    /// Whether vectors are stored quantized, with their originals kept
+    /// beside them.
+    pub(crate) fn quantized(self) -> bool {
+        self != Self::Float32
+    }
+
+    /// The `vec0` column declaration for vectors of `dimension` components.
+    /// `bit` columns are always compared by Hamming distance, so they take
+    /// no `distance_metric`.
+    pub(crate) fn column(self, dimension: usize, metric: Metric) -> String {
+        match self {
+            Self::Float32 => format!(
+                "vec float[{}] distance_metric={}",
+                dimension,
+                metric.vec0_metric()
+            ),
+            Self::Int8 => format!(
+                "vec int8[{}] distance_metric={}",
+                dimension,
+                metric.vec0_metric()
+            ),
+            Self::Binary => format!("vec bit[{}]", dimension),
+        }
+    }
+
+    /// Why a collection can't be created with this storage, if it can't.
+    pub(crate) fn check(self, dimension: usize, metric: Metric) -> Result<(), String> {
+        match self {
+            Self::Float32 => Ok(()),
+            Self::Int8 if metric == Metric::Dot => {
+                Err("int8 storage supports the cosine, l2 and l1 metrics".to_string())
+            }
+            Self::Int8 => Ok(()),
+            Self::Binary if metric != Metric::Cosine => {
+                Err("binary storage only supports the cosine metric".to_string())
+            }
+            Self::Binary if dimension % 8 != 0 => Err(format!(
+                "binary storage needs a vector_size divisible by 8, not {}",
+                dimension
+            )),
+            Self::Binary => Ok(()),
+        }
+    }
+
+    /// Wraps a bound parameter so sqlite-vec reads its blob as this storage's
+    /// vector type rather than as floats.
+    pub(crate) fn param(self, param: &str) -> String {
+        match self {
+            Self::Float32 => param.to_string(),
+            Self::Int8 => format!("vec_int8({})", param),
+            Self::Binary => format!("vec_bit({})", param),
+        }
+    }
+
+    /// Quantizes a vector into the blob stored for it.
+    pub(crate) fn encode(self, vector: &[f32]) -> Vec<u8> {
+        match self {
+            Self::Float32 => vector.as_bytes().to_vec(),
+            Self::Int8 => vector
+                .iter()
+                .map(|v| (v * INT8_SCALE).round().clamp(-INT8_SCALE, INT8_SCALE) as i8 as u8)
+                .collect(),
+            // sqlite-vec keeps component i in bit i % 8 of byte i / 8.
+            Self::Binary => vector
+                .chunks(8)
+                .map(|bits| {
+                    bits.iter()
+                        .enumerate()
+                        .filter(|(_, v)| **v > 0.0)
+                        .fold(0u8, |byte, (i, _)| byte | 1 << i)
+                })
+                .collect(),
+        }
+    }
+
+    /// Turns a distance sqlite-vec reports between two stored vectors of
+    /// `dimension` components into the one `metric` gives their floats.
+    pub(crate) fn distance(self, metric: Metric, distance: f32, dimension: usize) -> f32 {
+        match (self, metric) {
+            (Self::Int8, Metric::L2 | Metric::L1) => distance / INT8_SCALE,
+            (Self::Binary, _) => 2.0 * distance / dimension as f32,
+            _ => distance,
+        }
+    }
+}
+
+/// Creates the table keeping the originals of a quantized collection.
+pub(crate) fn create(conn: &Connection, name: &CollectionName) -> rusqlite::Result<()> {
+    conn.execute(
+        &format!(
+            "CREATE TABLE {} (rowid INTEGER PRIMARY KEY, vec BLOB NOT NULL)",
+            name.originals_table()
+        ),
+        (),
+    )?;
+    Ok(())
+}
+
+/// The table vectors are read back from at full precision: the originals of
+/// a quantized collection, otherwise its `vec0` table. Either holds float32
+/// blobs in a `vec` column keyed by rowid.
+pub(crate) fn originals(name: &CollectionName, storage: Storage) -> String {
+    if storage.quantized() {
+        name.originals_table()
+    } else {
+        name.quoted()
+    }
+}
+
+impl FromStr for Storage {
+    type Err = UnknownStorage;
+
+    fn from_str(storage: &str) -> Result<Self, Self::Err> {
+        match storage {
+            "float32" => Ok(Self::Float32),
+            "int8" => Ok(Self::Int8),
+            "binary" => Ok(Self::Binary),
+            _ => Err(UnknownStorage(storage.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for UnknownStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "unknown storage {:?}, expected float32, int8 or binary",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for UnknownStorage {}
+
+impl ToSql for Storage {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+impl FromSql for Storage {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: UnknownStorage| FromSqlError::Other(e.into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_display() {
+        for storage in [Storage::Float32, Storage::Int8, Storage::Binary] {
+            assert_eq!(storage.as_str().parse::<Storage>(), Ok(storage));
+        }
+        assert_eq!(
+            "float16".parse::<Storage>(),
+            Err(UnknownStorage("float16".to_string()))
+        );
+        assert_eq!(Storage::Binary.column(16, Metric::Cosine), "vec bit[16]");
+        assert_eq!(
+            Storage::Int8.column(4, Metric::L2),
+            "vec int8[4] distance_metric=l2"
+        );
+    }
+
+    #[test]
+    fn test_encode() {
+        let vector = [0.5, -1.0, 2.0, 0.0, -0.25, 0.1, 0.9, -0.9, 0.3];
+
+        let int8: Vec<_> = Storage::Int8
+            .encode(&vector)
+            .into_iter()
+            .map(|b| b as i8)
+            .collect();
+        assert_eq!(int8, [64, -127, 127, 0, -32, 13, 114, -114, 38]);
+
+        let binary = Storage::Binary.encode(&vector);
+        assert_eq!(binary, [0b0110_0101, 0b0000_0001]);
+
+        assert_eq!(
+            crate::document::vector_from_blob(&Storage::Float32.encode(&vector)),
+            vector
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(Storage::Float32.check(3, Metric::Dot).is_ok());
+        assert!(Storage::Int8.check(3, Metric::L1).is_ok());
+        assert!(Storage::Int8.check(3, Metric::Dot).is_err());
+        assert!(Storage::Binary.check(16, Metric::Cosine).is_ok());
+        assert!(Storage::Binary.check(16, Metric::L2).is_err());
+        assert!(Storage::Binary.check(12, Metric::Cosine).is_err());
+    }
+
+    #[test]
+    fn test_distance() {
+        assert_eq!(Storage::Int8.distance(Metric::Cosine, 0.25, 8), 0.25);
+        assert_eq!(Storage::Int8.distance(Metric::L2, 127.0, 8), 1.0);
+        // Half the bits differing makes the sign vectors orthogonal.
+        assert_eq!(Storage::Binary.distance(Metric::Cosine, 4.0, 8), 1.0);
+        assert_eq!(Storage::Binary.distance(Metric::Cosine, 0.0, 8), 0.0);
+        assert_eq!(Storage::Float32.distance(Metric::L2, 3.0, 8), 3.0);
+    }
+}
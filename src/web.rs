@@ -1,44 +1,402 @@
-use log::{error, info};
+use log::{error, info, warn};
 
+use actix_multipart::form::{bytes::Bytes, text::Text, MultipartForm, MultipartFormConfig};
 use actix_web::{
-    delete, get, middleware::Logger, post, web, App, HttpResponse, HttpServer, Responder,
+    delete, get, middleware::Logger, post, put, web, App, FromRequest, HttpMessage, HttpRequest,
+    HttpResponse, HttpServer, Responder,
 };
 use deadpool_sqlite::{Config, Manager, Pool};
-use rusqlite; //::{ffi::sqlite3_auto_extension, Connection, Result};
+use rusqlite::TransactionBehavior;
 use serde::{Deserialize, Serialize};
-use sqlite_vec;
-use zerocopy::IntoBytes;
+use std::sync::Arc;
+
+use crate::cache::{self, EmbeddingCache};
+use crate::chunk::{Chunking, Strategy};
+use crate::collection::{self, CollectionName};
+use crate::document::{self, Embedded};
+use crate::embedding::{Model, ModelRegistry, Role, Templates};
+use crate::filter::Filter;
+use crate::image;
+use crate::metric::Metric;
+use crate::search;
+use crate::sparse::{self, SparseVector};
+use crate::storage::Storage;
+use fastembed::RerankResult;
 
 // This struct represents state
 #[derive(Clone)]
 struct AppState {
     pool: Pool,
-    config: crate::config::Config,
+    models: Arc<ModelRegistry>,
+    cache: Arc<EmbeddingCache>,
     // table: Mutex<VecTable<String>>, // Using `sqlite_vec` with a generic type
 }
 
 #[derive(Deserialize, Serialize)]
 struct CreateCollectionRequest {
-    name: String,
-    vector_size: usize,
+    name: CollectionName,
+    /// Dimensions of the stored vectors. Defaults to the model's, and must
+    /// match it when a model is named.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vector_size: Option<usize>,
+    /// Embedding model bound to the collection, by its config name. Defaults
+    /// to the server's model.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    /// How vectors are compared, see [`crate::metric`].
+    #[serde(default)]
+    metric: Metric,
+    /// Also index the source text for keyword and hybrid search.
+    #[serde(default)]
+    keyword_index: bool,
+    /// Split documents into chunks embedded separately, see [`crate::chunk`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chunking: Option<Chunking>,
+    /// Query and passage templates to use instead of the model's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    templates: Option<Templates>,
+    /// Also keep a sparse vector per document from this model, such as
+    /// `splade-pp-en-v1`, for sparse and hybrid search.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sparse_model: Option<String>,
+    /// Make this a collection of images embedded with this model, such as
+    /// `clip-vit-b-32-vision`. `model` then defaults to its text encoder.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    image_model: Option<String>,
+    /// Quantize the stored vectors to save space, see [`crate::storage`].
+    #[serde(default)]
+    storage: Storage,
 }
 
 #[derive(Deserialize, Serialize)]
 struct CreateVectorRequest {
+    /// Stable caller-supplied id, such as a post slug.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
     text: String,
+    /// Arbitrary JSON object returned with search results.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<document::Metadata>,
+    /// Precomputed embedding stored as is instead of embedding `text`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vector: Option<Vec<f32>>,
+}
+
+/// An image sent as JSON.
+#[derive(Deserialize, Serialize)]
+struct ImageRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    /// The image file, such as a PNG or JPEG, base64 encoded, bare or as a
+    /// `data:` URL.
+    image: String,
+    /// Kept as the key returned with search results, such as a file name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<document::Metadata>,
+}
+
+/// An image sent as a multipart form, with the fields of [`ImageRequest`]
+/// and the image as a file. The file name stands in for a missing `name`,
+/// and `metadata` is a JSON object.
+#[derive(MultipartForm)]
+struct ImageForm {
+    image: Bytes,
+    id: Option<Text<String>>,
+    name: Option<Text<String>>,
+    metadata: Option<Text<String>>,
+}
+
+/// An image as uploaded, whichever way it was sent.
+struct ImageUpload {
+    id: Option<String>,
+    name: Option<String>,
+    image: Vec<u8>,
+    metadata: Option<document::Metadata>,
+}
+
+impl ImageUpload {
+    /// Reads an [`ImageForm`] or, for any other content type, an
+    /// [`ImageRequest`], and checks the image is in a known format.
+    async fn read(http: &HttpRequest, body: web::Payload) -> Result<Self, HttpResponse> {
+        let mut body = body.into_inner();
+        if http.content_type() == "multipart/form-data" {
+            let form = MultipartForm::<ImageForm>::from_request(http, &mut body)
+                .await
+                .map_err(HttpResponse::from_error)?
+                .into_inner();
+            check_image(&form.image.data)?;
+            let metadata = form
+                .metadata
+                .map(|metadata| serde_json::from_str(&metadata.into_inner()))
+                .transpose()
+                .map_err(|e| {
+                    HttpResponse::BadRequest()
+                        .body(format!("metadata must be a JSON object: {}", e))
+                })?;
+            return Ok(Self {
+                id: form.id.map(Text::into_inner),
+                name: form.name.map(Text::into_inner).or(form.image.file_name),
+                image: form.image.data.to_vec(),
+                metadata,
+            });
+        }
+        let req = web::Json::<ImageRequest>::from_request(http, &mut body)
+            .await
+            .map_err(HttpResponse::from_error)?
+            .into_inner();
+        Ok(Self {
+            image: decode_image(&req.image)?,
+            id: req.id,
+            name: req.name,
+            metadata: req.metadata,
+        })
+    }
 }
 
 #[derive(Deserialize, Serialize)]
+struct BatchRequest {
+    documents: Vec<CreateVectorRequest>,
+}
+
+/// Outcome of one document in a batch, reported in request order.
+#[derive(Serialize)]
+struct BatchItemResult {
+    index: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    status: BatchStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum BatchStatus {
+    Inserted,
+    Updated,
+    Unchanged,
+    Failed,
+}
+
+/// What a batch item turns into once the embeddings are back.
+enum BatchWrite {
+    Failed(String),
+    /// Same text as the stored row, so only its metadata is rewritten.
+    Metadata,
+    /// The document's vectors and, in collections with a sparse model, its
+    /// sparse vectors.
+    Vector(Embedded, Vec<SparseVector>),
+}
+
+#[derive(Deserialize, Serialize, Default)]
 struct SearchRequest {
+    #[serde(default)]
     text: String,
     limit: Option<usize>,
+    /// Metadata filter expression, see [`crate::filter`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    filter: Option<String>,
+    /// Query vector to search with directly, in place of `text`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    vector: Option<Vec<f32>>,
+    /// Image to search an image collection with, in place of `text`, base64
+    /// encoded like [`ImageRequest::image`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    image: Option<String>,
+    #[serde(default)]
+    mode: SearchMode,
+    /// Share of a hybrid score given to the keyword or sparse ranking, from
+    /// 0 to 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    keyword_weight: Option<f32>,
+    /// Rescore the candidates with a cross-encoder before returning them.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rerank: Option<RerankOptions>,
+    /// Rescore the nearest neighbours of an int8 or binary collection against
+    /// their float32 originals, see [`search::rescore`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rescore: Option<RescoreOptions>,
+    /// Trade some relevance for variety among the results.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    diversity: Option<DiversityOptions>,
+    /// Return one result per document, with its best matching chunk as the
+    /// `key`, instead of one per chunk.
+    #[serde(default)]
+    group_by_document: bool,
+}
+
+#[derive(Deserialize, Serialize)]
+struct RerankOptions {
+    /// Reranker to use, defaulting to the one configured under `[rerank]`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    /// How many candidates to fetch and rescore. Must be at least the limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    top_n: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct RescoreOptions {
+    /// How many neighbours to fetch and rescore. Must be at least the limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    top_n: Option<usize>,
+}
+
+/// Maximal marginal relevance over the candidates, see [`search::diversify`].
+#[derive(Deserialize, Serialize)]
+struct DiversityOptions {
+    /// Weight of relevance against redundancy, from 0 to 1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lambda: Option<f32>,
+    /// How many candidates to choose from. Must be at least the limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fetch_k: Option<usize>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+enum SearchMode {
+    /// Nearest neighbours of the embedded text or the given vector.
+    #[default]
+    Vector,
+    /// BM25 over the collection's keyword index.
+    Keyword,
+    /// Dot products with the collection's sparse vectors.
+    Sparse,
+    /// Vector search merged with reciprocal rank fusion with sparse search,
+    /// in collections with a sparse model, or else keyword search.
+    Hybrid,
+}
+
+/// How many candidates each ranking contributes to a hybrid search, as a
+/// multiple of the limit, so rows found by only one side can still place.
+const HYBRID_DEPTH: usize = 4;
+
+/// Candidates fetched for reranking or diversification when the request
+/// doesn't say, as a multiple of the limit.
+const CANDIDATE_DEPTH: usize = 4;
+
+#[derive(Deserialize, Serialize)]
+struct SimilarQuery {
+    limit: Option<usize>,
+    filter: Option<String>,
 }
 
 #[derive(Serialize)]
 struct SearchResult {
     rowid: i64,
+    #[serde(skip)]
+    document: i64,
+    id: Option<String>,
     key: String,
+    /// Score where higher is closer. Vector searches derive it from `distance`
+    /// according to the collection's metric, keyword searches report the
+    /// negated BM25 rank, sparse searches the dot product and hybrid searches
+    /// the fused reciprocal rank score.
     similarity: f32,
+    /// Vector distance, when the row was found by a vector search.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    distance: Option<f32>,
+    /// Negated BM25 rank, when the row was found by a keyword search.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    keyword_score: Option<f32>,
+    /// Sparse dot product, when the row was found by a sparse search.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sparse_score: Option<f32>,
+    /// Cross-encoder relevance, when the results were reranked. Results are
+    /// then ordered by this score while `similarity` keeps the first stage's.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rerank_score: Option<f32>,
+    metadata: serde_json::Value,
+}
+
+impl SearchResult {
+    fn new(hit: search::Hit, metric: Metric) -> Self {
+        Self {
+            rowid: hit.rowid,
+            document: hit.document,
+            id: hit.id,
+            key: hit.key,
+            similarity: metric.similarity(hit.distance),
+            distance: Some(hit.distance),
+            keyword_score: None,
+            sparse_score: None,
+            rerank_score: None,
+            metadata: hit.metadata,
+        }
+    }
+
+    fn from_hits(hits: Vec<search::Hit>, metric: Metric) -> Vec<Self> {
+        hits.into_iter().map(|hit| Self::new(hit, metric)).collect()
+    }
+
+    fn from_keyword(hit: search::Hit) -> Self {
+        Self {
+            rowid: hit.rowid,
+            document: hit.document,
+            id: hit.id,
+            key: hit.key,
+            similarity: -hit.distance,
+            distance: None,
+            keyword_score: Some(-hit.distance),
+            sparse_score: None,
+            rerank_score: None,
+            metadata: hit.metadata,
+        }
+    }
+
+    fn from_sparse(hit: search::Hit) -> Self {
+        Self {
+            keyword_score: None,
+            sparse_score: Some(-hit.distance),
+            ..Self::from_keyword(hit)
+        }
+    }
+
+    /// `sparse` says whether the ranking fused with the vector one was the
+    /// sparse or the keyword search.
+    fn from_fused(fused: search::Fused, sparse: bool) -> Self {
+        let score = fused.rank.map(|rank| -rank);
+        Self {
+            rowid: fused.rowid,
+            document: fused.document,
+            id: fused.id,
+            key: fused.key,
+            similarity: fused.score,
+            distance: fused.distance,
+            keyword_score: score.filter(|_| !sparse),
+            sparse_score: score.filter(|_| sparse),
+            rerank_score: None,
+            metadata: fused.metadata,
+        }
+    }
+
+    /// Reorders candidates by reranker score and keeps the best `limit`.
+    fn reranked(candidates: Vec<Self>, scores: Vec<RerankResult>, limit: usize) -> Vec<Self> {
+        let mut candidates: Vec<_> = candidates.into_iter().map(Some).collect();
+        let mut scores = scores;
+        scores.sort_by(|a, b| b.score.total_cmp(&a.score));
+        scores
+            .into_iter()
+            .filter_map(|scored| {
+                let mut result = candidates.get_mut(scored.index)?.take()?;
+                result.rerank_score = Some(scored.score);
+                Some(result)
+            })
+            .take(limit)
+            .collect()
+    }
+
+    /// Keeps the candidates at the given positions, in that order.
+    fn picked(candidates: Vec<Self>, positions: Vec<usize>) -> Vec<Self> {
+        let mut candidates: Vec<_> = candidates.into_iter().map(Some).collect();
+        positions
+            .into_iter()
+            .filter_map(|i| candidates.get_mut(i)?.take())
+            .collect()
+    }
 }
 
 #[post("/collection")]
@@ -47,19 +405,120 @@ async fn create_collection(
     req: web::Json<CreateCollectionRequest>,
 ) -> impl Responder {
     info!("Creating collection: {}", req.name);
+    let name = req.name.clone();
+    if let Some(Err(e)) = req.chunking.as_ref().map(Chunking::validate) {
+        return HttpResponse::BadRequest().body(e.to_string());
+    }
+    let image_model = match req.image_model.as_deref() {
+        None => None,
+        Some(name) => match data.models.resolve_image(name) {
+            Some(model) => Some(model),
+            None => {
+                return HttpResponse::BadRequest().body(format!("Unknown image model: {}", name))
+            }
+        },
+    };
+    if image_model.is_some() && req.chunking.is_some() {
+        return HttpResponse::BadRequest().body("Image collections can't be chunked");
+    }
+    // Image collections embed texts with their image model's text encoder.
+    let model = match (req.model.as_deref(), &image_model) {
+        (None, None) => data.models.default_model().clone(),
+        (None, Some(image_model)) => match image_model.text_model() {
+            Some(model) => model,
+            None => {
+                return HttpResponse::BadRequest().body(format!(
+                    "Image model {} has no text encoder, name a model for texts",
+                    image_model.name()
+                ))
+            }
+        },
+        (Some(model), _) => match data.models.resolve(model) {
+            Some(model) => model,
+            None => return HttpResponse::BadRequest().body(format!("Unknown model: {}", model)),
+        },
+    };
+    let model_dimension = data.models.dimension(&model);
+    // A collection on the server's model may still be sized for precomputed
+    // vectors of another size, but one naming its model has to fit it.
+    let dimension = match (req.vector_size, model_dimension) {
+        (Some(size), Some(expected)) if req.model.is_some() && size != expected => {
+            return HttpResponse::BadRequest().body(format!(
+                "Model {} produces {} dimensions, not {}",
+                model.name(),
+                expected,
+                size
+            ))
+        }
+        (Some(size), _) => size,
+        (None, Some(expected)) => expected,
+        (None, None) => return HttpResponse::BadRequest().body("vector_size is required"),
+    };
+    let by_tokens = matches!(&req.chunking, Some(c) if c.strategy == Strategy::Tokens);
+    if by_tokens && matches!(model, Model::Remote(_)) {
+        return HttpResponse::BadRequest().body(format!(
+            "Remote model {} has no tokenizer to chunk by, chunk by words instead",
+            model.name()
+        ));
+    }
+    if let Err(message) = req.storage.check(dimension, req.metric) {
+        return HttpResponse::BadRequest().body(message);
+    }
+    if let Some(image_model) = &image_model {
+        let image_dimension = data.models.image_dimension(image_model);
+        if image_dimension != Some(dimension) {
+            return HttpResponse::BadRequest().body(format!(
+                "Image model {} produces {} dimensions, not {}",
+                image_model.name(),
+                image_dimension.unwrap_or_default(),
+                dimension
+            ));
+        }
+    }
+    let sparse_model = match req.sparse_model.as_deref() {
+        None => None,
+        Some(name) => match data.models.resolve_sparse(name) {
+            Some(model) => Some(model.name().to_string()),
+            None => {
+                return HttpResponse::BadRequest().body(format!("Unknown sparse model: {}", name))
+            }
+        },
+    };
     let conn = data.pool.get().await.unwrap();
-    let query = format!(
-        "CREATE VIRTUAL TABLE {} using vec0(key TEXT, vec float[{}] distance_metric=cosine);",
-        req.name, req.vector_size
-    );
+    let schema = collection::Schema {
+        dimension,
+        metric: req.metric,
+        model: model.name().to_string(),
+        keyword_index: req.keyword_index,
+        chunking: req.chunking.clone(),
+        templates: req.templates.clone(),
+        sparse_model,
+        image_model: image_model.map(|model| model.name().to_string()),
+        storage: req.storage,
+    };
 
-    let result = conn.interact(move |conn| conn.execute(&query, ())).await;
+    let result = conn
+        .interact(move |conn| {
+            let tx = conn.transaction()?;
+            if collection::exists(&tx, &name)? {
+                return Ok(false);
+            }
+            collection::create(&tx, &name, &schema)?;
+            tx.commit()?;
+            Ok::<_, rusqlite::Error>(true)
+        })
+        .await;
 
     match result {
-        Ok(_) => {
+        Ok(Ok(true)) => {
             info!("Successfully created collection: {}", req.name);
             HttpResponse::Ok().body("Collection created successfully")
         }
+        Ok(Ok(false)) => HttpResponse::Conflict().body("Collection already exists"),
+        Ok(Err(e)) => {
+            error!("Failed to create collection {}: {}", req.name, e);
+            HttpResponse::InternalServerError().body("Failed to create collection")
+        }
         Err(e) => {
             error!("Failed to create collection {}: {}", req.name, e);
             HttpResponse::InternalServerError().body("Failed to create collection")
@@ -67,382 +526,3377 @@ async fn create_collection(
     }
 }
 
-#[delete("/collection/{name}")]
-async fn delete_collection(data: web::Data<AppState>, path: web::Path<String>) -> impl Responder {
-    let collection_name = path.into_inner();
+#[get("/collection")]
+async fn list_collections(data: web::Data<AppState>) -> impl Responder {
     let conn = data.pool.get().await.unwrap();
-    let query = format!("DROP TABLE IF EXISTS {}", collection_name);
+    let result = conn.interact(|conn| collection::list(conn)).await;
+
+    match result {
+        Ok(Ok(collections)) => HttpResponse::Ok().json(collections),
+        Ok(Err(e)) => {
+            error!("Failed to list collections: {}", e);
+            HttpResponse::InternalServerError().body("Failed to list collections")
+        }
+        Err(e) => {
+            error!("Pool error while listing collections: {}", e);
+            HttpResponse::InternalServerError().body("Failed to list collections")
+        }
+    }
+}
 
-    let result = conn.interact(move |conn| conn.execute(&query, ())).await;
+/// How often the embedding cache saved running the model.
+#[get("/cache")]
+async fn cache_stats(data: web::Data<AppState>) -> impl Responder {
+    let conn = data.pool.get().await.unwrap();
+    let cache = data.cache.clone();
+    let result = conn.interact(move |conn| cache.stats(conn)).await;
 
     match result {
-        Ok(_) => HttpResponse::Ok().body("Collection deleted successfully"),
-        Err(_) => HttpResponse::InternalServerError().body("Failed to delete collection"),
+        Ok(Ok(stats)) => HttpResponse::Ok().json(stats),
+        Ok(Err(e)) => {
+            error!("Failed to read cache statistics: {}", e);
+            HttpResponse::InternalServerError().body("Failed to read cache statistics")
+        }
+        Err(e) => {
+            error!("Pool error while reading cache statistics: {}", e);
+            HttpResponse::InternalServerError().body("Failed to read cache statistics")
+        }
     }
 }
 
-#[post("/collection/{name}")]
-async fn insert_vector(
+#[get("/collection/{name}")]
+async fn get_collection(
     data: web::Data<AppState>,
-    path: web::Path<String>,
-    req: web::Json<CreateVectorRequest>,
+    path: web::Path<CollectionName>,
 ) -> impl Responder {
     let collection_name = path.into_inner();
+    let conn = data.pool.get().await.unwrap();
+    let result = conn
+        .interact(move |conn| collection::describe(conn, &collection_name))
+        .await;
 
-    // Generate embedding
-    match crate::embedding::embed(&data.config, vec![&req.text]).await {
-        Ok(vector) => {
-            let conn = data.pool.get().await.unwrap();
-
-            let result = conn
-                .interact(move |conn| {
-                    let tx = conn.transaction().unwrap();
-                    {
-                        let mut query = tx
-                            .prepare(
-                                format!(
-                                    "INSERT INTO {} (key, vec) VALUES (?, ?)",
-                                    collection_name.as_str()
-                                )
-                                .as_str(),
-                            )
-                            .unwrap();
-                        for vector in &vector {
-                            query
-                                .execute(rusqlite::params![&req.text, vector.as_bytes()])
-                                .unwrap();
-                        }
-                    }
-                    tx.commit().unwrap();
-                })
-                .await;
-
-            match result {
-                Ok(_) => HttpResponse::Ok().body("Vector inserted successfully"),
-                Err(e) => {
-                    error!("Failed to insert vector: {}", e);
-                    HttpResponse::InternalServerError().body("Failed to insert vector")
-                }
-            }
+    match result {
+        Ok(Ok(Some(info))) => HttpResponse::Ok().json(info),
+        Ok(Ok(None)) => HttpResponse::NotFound().body("Collection not found"),
+        Ok(Err(e)) => {
+            error!("Failed to describe collection: {}", e);
+            HttpResponse::InternalServerError().body("Failed to describe collection")
         }
         Err(e) => {
-            error!("Failed to generate embedding: {}", e);
-            HttpResponse::InternalServerError().body("Failed to generate embedding")
+            error!("Pool error while describing collection: {}", e);
+            HttpResponse::InternalServerError().body("Failed to describe collection")
         }
     }
 }
 
-#[post("/collection/{name}/search")]
-async fn search_vectors(
+#[delete("/collection/{name}")]
+async fn delete_collection(
     data: web::Data<AppState>,
-    path: web::Path<String>,
-    req: web::Json<SearchRequest>,
+    path: web::Path<CollectionName>,
 ) -> impl Responder {
     let collection_name = path.into_inner();
-    let limit = req.limit.unwrap_or(10);
+    if let Err(resp) = require_collection(&data, &collection_name).await {
+        return resp;
+    }
+    let conn = data.pool.get().await.unwrap();
 
-    // Generate embedding for search text
-    match crate::embedding::embed(&data.config, vec![&req.text]).await {
-        Ok(vector) => {
-            // dbg!(&vector[0].as_str());
-            let conn = data.pool.get().await.unwrap();
-            let query = format!(
-                "SELECT rowid, key, distance FROM {} WHERE vec MATCH ?1 ORDER BY distance LIMIT {}",
-                collection_name, limit
-            );
-
-            let result = conn
-                .interact(move |conn| {
-                    let mut stmt = conn.prepare(query.as_str())?;
-                    let rows = stmt.query_map([&vector[0].as_bytes()], |row| {
-                        Ok(SearchResult {
-                            rowid: row.get(0)?,
-                            key: row.get(1)?,
-                            similarity: 1.0 - row.get::<_, f32>(2)?,
-                        })
-                    })?;
-
-                    let mut results = Vec::new();
-                    for row in rows {
-                        results.push(row?);
-                    }
-                    Ok::<Vec<SearchResult>, rusqlite::Error>(results)
-                })
-                .await;
+    let result = conn
+        .interact(move |conn| {
+            let tx = conn.transaction()?;
+            collection::remove(&tx, &collection_name)?;
+            tx.commit()
+        })
+        .await;
 
-            match result {
-                Ok(Ok(results)) => HttpResponse::Ok().json(results),
-                Ok(Err(e)) => {
-                    dbg!(&e);
-                    error!("Database error during search: {}", e);
-                    HttpResponse::InternalServerError().body("Search failed")
-                }
-                Err(e) => {
-                    error!("Pool error during search: {}", e);
-                    HttpResponse::InternalServerError().body("Search failed")
-                }
-            }
-        }
-        Err(e) => {
-            error!("Failed to generate embedding: {}", e);
-            HttpResponse::InternalServerError().body("Failed to generate embedding")
-        }
+    match result {
+        Ok(Ok(_)) => HttpResponse::Ok().body("Collection deleted successfully"),
+        _ => HttpResponse::InternalServerError().body("Failed to delete collection"),
     }
 }
 
-#[get("/")]
-async fn index(data: web::Data<AppState>) -> impl Responder {
+#[delete("/collection/{name}/{id}")]
+async fn delete_document(
+    data: web::Data<AppState>,
+    path: web::Path<(CollectionName, String)>,
+) -> impl Responder {
+    let (collection_name, id) = path.into_inner();
+    let schema = match require_collection(&data, &collection_name).await {
+        Ok(schema) => schema,
+        Err(resp) => return resp,
+    };
     let conn = data.pool.get().await.unwrap();
-    let result: u8 = conn
-        .interact(|conn| {
-            let mut stmt = conn.prepare("SELECT 1")?;
-            let mut rows = stmt.query([])?;
-            let row = rows.next()?.unwrap();
-            row.get(0)
+
+    let result = conn
+        .interact(move |conn| {
+            let tx = conn.transaction()?;
+            let deleted = document::delete(&tx, &collection_name, &schema, &id)?;
+            if deleted {
+                collection::touch(&tx, &collection_name)?;
+            }
+            tx.commit()?;
+            Ok::<_, rusqlite::Error>(deleted)
         })
-        .await
-        .unwrap()
-        .unwrap();
-    HttpResponse::Ok().body(result.to_string())
+        .await;
+
+    match result {
+        Ok(Ok(true)) => HttpResponse::Ok().body("Document deleted successfully"),
+        Ok(Ok(false)) => HttpResponse::NotFound().body("Document not found"),
+        Ok(Err(e)) => {
+            error!("Failed to delete document: {}", e);
+            HttpResponse::InternalServerError().body("Failed to delete document")
+        }
+        Err(e) => {
+            error!("Failed to delete document: {}", e);
+            HttpResponse::InternalServerError().body("Failed to delete document")
+        }
+    }
 }
 
-#[actix_web::main]
-pub async fn web_entry(config: crate::config::Config) -> std::io::Result<()> {
-    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
-    unsafe {
-        rusqlite::ffi::sqlite3_auto_extension(Some(std::mem::transmute(
-            sqlite_vec::sqlite3_vec_init as *const (),
-        )));
+#[post("/collection/{name}")]
+async fn insert_vector(
+    data: web::Data<AppState>,
+    path: web::Path<CollectionName>,
+    mut req: web::Json<CreateVectorRequest>,
+) -> impl Responder {
+    let collection_name = path.into_inner();
+    let schema = match require_collection(&data, &collection_name).await {
+        Ok(schema) => schema,
+        Err(resp) => return resp,
+    };
+    if req.id.as_deref() == Some("") {
+        return HttpResponse::BadRequest().body("Document id must not be empty");
     }
 
-    // Configure SQLite connection pool
-    let cfg = Config::new(&config.database.path);
-    let manager = Manager::from_config(&cfg, deadpool_sqlite::Runtime::Tokio1);
-    let pool = Pool::builder(manager).build().unwrap();
-    let state = AppState {
-        pool,
-        config: config.clone(),
+    let supplied = req.vector.take();
+    let embedded = match embedded_for(&data, &schema, &req.text, supplied).await {
+        Ok(embedded) => embedded,
+        Err(resp) => return resp,
     };
-    info!(
-        "Starting web server at {}:{}",
-        config.server.host, config.server.port
-    );
+    let sparse = match sparse_for(&data, &schema, &req.text).await {
+        Ok(sparse) => sparse,
+        Err(resp) => return resp,
+    };
+    let conn = data.pool.get().await.unwrap();
 
-    // return Err(std::io::Error::new(std::io::ErrorKind::Other, "ASD"));
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(state.clone()))
-            .service(create_collection)
-            .service(delete_collection)
-            .service(insert_vector)
-            .service(search_vectors) // Add the new handler
-            .service(index)
-            .wrap(Logger::default())
-    })
-    .bind((config.server.host, config.server.port))?
-    .run()
-    .await
+    let result = conn
+        .interact(move |conn| {
+            let tx = conn.transaction()?;
+            let rowid = document::upsert(
+                &tx,
+                &collection_name,
+                &schema,
+                None,
+                req.id.as_deref(),
+                &req.text,
+                &embedded,
+                req.metadata.as_ref(),
+            )?;
+            sparse::index(&tx, &collection_name, &schema, rowid, &sparse)?;
+            collection::touch(&tx, &collection_name)?;
+            tx.commit()
+        })
+        .await;
+
+    match result {
+        Ok(Ok(_)) => HttpResponse::Ok().body("Vector inserted successfully"),
+        Ok(Err(e)) if document::is_duplicate(&e) => {
+            HttpResponse::Conflict().body("A document with this id already exists")
+        }
+        Ok(Err(e)) => {
+            error!("Failed to insert vector: {}", e);
+            HttpResponse::InternalServerError().body("Failed to insert vector")
+        }
+        Err(e) => {
+            error!("Failed to insert vector: {}", e);
+            HttpResponse::InternalServerError().body("Failed to insert vector")
+        }
+    }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use actix_web::{http::StatusCode, test};
+#[put("/collection/{name}/{id}")]
+async fn upsert_vector(
+    data: web::Data<AppState>,
+    path: web::Path<(CollectionName, String)>,
+    mut req: web::Json<CreateVectorRequest>,
+) -> impl Responder {
+    let (collection_name, id) = path.into_inner();
+    let schema = match require_collection(&data, &collection_name).await {
+        Ok(schema) => schema,
+        Err(resp) => return resp,
+    };
+    if req.id.as_ref().is_some_and(|body_id| *body_id != id) {
+        return HttpResponse::BadRequest().body("Document id in body does not match the path");
+    }
 
-    async fn create_test_app() -> (
-        web::Data<AppState>,
-        App<
-            impl actix_web::dev::ServiceFactory<
-                actix_web::dev::ServiceRequest,
-                Config = (),
-                Response = actix_web::dev::ServiceResponse,
-                Error = actix_web::Error,
-                InitError = (),
-            >,
-        >,
-    ) {
-        unsafe {
-            rusqlite::ffi::sqlite3_auto_extension(Some(std::mem::transmute(
-                sqlite_vec::sqlite3_vec_init as *const (),
-            )));
+    let conn = data.pool.get().await.unwrap();
+    let (lookup_name, lookup_id) = (collection_name.clone(), id.clone());
+    let existing = match conn
+        .interact(move |conn| document::find(conn, &lookup_name, &lookup_id))
+        .await
+    {
+        Ok(Ok(existing)) => existing,
+        Ok(Err(e)) => {
+            error!("Failed to look up document {}: {}", id, e);
+            return HttpResponse::InternalServerError().body("Failed to upsert vector");
         }
-        let app_config = crate::config::Config::default();
-        let cfg = Config::new(":memory:");
-        let manager = Manager::from_config(&cfg, deadpool_sqlite::Runtime::Tokio1);
-        let pool = Pool::builder(manager).build().unwrap();
-        let state = AppState {
-            pool,
-            config: app_config,
-        };
-        let app_data = web::Data::new(state);
+        Err(e) => {
+            error!("Pool error while looking up document {}: {}", id, e);
+            return HttpResponse::InternalServerError().body("Failed to upsert vector");
+        }
+    };
 
-        let app = App::new()
-            .app_data(app_data.clone())
-            .service(index)
-            .service(create_collection)
-            .service(delete_collection)
-            .service(insert_vector)
-            .service(search_vectors);
+    // Re-embedding is the expensive part, so when the text is the same only
+    // the metadata is rewritten. A supplied vector is always stored.
+    if let Some(doc) = existing
+        .as_ref()
+        .filter(|doc| req.vector.is_none() && doc.text == req.text)
+    {
+        let rowid = doc.rowid;
+        let result = conn
+            .interact(move |conn| {
+                let tx = conn.transaction()?;
+                document::set_metadata(&tx, &collection_name, rowid, req.metadata.as_ref())?;
+                collection::touch(&tx, &collection_name)?;
+                tx.commit()
+            })
+            .await;
 
-        (app_data, app)
+        return match result {
+            Ok(Ok(_)) => HttpResponse::Ok().body("Vector unchanged"),
+            Ok(Err(e)) => {
+                error!("Failed to update metadata: {}", e);
+                HttpResponse::InternalServerError().body("Failed to upsert vector")
+            }
+            Err(e) => {
+                error!("Failed to update metadata: {}", e);
+                HttpResponse::InternalServerError().body("Failed to upsert vector")
+            }
+        };
     }
 
-    #[actix_web::test]
-    async fn test_index() {
-        let (_, app) = create_test_app().await;
-        let app = test::init_service(app).await;
-        let req = test::TestRequest::get().uri("/").to_request();
-        let resp = test::call_service(&app, req).await;
-
-        assert_eq!(resp.status(), StatusCode::OK);
+    // Embedding looks up the cache on a connection of its own, and the model
+    // may take a while, so this one goes back to the pool meanwhile.
+    drop(conn);
+    let supplied = req.vector.take();
+    let embedded = match embedded_for(&data, &schema, &req.text, supplied).await {
+        Ok(embedded) => embedded,
+        Err(resp) => return resp,
+    };
+    let sparse = match sparse_for(&data, &schema, &req.text).await {
+        Ok(sparse) => sparse,
+        Err(resp) => return resp,
+    };
+    let conn = data.pool.get().await.unwrap();
+    let result = conn
+        .interact(move |conn| {
+            // The id is looked up again under the write lock, since another
+            // request may have written or deleted it while this one embedded.
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let existing = document::find(&tx, &collection_name, &id)?;
+            let rowid = document::upsert(
+                &tx,
+                &collection_name,
+                &schema,
+                existing.map(|doc| doc.rowid),
+                Some(&id),
+                &req.text,
+                &embedded,
+                req.metadata.as_ref(),
+            )?;
+            sparse::index(&tx, &collection_name, &schema, rowid, &sparse)?;
+            collection::touch(&tx, &collection_name)?;
+            tx.commit()
+        })
+        .await;
 
-        let body = test::read_body(resp).await;
-        assert_eq!(body, "1");
+    match result {
+        Ok(Ok(_)) => HttpResponse::Ok().body("Vector upserted successfully"),
+        Ok(Err(e)) => {
+            error!("Failed to upsert vector: {}", e);
+            HttpResponse::InternalServerError().body("Failed to upsert vector")
+        }
+        Err(e) => {
+            error!("Failed to upsert vector: {}", e);
+            HttpResponse::InternalServerError().body("Failed to upsert vector")
+        }
     }
+}
 
-    #[actix_web::test]
+/// Stores an image in an image collection, sent either as a multipart form
+/// or as JSON. An image with the id of a stored document replaces it.
+#[post("/collection/{name}/images")]
+async fn upload_image(
+    data: web::Data<AppState>,
+    path: web::Path<CollectionName>,
+    http: HttpRequest,
+    body: web::Payload,
+) -> impl Responder {
+    let collection_name = path.into_inner();
+    let schema = match require_collection(&data, &collection_name).await {
+        Ok(schema) => schema,
+        Err(resp) => return resp,
+    };
+    if schema.image_model.is_none() {
+        return HttpResponse::BadRequest().body("Collection has no image model");
+    }
+
+    let upload = match ImageUpload::read(&http, body).await {
+        Ok(upload) => upload,
+        Err(resp) => return resp,
+    };
+    if upload.id.as_deref() == Some("") {
+        return HttpResponse::BadRequest().body("Document id must not be empty");
+    }
+    let ImageUpload {
+        id,
+        name,
+        image,
+        metadata,
+    } = upload;
+
+    let vector = match image_vector(&data, &schema, image).await {
+        Ok(vector) => vector,
+        Err(resp) => return resp,
+    };
+    let conn = data.pool.get().await.unwrap();
+
+    let result = conn
+        .interact(move |conn| {
+            let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let existing = match id.as_deref() {
+                Some(id) => document::find(&tx, &collection_name, id)?.map(|doc| doc.rowid),
+                None => None,
+            };
+            document::upsert(
+                &tx,
+                &collection_name,
+                &schema,
+                existing,
+                id.as_deref(),
+                name.as_deref().unwrap_or_default(),
+                &Embedded::Whole(vector),
+                metadata.as_ref(),
+            )?;
+            collection::touch(&tx, &collection_name)?;
+            tx.commit()
+        })
+        .await;
+
+    match result {
+        Ok(Ok(_)) => HttpResponse::Ok().body("Image stored successfully"),
+        Ok(Err(e)) => {
+            error!("Failed to store image: {}", e);
+            HttpResponse::InternalServerError().body("Failed to store image")
+        }
+        Err(e) => {
+            error!("Failed to store image: {}", e);
+            HttpResponse::InternalServerError().body("Failed to store image")
+        }
+    }
+}
+
+/// Writes many documents at once. Items with an id replace the document stored
+/// under it like `PUT` does, items without one are appended. Texts are
+/// embedded together and every write happens in one transaction, with a
+/// savepoint per item so a failing item is reported without undoing the rest.
+#[post("/collection/{name}/batch")]
+async fn batch_insert(
+    data: web::Data<AppState>,
+    path: web::Path<CollectionName>,
+    req: web::Json<BatchRequest>,
+) -> impl Responder {
+    let collection_name = path.into_inner();
+    let schema = match require_collection(&data, &collection_name).await {
+        Ok(schema) => schema,
+        Err(resp) => return resp,
+    };
+    let mut documents = req.into_inner().documents;
+
+    let conn = data.pool.get().await.unwrap();
+    let (lookup_name, ids) = (
+        collection_name.clone(),
+        documents
+            .iter()
+            .map(|doc| doc.id.clone())
+            .collect::<Vec<_>>(),
+    );
+    let existing = match conn
+        .interact(move |conn| {
+            ids.iter()
+                .map(|id| match id {
+                    Some(id) => document::find(conn, &lookup_name, id),
+                    None => Ok(None),
+                })
+                .collect::<rusqlite::Result<Vec<_>>>()
+        })
+        .await
+    {
+        Ok(Ok(existing)) => existing,
+        Ok(Err(e)) => {
+            error!("Failed to look up batch documents: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to insert batch");
+        }
+        Err(e) => {
+            error!("Pool error while looking up batch documents: {}", e);
+            return HttpResponse::InternalServerError().body("Failed to insert batch");
+        }
+    };
+
+    // Settle everything that doesn't need the model first, so only new or
+    // changed texts are embedded.
+    let mut seen = std::collections::HashSet::new();
+    let mut writes = Vec::with_capacity(documents.len());
+    let mut pending = Vec::new();
+    let supplied: Vec<_> = documents.iter_mut().map(|doc| doc.vector.take()).collect();
+    for (i, ((doc, existing), vector)) in documents.iter().zip(&existing).zip(supplied).enumerate()
+    {
+        writes.push(match doc.id.as_deref() {
+            Some("") => BatchWrite::Failed("Document id must not be empty".to_string()),
+            Some(id) if !seen.insert(id) => BatchWrite::Failed("Duplicate id in batch".to_string()),
+            _ => match (vector, existing) {
+                (Some(_), _) if schema.chunking.is_some() => {
+                    BatchWrite::Failed(CHUNKED_VECTOR.to_string())
+                }
+                (Some(vector), _) => match schema.check_dimension(&vector) {
+                    Ok(()) => BatchWrite::Vector(Embedded::Whole(vector), Vec::new()),
+                    Err(e) => BatchWrite::Failed(e.to_string()),
+                },
+                (None, Some(stored)) if stored.text == doc.text => BatchWrite::Metadata,
+                // Replaced by the vector once the embedding succeeds.
+                (None, _) => {
+                    pending.push(i);
+                    BatchWrite::Failed("Failed to generate embedding".to_string())
+                }
+            },
+        });
+    }
+
+    // As in `upsert_vector`, the connection isn't held while embedding.
+    drop(conn);
+    // Kept for the sparse pass, so a document isn't split twice.
+    let mut split: Vec<Option<Vec<&str>>> = vec![None; documents.len()];
+    for &i in &pending {
+        match passages(&data, &schema, &documents[i].text).await {
+            Ok(passages) => split[i] = Some(passages),
+            // The item stays failed.
+            Err(e) => error!("Failed to split document for batch: {}", e),
+        }
+    }
+    let pending: Vec<_> = pending
+        .into_iter()
+        .filter(|&i| split[i].is_some())
+        .collect();
+    if !pending.is_empty() {
+        let passages: Vec<_> = pending.iter().map(|&i| split[i].clone().unwrap()).collect();
+        let texts = passages.iter().flatten().copied().collect();
+        match embed_texts(&data, &schema, Role::Passage, texts).await {
+            Ok(vectors) => {
+                let mut vectors = vectors.into_iter();
+                for (&i, passages) in pending.iter().zip(passages) {
+                    let item_vectors = vectors.by_ref().take(passages.len()).collect();
+                    writes[i] =
+                        BatchWrite::Vector(embedded(&schema, passages, item_vectors), Vec::new());
+                }
+            }
+            Err(e) => error!("Failed to generate embeddings for batch: {}", e),
+        }
+    }
+    // Sparse vectors are always computed from the text, including for items
+    // that came with their dense vector.
+    let to_write: Vec<_> = (0..writes.len())
+        .filter(|&i| matches!(writes[i], BatchWrite::Vector(..)))
+        .collect();
+    if schema.sparse_model.is_some() && !to_write.is_empty() {
+        let mut passages_of = Vec::with_capacity(to_write.len());
+        for &i in &to_write {
+            passages_of.push(match split[i].take() {
+                Some(passages) => passages,
+                // Items with a supplied vector, which chunked collections
+                // refuse, so the text is the only passage.
+                None => vec![documents[i].text.as_str()],
+            });
+        }
+        let passages = passages_of;
+        let texts = passages.iter().flatten().copied().collect();
+        match embed_sparse(&data, &schema, texts).await {
+            Ok(vectors) => {
+                let mut vectors = vectors.into_iter();
+                for (&i, passages) in to_write.iter().zip(passages) {
+                    if let BatchWrite::Vector(_, sparse) = &mut writes[i] {
+                        *sparse = vectors.by_ref().take(passages.len()).collect();
+                    }
+                }
+            }
+            Err(e) => {
+                error!("Failed to generate sparse embeddings for batch: {}", e);
+                for i in to_write {
+                    writes[i] = BatchWrite::Failed("Failed to generate embedding".to_string());
+                }
+            }
+        }
+    }
+
+    let conn = data.pool.get().await.unwrap();
+    let result = conn
+        .interact(move |conn| {
+            // Ids are looked up again under the write lock, since other
+            // requests may have written or deleted them while this one
+            // embedded.
+            let mut tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let mut results = Vec::with_capacity(documents.len());
+            for (i, (doc, write)) in documents.into_iter().zip(writes).enumerate() {
+                let existing = match (&write, doc.id.as_deref()) {
+                    (BatchWrite::Failed(_), _) | (_, None) => None,
+                    (_, Some(id)) => document::find(&tx, &collection_name, id)?,
+                };
+                let outcome = match (write, existing) {
+                    (BatchWrite::Failed(error), _) => Err(error),
+                    (BatchWrite::Metadata, Some(stored)) if stored.text == doc.text => {
+                        with_savepoint(&mut tx, i, |conn| {
+                            document::set_metadata(
+                                conn,
+                                &collection_name,
+                                stored.rowid,
+                                doc.metadata.as_ref(),
+                            )?;
+                            Ok(BatchStatus::Unchanged)
+                        })?
+                    }
+                    // Its text changed or it was deleted meanwhile, and this
+                    // text was never embedded.
+                    (BatchWrite::Metadata, _) => {
+                        Err("Document changed while the batch was written".to_string())
+                    }
+                    (BatchWrite::Vector(embedded, sparse), existing) => {
+                        let existing = existing.map(|stored| stored.rowid);
+                        with_savepoint(&mut tx, i, |conn| {
+                            let rowid = document::upsert(
+                                conn,
+                                &collection_name,
+                                &schema,
+                                existing,
+                                doc.id.as_deref(),
+                                &doc.text,
+                                &embedded,
+                                doc.metadata.as_ref(),
+                            )?;
+                            sparse::index(conn, &collection_name, &schema, rowid, &sparse)?;
+                            Ok(match existing {
+                                Some(_) => BatchStatus::Updated,
+                                None => BatchStatus::Inserted,
+                            })
+                        })?
+                    }
+                };
+                let (status, error) = match outcome {
+                    Ok(status) => (status, None),
+                    Err(error) => (BatchStatus::Failed, Some(error)),
+                };
+                results.push(BatchItemResult {
+                    index: i,
+                    id: doc.id,
+                    status,
+                    error,
+                });
+            }
+            collection::touch(&tx, &collection_name)?;
+            tx.commit()?;
+            Ok::<_, rusqlite::Error>(results)
+        })
+        .await;
+
+    match result {
+        Ok(Ok(results)) => HttpResponse::Ok().json(results),
+        Ok(Err(e)) => {
+            error!("Failed to insert batch: {}", e);
+            HttpResponse::InternalServerError().body("Failed to insert batch")
+        }
+        Err(e) => {
+            error!("Failed to insert batch: {}", e);
+            HttpResponse::InternalServerError().body("Failed to insert batch")
+        }
+    }
+}
+
+/// Runs one batch item's writes under a savepoint. A failing item is rolled
+/// back on its own and reported through the inner result, while errors from
+/// the savepoint itself abort the whole batch.
+fn with_savepoint(
+    tx: &mut rusqlite::Transaction,
+    item: usize,
+    write: impl FnOnce(&rusqlite::Connection) -> rusqlite::Result<BatchStatus>,
+) -> rusqlite::Result<Result<BatchStatus, String>> {
+    let savepoint = tx.savepoint()?;
+    match write(&savepoint) {
+        Ok(status) => {
+            savepoint.commit()?;
+            Ok(Ok(status))
+        }
+        // Dropping the savepoint without committing rolls it back.
+        Err(e) if document::is_duplicate(&e) => {
+            Ok(Err("A document with this id already exists".to_string()))
+        }
+        Err(e) => {
+            error!("Failed to write batch item {}: {}", item, e);
+            Ok(Err("Failed to insert vector".to_string()))
+        }
+    }
+}
+
+#[post("/collection/{name}/search")]
+async fn search_vectors(
+    data: web::Data<AppState>,
+    path: web::Path<CollectionName>,
+    mut req: web::Json<SearchRequest>,
+) -> impl Responder {
+    let collection_name = path.into_inner();
+    let schema = match require_collection(&data, &collection_name).await {
+        Ok(schema) => schema,
+        Err(resp) => return resp,
+    };
+    let mode = req.mode;
+    // Hybrid search fuses with the sparse ranking where there is one.
+    let sparse = schema.sparse_model.is_some();
+    let missing = match mode {
+        SearchMode::Vector => None,
+        SearchMode::Keyword if !schema.keyword_index => Some("Collection has no keyword index"),
+        SearchMode::Sparse if !sparse => Some("Collection has no sparse model"),
+        SearchMode::Hybrid if !schema.keyword_index && !sparse => {
+            Some("Collection has no keyword index or sparse model")
+        }
+        _ => None,
+    };
+    if let Some(message) = missing {
+        return HttpResponse::BadRequest().body(message);
+    }
+    if req.image.is_some() {
+        let invalid = if schema.image_model.is_none() {
+            Some("Collection has no image model")
+        } else if mode != SearchMode::Vector {
+            Some("Image search is a vector search")
+        } else if !req.text.is_empty() || req.vector.is_some() {
+            Some("Search by either text, vector or image, not several")
+        } else {
+            None
+        };
+        if let Some(message) = invalid {
+            return HttpResponse::BadRequest().body(message);
+        }
+    }
+    let by_vector = req.vector.is_some() || req.image.is_some();
+    let invalid = match (mode, req.text.is_empty(), by_vector) {
+        (SearchMode::Vector, true, false) => Some("Search needs a text or a vector"),
+        (SearchMode::Vector, false, true) => Some("Search by either text or vector, not both"),
+        (SearchMode::Keyword, _, true) => Some("Keyword search takes a text, not a vector"),
+        (SearchMode::Sparse, _, true) => Some("Sparse search takes a text, not a vector"),
+        (SearchMode::Keyword | SearchMode::Sparse | SearchMode::Hybrid, true, _) => {
+            Some("Keyword, sparse and hybrid search need a text")
+        }
+        _ => None,
+    };
+    if let Some(message) = invalid {
+        return HttpResponse::BadRequest().body(message);
+    }
+    let keyword_weight = req.keyword_weight.unwrap_or(0.5);
+    if !(0.0..=1.0).contains(&keyword_weight) {
+        return HttpResponse::BadRequest().body("keyword_weight must be between 0 and 1");
+    }
+    let limit = req.limit.unwrap_or(10);
+    if limit > search::MAX_K {
+        return HttpResponse::BadRequest().body(format!("limit must be at most {}", search::MAX_K));
+    }
+    let filter = match req.filter.as_deref().map(str::parse::<Filter>).transpose() {
+        Ok(filter) => filter,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+    let reranker = match &req.rerank {
+        None => None,
+        Some(_) if req.text.is_empty() => {
+            return HttpResponse::BadRequest().body("Reranking needs a text")
+        }
+        Some(options) => {
+            let model = match options.model.as_deref() {
+                None => data.models.default_reranker().clone(),
+                Some(name) => match crate::config::reranker(name) {
+                    Some(model) => model,
+                    None => {
+                        return HttpResponse::BadRequest()
+                            .body(format!("Unknown reranker: {}", name))
+                    }
+                },
+            };
+            let top_n = options.top_n.unwrap_or(limit * CANDIDATE_DEPTH);
+            if top_n < limit {
+                return HttpResponse::BadRequest().body("rerank.top_n must be at least the limit");
+            }
+            if top_n > search::MAX_K {
+                return HttpResponse::BadRequest()
+                    .body(format!("rerank.top_n must be at most {}", search::MAX_K));
+            }
+            Some((model, top_n))
+        }
+    };
+    let rescore = match &req.rescore {
+        None => None,
+        Some(_) if matches!(mode, SearchMode::Keyword | SearchMode::Sparse) => {
+            return HttpResponse::BadRequest().body("Rescoring needs a vector or hybrid search")
+        }
+        Some(_) if schema.storage == Storage::Float32 => {
+            return HttpResponse::BadRequest().body("Rescoring needs an int8 or binary collection")
+        }
+        Some(options) => {
+            let top_n = options.top_n.unwrap_or(limit * CANDIDATE_DEPTH);
+            if top_n < limit {
+                return HttpResponse::BadRequest().body("rescore.top_n must be at least the limit");
+            }
+            if top_n > search::MAX_K {
+                return HttpResponse::BadRequest()
+                    .body(format!("rescore.top_n must be at most {}", search::MAX_K));
+            }
+            Some(top_n)
+        }
+    };
+    let diversity = match &req.diversity {
+        None => None,
+        Some(_) if matches!(mode, SearchMode::Keyword | SearchMode::Sparse) => {
+            return HttpResponse::BadRequest().body("Diversity needs a vector or hybrid search")
+        }
+        Some(_) if reranker.is_some() => {
+            return HttpResponse::BadRequest().body("Search can't both rerank and diversify")
+        }
+        Some(options) => {
+            let lambda = options.lambda.unwrap_or(0.5);
+            if !(0.0..=1.0).contains(&lambda) {
+                return HttpResponse::BadRequest().body("diversity.lambda must be between 0 and 1");
+            }
+            let fetch_k = options.fetch_k.unwrap_or(limit * CANDIDATE_DEPTH);
+            if fetch_k < limit {
+                return HttpResponse::BadRequest()
+                    .body("diversity.fetch_k must be at least the limit");
+            }
+            if fetch_k > search::MAX_K {
+                return HttpResponse::BadRequest().body(format!(
+                    "diversity.fetch_k must be at most {}",
+                    search::MAX_K
+                ));
+            }
+            Some((lambda, fetch_k))
+        }
+    };
+    // Reranked and diversified searches fetch a larger set of candidates and
+    // cut it down to `limit` later.
+    let depth = match (&reranker, diversity) {
+        (Some((_, top_n)), _) => *top_n,
+        (None, Some((_, fetch_k))) => fetch_k,
+        (None, None) => limit,
+    };
+    // Grouping needs more chunks than documents, since several chunks of one
+    // document collapse into a single result.
+    let group_by_document = req.group_by_document;
+    let fetch = if group_by_document {
+        depth * CANDIDATE_DEPTH
+    } else {
+        depth
+    };
+    // The nearest-neighbour query is the deepest part of a search, and
+    // sqlite-vec caps how many neighbours it returns.
+    let neighbours = match mode {
+        SearchMode::Vector => fetch,
+        SearchMode::Hybrid => fetch * HYBRID_DEPTH,
+        SearchMode::Keyword | SearchMode::Sparse => 0,
+    };
+    if neighbours > search::MAX_K {
+        return HttpResponse::BadRequest().body(format!(
+            "Search would fetch {} nearest neighbours, at most {} are supported",
+            neighbours,
+            search::MAX_K
+        ));
+    }
+    let query = req.text.clone();
+
+    let vector = match mode {
+        SearchMode::Keyword | SearchMode::Sparse => Vec::new(),
+        SearchMode::Vector | SearchMode::Hybrid => {
+            let supplied = match req.image.take() {
+                Some(encoded) => match decode_image(&encoded) {
+                    Ok(image) => match image_vector(&data, &schema, image).await {
+                        Ok(vector) => Some(vector),
+                        Err(resp) => return resp,
+                    },
+                    Err(resp) => return resp,
+                },
+                None => req.vector.take(),
+            };
+            match vector_for(&data, &schema, Role::Query, &req.text, supplied).await {
+                Ok(vector) => vector,
+                Err(resp) => return resp,
+            }
+        }
+    };
+    let sparse_query = match mode {
+        SearchMode::Sparse | SearchMode::Hybrid if sparse => {
+            match embed_sparse(&data, &schema, vec![&req.text]).await {
+                Ok(vectors) => vectors.into_iter().next().unwrap_or_default(),
+                Err(e) => {
+                    error!("Failed to generate sparse embedding: {}", e);
+                    return HttpResponse::InternalServerError()
+                        .body("Failed to generate embedding");
+                }
+            }
+        }
+        _ => SparseVector::default(),
+    };
+    let conn = data.pool.get().await.unwrap();
+
+    let result = conn
+        .interact(move |conn| {
+            let (name, metric, filter) = (&collection_name, schema.metric, filter.as_ref());
+            // Rescored searches look past the `n` nearest by the quantized
+            // vectors, then keep the `n` nearest by the originals.
+            let nearest = |n: usize| match rescore {
+                None => search::knn(conn, name, &schema, &vector, n, filter),
+                Some(top_n) => {
+                    let mut hits = search::knn(conn, name, &schema, &vector, n.max(top_n), filter)?;
+                    search::rescore(conn, name, &schema, &vector, &mut hits)?;
+                    hits.truncate(n);
+                    Ok(hits)
+                }
+            };
+            let mut results: Vec<SearchResult> = match mode {
+                SearchMode::Vector => {
+                    let hits = nearest(fetch)?;
+                    SearchResult::from_hits(hits, metric)
+                }
+                SearchMode::Keyword => search::keyword(conn, name, &req.text, fetch, filter)?
+                    .into_iter()
+                    .map(SearchResult::from_keyword)
+                    .collect(),
+                SearchMode::Sparse => search::sparse(conn, name, &sparse_query, fetch, filter)?
+                    .into_iter()
+                    .map(SearchResult::from_sparse)
+                    .collect(),
+                SearchMode::Hybrid => {
+                    let per_side = fetch * HYBRID_DEPTH;
+                    let by_vector = nearest(per_side)?;
+                    let lexical = if sparse {
+                        search::sparse(conn, name, &sparse_query, per_side, filter)?
+                    } else {
+                        search::keyword(conn, name, &req.text, per_side, filter)?
+                    };
+                    search::fuse(by_vector, lexical, keyword_weight, fetch)
+                        .into_iter()
+                        .map(|fused| SearchResult::from_fused(fused, sparse))
+                        .collect()
+                }
+            };
+            if group_by_document {
+                results = search::group_by_document(results, |result| result.document, depth);
+            }
+            let Some((lambda, _)) = diversity else {
+                return Ok(results);
+            };
+            let rowids: Vec<_> = results.iter().map(|result| result.rowid).collect();
+            let positions =
+                search::diversify(conn, name, &schema, &vector, &rowids, lambda, limit)?;
+            Ok::<_, rusqlite::Error>(SearchResult::picked(results, positions))
+        })
+        .await;
+
+    let results = match result {
+        Ok(Ok(results)) => results,
+        Ok(Err(e)) => {
+            error!("Database error during search: {}", e);
+            return HttpResponse::InternalServerError().body("Search failed");
+        }
+        Err(e) => {
+            error!("Pool error during search: {}", e);
+            return HttpResponse::InternalServerError().body("Search failed");
+        }
+    };
+    let Some((model, _)) = reranker else {
+        return HttpResponse::Ok().json(results);
+    };
+
+    let documents = results.iter().map(|result| result.key.as_str()).collect();
+    match crate::embedding::rerank(&data.models, &model, &query, documents).await {
+        Ok(scores) => HttpResponse::Ok().json(SearchResult::reranked(results, scores, limit)),
+        Err(e) => {
+            error!("Failed to rerank results: {}", e);
+            HttpResponse::InternalServerError().body("Failed to rerank results")
+        }
+    }
+}
+
+#[get("/collection/{name}/{id}/similar")]
+async fn similar_vectors(
+    data: web::Data<AppState>,
+    path: web::Path<(CollectionName, String)>,
+    query: web::Query<SimilarQuery>,
+) -> impl Responder {
+    let (collection_name, id) = path.into_inner();
+    let schema = match require_collection(&data, &collection_name).await {
+        Ok(schema) => schema,
+        Err(resp) => return resp,
+    };
+    let limit = query.limit.unwrap_or(10);
+    if limit > search::MAX_K {
+        return HttpResponse::BadRequest().body(format!("limit must be at most {}", search::MAX_K));
+    }
+    let filter = match query
+        .filter
+        .as_deref()
+        .map(str::parse::<Filter>)
+        .transpose()
+    {
+        Ok(filter) => filter,
+        Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
+    };
+
+    // The stored vector is reused, so this never touches the embedding model.
+    let metric = schema.metric;
+    let conn = data.pool.get().await.unwrap();
+    let result = conn
+        .interact(move |conn| {
+            search::similar(conn, &collection_name, &schema, &id, limit, filter.as_ref())
+        })
+        .await;
+
+    match result {
+        Ok(Ok(Some(hits))) => HttpResponse::Ok().json(SearchResult::from_hits(hits, metric)),
+        Ok(Ok(None)) => HttpResponse::NotFound().body("Document not found"),
+        Ok(Err(e)) => {
+            error!("Database error during similar search: {}", e);
+            HttpResponse::InternalServerError().body("Search failed")
+        }
+        Err(e) => {
+            error!("Pool error during similar search: {}", e);
+            HttpResponse::InternalServerError().body("Search failed")
+        }
+    }
+}
+
+/// Looks the collection up in the catalog so handlers only ever touch tables
+/// rusticle created, answering 404 for anything else.
+async fn require_collection(
+    data: &AppState,
+    name: &CollectionName,
+) -> Result<collection::Schema, HttpResponse> {
+    let conn = data.pool.get().await.unwrap();
+    let lookup = name.clone();
+    match conn
+        .interact(move |conn| collection::schema(conn, &lookup))
+        .await
+    {
+        Ok(Ok(Some(schema))) => Ok(schema),
+        Ok(Ok(None)) => Err(HttpResponse::NotFound().body("Collection not found")),
+        Ok(Err(e)) => {
+            error!("Failed to look up collection {}: {}", name, e);
+            Err(HttpResponse::InternalServerError().body("Failed to look up collection"))
+        }
+        Err(e) => {
+            error!("Pool error while looking up collection {}: {}", name, e);
+            Err(HttpResponse::InternalServerError().body("Failed to look up collection"))
+        }
+    }
+}
+
+/// Embeds `texts` with the collection's model and its templates for `role`.
+async fn embed_texts(
+    data: &AppState,
+    schema: &collection::Schema,
+    role: Role,
+    texts: Vec<&str>,
+) -> Result<Vec<Vec<f32>>, fastembed::Error> {
+    let model = data.models.resolve(&schema.model).ok_or_else(|| {
+        fastembed::Error::msg(format!(
+            "Collection model {} is not supported",
+            schema.model
+        ))
+    })?;
+    let templates = data.models.templates(&model, schema.templates.as_ref());
+    if !data.cache.enabled() {
+        return crate::embedding::embed(&data.models, &model, &templates, role, texts).await;
+    }
+
+    let dimension = data.models.dimension(&model).unwrap_or_default();
+    let config = data.models.fingerprint(&model);
+    let keys: Vec<_> = texts
+        .iter()
+        .map(|text| {
+            cache::Key::new(
+                model.name(),
+                dimension,
+                &config,
+                templates.template(role),
+                text,
+            )
+        })
+        .collect();
+    // The cache only saves work, so texts it fails to look up are embedded
+    // as if they were never seen.
+    let lookup = keys.clone();
+    let cached = match data.pool.get().await {
+        Ok(conn) => match conn.interact(move |conn| cache::get(conn, &lookup)).await {
+            Ok(Ok(cached)) => Some(cached),
+            Ok(Err(e)) => {
+                warn!("Failed to look up cached embeddings: {}", e);
+                None
+            }
+            Err(e) => {
+                warn!("Pool error while looking up cached embeddings: {}", e);
+                None
+            }
+        },
+        Err(e) => {
+            warn!("Pool error while looking up cached embeddings: {}", e);
+            None
+        }
+    };
+    let mut embeddings = cached.unwrap_or_else(|| vec![None; texts.len()]);
+
+    let misses: Vec<usize> = (0..texts.len())
+        .filter(|&i| embeddings[i].is_none())
+        .collect();
+    data.cache.record(texts.len() - misses.len(), misses.len());
+    if !misses.is_empty() {
+        let computed = crate::embedding::embed(
+            &data.models,
+            &model,
+            &templates,
+            role,
+            misses.iter().map(|&i| texts[i]).collect(),
+        )
+        .await?;
+        let entries: Vec<_> = misses
+            .iter()
+            .zip(&computed)
+            .map(|(&i, vector)| (keys[i].clone(), vector.clone()))
+            .collect();
+        let max_entries = data.cache.max_entries();
+        let stored = match data.pool.get().await {
+            Ok(conn) => conn
+                .interact(move |conn| {
+                    let tx = conn.transaction()?;
+                    cache::put(&tx, &entries, max_entries)?;
+                    tx.commit()
+                })
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|result| result.map_err(|e| e.to_string())),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = stored {
+            warn!("Failed to cache embeddings: {}", e);
+        }
+        for (i, vector) in misses.into_iter().zip(computed) {
+            embeddings[i] = Some(vector);
+        }
+    }
+    Ok(embeddings.into_iter().flatten().collect())
+}
+
+/// Uses a precomputed vector after checking it fits the collection, or embeds
+/// `text` with the server's model when none was supplied.
+async fn vector_for(
+    data: &AppState,
+    schema: &collection::Schema,
+    role: Role,
+    text: &str,
+    vector: Option<Vec<f32>>,
+) -> Result<Vec<f32>, HttpResponse> {
+    if let Some(vector) = vector {
+        return match schema.check_dimension(&vector) {
+            Ok(()) => Ok(vector),
+            Err(e) => Err(HttpResponse::BadRequest().body(e.to_string())),
+        };
+    }
+    match embed_texts(data, schema, role, vec![text]).await {
+        Ok(vectors) => Ok(vectors.into_iter().next().unwrap_or_default()),
+        Err(e) => {
+            error!("Failed to generate embedding: {}", e);
+            Err(HttpResponse::InternalServerError().body("Failed to generate embedding"))
+        }
+    }
+}
+
+/// Decodes an image sent as base64, checking it is in a known format.
+fn decode_image(encoded: &str) -> Result<Vec<u8>, HttpResponse> {
+    let image = image::decode_base64(encoded).map_err(|e| {
+        HttpResponse::BadRequest().body(format!("Image is not valid base64: {}", e))
+    })?;
+    check_image(&image)?;
+    Ok(image)
+}
+
+fn check_image(image: &[u8]) -> Result<(), HttpResponse> {
+    if image::is_image(image) {
+        Ok(())
+    } else {
+        Err(HttpResponse::BadRequest().body("Unsupported image format"))
+    }
+}
+
+/// Embeds an image with the collection's image model.
+async fn image_vector(
+    data: &AppState,
+    schema: &collection::Schema,
+    image: Vec<u8>,
+) -> Result<Vec<f32>, HttpResponse> {
+    let name = schema.image_model.as_deref().unwrap_or_default();
+    let embedded = match data.models.resolve_image(name) {
+        Some(model) => crate::embedding::embed_images(&data.models, &model, vec![image]).await,
+        None => Err(fastembed::Error::msg(format!(
+            "Collection image model {} is not supported",
+            name
+        ))),
+    };
+    match embedded {
+        Ok(vectors) => Ok(vectors.into_iter().next().unwrap_or_default()),
+        Err(e) => {
+            error!("Failed to generate image embedding: {}", e);
+            Err(HttpResponse::InternalServerError().body("Failed to generate embedding"))
+        }
+    }
+}
+
+/// Embeds `texts` with the collection's sparse model, or returns nothing for
+/// collections without one.
+async fn embed_sparse(
+    data: &AppState,
+    schema: &collection::Schema,
+    texts: Vec<&str>,
+) -> Result<Vec<SparseVector>, fastembed::Error> {
+    let Some(name) = &schema.sparse_model else {
+        return Ok(Vec::new());
+    };
+    let model = data.models.resolve_sparse(name).ok_or_else(|| {
+        fastembed::Error::msg(format!("Collection sparse model {} is not supported", name))
+    })?;
+    let texts = texts.into_iter().map(String::from).collect();
+    let model = data.models.sparse_model(&model).await?;
+    web::block(move || model.embed_sparse(texts)).await?
+}
+
+/// The sparse vectors a document is indexed with, one per passage.
+async fn sparse_for(
+    data: &AppState,
+    schema: &collection::Schema,
+    text: &str,
+) -> Result<Vec<SparseVector>, HttpResponse> {
+    let passages = passages(data, schema, text).await.map_err(|e| {
+        error!("Failed to split document: {}", e);
+        HttpResponse::InternalServerError().body("Failed to generate embedding")
+    })?;
+    embed_sparse(data, schema, passages).await.map_err(|e| {
+        error!("Failed to generate sparse embedding: {}", e);
+        HttpResponse::InternalServerError().body("Failed to generate embedding")
+    })
+}
+
+const CHUNKED_VECTOR: &str = "Chunked collections embed their own text, vectors can't be supplied";
+
+/// The texts a document is embedded as: its chunks in a chunked collection,
+/// otherwise the whole text.
+async fn passages<'a>(
+    data: &AppState,
+    schema: &collection::Schema,
+    text: &'a str,
+) -> Result<Vec<&'a str>, fastembed::Error> {
+    let Some(chunking) = &schema.chunking else {
+        return Ok(vec![text]);
+    };
+    let tokens = match chunking.strategy {
+        Strategy::Tokens => {
+            let model = data.models.resolve(&schema.model).ok_or_else(|| {
+                fastembed::Error::msg(format!(
+                    "Collection model {} is not supported",
+                    schema.model
+                ))
+            })?;
+            crate::embedding::tokens(&data.models, &model, text).await?
+        }
+        _ => Vec::new(),
+    };
+    Ok(chunking.split(text, &tokens))
+}
+
+/// Pairs the passages from [`passages`] with their embeddings.
+fn embedded(schema: &collection::Schema, passages: Vec<&str>, vectors: Vec<Vec<f32>>) -> Embedded {
+    match schema.chunking {
+        Some(_) => Embedded::Chunks(
+            passages
+                .into_iter()
+                .map(String::from)
+                .zip(vectors)
+                .collect(),
+        ),
+        None => Embedded::Whole(vectors.into_iter().next().unwrap_or_default()),
+    }
+}
+
+/// Like [`vector_for`] but for a document being written, which chunked
+/// collections embed chunk by chunk.
+async fn embedded_for(
+    data: &AppState,
+    schema: &collection::Schema,
+    text: &str,
+    vector: Option<Vec<f32>>,
+) -> Result<Embedded, HttpResponse> {
+    match (&schema.chunking, vector) {
+        (None, vector) => {
+            return vector_for(data, schema, Role::Passage, text, vector)
+                .await
+                .map(Embedded::Whole)
+        }
+        (Some(_), Some(_)) => return Err(HttpResponse::BadRequest().body(CHUNKED_VECTOR)),
+        (Some(_), None) => {}
+    }
+    let passages = match passages(data, schema, text).await {
+        Ok(passages) => passages,
+        Err(e) => {
+            error!("Failed to split document: {}", e);
+            return Err(HttpResponse::InternalServerError().body("Failed to generate embedding"));
+        }
+    };
+    match embed_texts(data, schema, Role::Passage, passages.clone()).await {
+        Ok(vectors) => Ok(embedded(schema, passages, vectors)),
+        Err(e) => {
+            error!("Failed to generate embedding: {}", e);
+            Err(HttpResponse::InternalServerError().body("Failed to generate embedding"))
+        }
+    }
+}
+
+/// Raises actix's JSON body limit so batches of full documents fit.
+fn json_config(limit: usize) -> web::JsonConfig {
+    web::JsonConfig::default().limit(limit)
+}
+
+/// Raises the multipart form limits to the JSON one, so uploads may be as
+/// large either way.
+fn multipart_config(limit: usize) -> MultipartFormConfig {
+    MultipartFormConfig::default()
+        .total_limit(limit)
+        .memory_limit(limit)
+}
+
+/// Answers malformed path segments, such as invalid collection names, with a
+/// 400 and the reason instead of actix's default 404.
+fn path_config() -> web::PathConfig {
+    web::PathConfig::default().error_handler(|err, _| {
+        let message = err.to_string();
+        actix_web::error::InternalError::from_response(
+            err,
+            HttpResponse::BadRequest().body(message),
+        )
+        .into()
+    })
+}
+
+#[get("/")]
+async fn index(data: web::Data<AppState>) -> impl Responder {
+    let conn = data.pool.get().await.unwrap();
+    let result: u8 = conn
+        .interact(|conn| {
+            let mut stmt = conn.prepare("SELECT 1")?;
+            let mut rows = stmt.query([])?;
+            let row = rows.next()?.unwrap();
+            row.get(0)
+        })
+        .await
+        .unwrap()
+        .unwrap();
+    HttpResponse::Ok().body(result.to_string())
+}
+
+/// Registers sqlite-vec as an auto extension so every pooled connection has
+/// `vec0` available.
+pub(crate) fn register_sqlite_vec() {
+    unsafe {
+        rusqlite::ffi::sqlite3_auto_extension(Some(std::mem::transmute::<
+            *const (),
+            unsafe extern "C" fn(
+                *mut rusqlite::ffi::sqlite3,
+                *mut *mut std::os::raw::c_char,
+                *const rusqlite::ffi::sqlite3_api_routines,
+            ) -> i32,
+        >(
+            sqlite_vec::sqlite3_vec_init as *const ()
+        )));
+    }
+}
+
+#[actix_web::main]
+pub async fn web_entry(config: crate::config::Config) -> std::io::Result<()> {
+    env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
+    let (server, _) = server(config).await?;
+    server.await
+}
+
+/// Opens the database and loads the embedding model, then binds the server
+/// without running it. Also returns the address it listens on, which tells
+/// callers binding port 0 where to find it.
+pub(crate) async fn server(
+    config: crate::config::Config,
+) -> std::io::Result<(actix_web::dev::Server, std::net::SocketAddr)> {
+    register_sqlite_vec();
+
+    // Load the embedding model up front so a missing or broken model fails the
+    // boot instead of every request.
+    let models = match ModelRegistry::new(&config).and_then(|models| {
+        models.get()?;
+        Ok(models)
+    }) {
+        Ok(models) => Arc::new(models),
+        Err(e) => {
+            error!("Failed to load embedding model: {}", e);
+            return Err(std::io::Error::other(e.to_string()));
+        }
+    };
+
+    // Configure SQLite connection pool
+    let cfg = Config::new(&config.database.path);
+    let manager = Manager::from_config(&cfg, deadpool_sqlite::Runtime::Tokio1);
+    let pool = Pool::builder(manager).build().unwrap();
+    let conn = pool.get().await.map_err(std::io::Error::other)?;
+    conn.interact(|conn| collection::init_catalog(conn))
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?
+        .map_err(std::io::Error::other)?;
+    drop(conn);
+    let state = AppState {
+        pool,
+        models,
+        cache: Arc::new(EmbeddingCache::new(&config.embedding.cache)),
+    };
+    let max_body_size = config.server.max_body_size;
+    info!(
+        "Starting web server at {}:{}",
+        config.server.host, config.server.port
+    );
+
+    // return Err(std::io::Error::new(std::io::ErrorKind::Other, "ASD"));
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(web::Data::new(state.clone()))
+            .app_data(json_config(max_body_size))
+            .app_data(multipart_config(max_body_size))
+            .app_data(path_config())
+            .service(create_collection)
+            .service(list_collections)
+            .service(get_collection)
+            .service(delete_collection)
+            .service(delete_document)
+            .service(insert_vector)
+            .service(upsert_vector)
+            .service(batch_insert)
+            .service(upload_image)
+            .service(search_vectors) // Add the new handler
+            .service(similar_vectors)
+            .service(cache_stats)
+            .service(index)
+            .wrap(Logger::default())
+    })
+    .bind((config.server.host, config.server.port))?;
+    let addr = server.addrs()[0];
+    Ok((server.run(), addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test};
+
+    async fn create_test_app() -> (
+        web::Data<AppState>,
+        App<
+            impl actix_web::dev::ServiceFactory<
+                actix_web::dev::ServiceRequest,
+                Config = (),
+                Response = actix_web::dev::ServiceResponse,
+                Error = actix_web::Error,
+                InitError = (),
+            >,
+        >,
+    ) {
+        register_sqlite_vec();
+        let app_config = crate::config::Config::hermetic();
+        let cfg = Config::new(":memory:");
+        let manager = Manager::from_config(&cfg, deadpool_sqlite::Runtime::Tokio1);
+        let pool = Pool::builder(manager).build().unwrap();
+        pool.get()
+            .await
+            .unwrap()
+            .interact(|conn| collection::init_catalog(conn))
+            .await
+            .unwrap()
+            .unwrap();
+        let state = AppState {
+            pool,
+            models: Arc::new(ModelRegistry::new(&app_config).unwrap()),
+            cache: Arc::new(EmbeddingCache::new(&app_config.embedding.cache)),
+        };
+        let app_data = web::Data::new(state);
+
+        let app = App::new()
+            .app_data(app_data.clone())
+            .app_data(json_config(app_config.server.max_body_size))
+            .app_data(multipart_config(app_config.server.max_body_size))
+            .app_data(path_config())
+            .service(index)
+            .service(create_collection)
+            .service(list_collections)
+            .service(get_collection)
+            .service(delete_collection)
+            .service(delete_document)
+            .service(insert_vector)
+            .service(upsert_vector)
+            .service(batch_insert)
+            .service(upload_image)
+            .service(search_vectors)
+            .service(similar_vectors)
+            .service(cache_stats);
+
+        (app_data, app)
+    }
+
+    /// A request for a collection with every option left at its default.
+    fn create_request(name: &str) -> CreateCollectionRequest {
+        CreateCollectionRequest {
+            name: name.parse().unwrap(),
+            vector_size: None,
+            model: None,
+            metric: Metric::default(),
+            keyword_index: false,
+            chunking: None,
+            templates: None,
+            sparse_model: None,
+            image_model: None,
+            storage: Storage::default(),
+        }
+    }
+
+    fn search_request(query: &str) -> SearchRequest {
+        SearchRequest {
+            text: query.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_index() {
+        let (_, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+        let req = test::TestRequest::get().uri("/").to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = test::read_body(resp).await;
+        assert_eq!(body, "1");
+    }
+
+    #[actix_web::test]
     async fn test_create_collection() {
         let (app_data, app) = create_test_app().await;
         let app = test::init_service(app).await;
 
         let req = test::TestRequest::post()
             .uri("/collection")
-            .set_json(&CreateCollectionRequest {
-                name: "test".to_string(),
-                vector_size: 768,
-            })
+            .set_json(&CreateCollectionRequest {
+                vector_size: Some(768),
+                ..create_request("test")
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Check if table exists and verify its structure
+        let conn = app_data.pool.get().await.unwrap();
+        let table_info = conn
+            .interact(|conn| {
+                conn.prepare("SELECT sql FROM sqlite_master WHERE type='table' AND name='test'")?
+                    .query_row([], |row| row.get::<_, String>(0))
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(table_info.contains("vec float[768]"));
+        assert!(table_info.contains("key TEXT"));
+    }
+
+    #[actix_web::test]
+    async fn test_delete_collection() {
+        let (app_data, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(&CreateCollectionRequest {
+                vector_size: Some(10),
+                ..create_request("test")
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::delete()
+            .uri("/collection/test")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Check if table exists
+        let conn = app_data.pool.get().await.unwrap();
+        let table_info = conn
+            .interact(|conn| {
+                conn.prepare("SELECT sql FROM sqlite_master WHERE type='table' AND name='test'")?
+                    .query_row([], |row| row.get::<_, String>(0))
+            })
+            .await
+            .unwrap();
+
+        assert!(table_info.is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_list_and_get_collection() {
+        let (_, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        for name in ["posts", "tags"] {
+            let req = test::TestRequest::post()
+                .uri("/collection")
+                .set_json(&CreateCollectionRequest {
+                    vector_size: Some(4),
+                    ..create_request(name)
+                })
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        let req = test::TestRequest::get().uri("/collection").to_request();
+        let collections: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        let names: Vec<_> = collections.iter().map(|c| c["name"].clone()).collect();
+        assert_eq!(names, vec!["posts", "tags"]);
+
+        let req = test::TestRequest::get()
+            .uri("/collection/posts")
+            .to_request();
+        let info: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(info["dimension"], 4);
+        assert_eq!(info["metric"], "cosine");
+        assert_eq!(info["model"], "hash");
+        assert_eq!(info["count"], 0);
+        assert!(info["created_at"].is_string());
+        assert!(info["updated_at"].is_string());
+
+        let req = test::TestRequest::delete()
+            .uri("/collection/tags")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .uri("/collection/tags")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_rejects_hostile_collection_names() {
+        let (app_data, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(&CreateCollectionRequest {
+                vector_size: Some(4),
+                ..create_request("y")
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(serde_json::json!({"name": "x; DROP TABLE y", "vector_size": 4}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = test::read_body(resp).await;
+        assert!(String::from_utf8_lossy(&body).contains("collection name"));
+
+        for uri in [
+            "/collection/x%3B%20DROP%20TABLE%20y",
+            "/collection/y%22%3B%20DROP%20TABLE%20y%3B%20--",
+            "/collection/_rusticle_collections",
+            "/collection/sqlite_master",
+        ] {
+            let req = test::TestRequest::delete().uri(uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", uri);
+
+            let req = test::TestRequest::post()
+                .uri(&format!("{}/search", uri))
+                .set_json(search_request("query"))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+
+        // Names of sqlite-vec's shadow tables are rejected outright, and
+        // other tables that aren't collections are not reachable either.
+        let req = test::TestRequest::delete()
+            .uri("/collection/y_chunks")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let conn = app_data.pool.get().await.unwrap();
+        conn.interact(|conn| conn.execute("CREATE TABLE plain (x)", ()))
+            .await
+            .unwrap()
+            .unwrap();
+        drop(conn);
+        let req = test::TestRequest::delete()
+            .uri("/collection/plain")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(serde_json::json!({"name": "Plain", "vector_size": 4}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        // Table names ignore case, so a name differing only in case is taken.
+        for name in ["y", "Y"] {
+            let req = test::TestRequest::post()
+                .uri("/collection")
+                .set_json(serde_json::json!({"name": name, "vector_size": 4}))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::CONFLICT, "{}", name);
+        }
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(serde_json::json!({"name": "y_rowids", "vector_size": 4}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let conn = app_data.pool.get().await.unwrap();
+        let tables: i64 = conn
+            .interact(|conn| {
+                conn.query_row(
+                    "SELECT count(*) FROM sqlite_master WHERE name IN ('y', 'y_chunks')",
+                    [],
+                    |row| row.get(0),
+                )
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(tables, 2);
+    }
+
+    #[actix_web::test]
+    async fn test_hyphenated_collection_name() {
+        let (_, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(&CreateCollectionRequest {
+                vector_size: Some(4),
+                ..create_request("blog-posts")
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .uri("/collection/blog-posts")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::delete()
+            .uri("/collection/blog-posts")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn test_insert_vector() {
+        let (app_data, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(&CreateCollectionRequest {
+                vector_size: Some(768),
+                ..create_request("test")
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/collection/test")
+            .set_json(&CreateVectorRequest {
+                id: None,
+                text: "test".to_string(),
+                metadata: None,
+                vector: None,
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Check if vector was inserted
+        let conn = app_data.pool.get().await.unwrap();
+        let vector = conn
+            .interact(|conn| {
+                conn.prepare("SELECT vec FROM test WHERE key = 'test'")?
+                    .query_row([], |row| row.get::<_, Vec<u8>>(0))
+            })
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(vector.len(), 768 * 4);
+    }
+
+    #[actix_web::test]
+    async fn test_search_vectors() {
+        let (_, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(&CreateCollectionRequest {
+                vector_size: Some(768),
+                ..create_request("test")
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/collection/test")
+            .set_json(&CreateVectorRequest {
+                id: None,
+                text: "Cricket legend Sachin tendulkar".to_string(),
+                metadata: None,
+                vector: None,
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/collection/test/search")
+            .set_json(&SearchRequest {
+                limit: Some(1),
+                ..search_request("Roger Federer is a great tennis player")
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        // let body = test::read_body(resp).await;
+        // assert_eq!(body, "[]");
+    }
+
+    #[actix_web::test]
+    async fn test_searches_reuse_model() {
+        let (app_data, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(&CreateCollectionRequest {
+                vector_size: Some(768),
+                ..create_request("test")
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let first = app_data.models.get().unwrap();
+        for text in ["first query", "second query"] {
+            let req = test::TestRequest::post()
+                .uri("/collection/test/search")
+                .set_json(&SearchRequest {
+                    limit: Some(1),
+                    ..search_request(text)
+                })
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        assert!(Arc::ptr_eq(&first, &app_data.models.get().unwrap()));
+        assert_eq!(app_data.models.load_count(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_upsert_vector() {
+        let (app_data, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(&CreateCollectionRequest {
+                vector_size: Some(768),
+                ..create_request("test")
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let upsert = |text: &str| {
+            test::TestRequest::put()
+                .uri("/collection/test/hello-world")
+                .set_json(&CreateVectorRequest {
+                    id: None,
+                    text: text.to_string(),
+                    metadata: None,
+                    vector: None,
+                })
+                .to_request()
+        };
+
+        let body = test::call_and_read_body(&app, upsert("Hello world")).await;
+        assert_eq!(body, "Vector upserted successfully");
+        let body = test::call_and_read_body(&app, upsert("Hello world")).await;
+        assert_eq!(body, "Vector unchanged");
+        let body = test::call_and_read_body(&app, upsert("Hello again")).await;
+        assert_eq!(body, "Vector upserted successfully");
+
+        let conn = app_data.pool.get().await.unwrap();
+        let (count, key): (i64, String) = conn
+            .interact(|conn| {
+                conn.query_row("SELECT count(*), key FROM test", [], |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(key, "Hello again");
+        // Tests share a single in-memory connection, so hand it back to the pool.
+        drop(conn);
+
+        // Posting an id that already exists is a conflict rather than a duplicate.
+        let req = test::TestRequest::post()
+            .uri("/collection/test")
+            .set_json(&CreateVectorRequest {
+                id: Some("hello-world".to_string()),
+                text: "Hello world".to_string(),
+                metadata: None,
+                vector: None,
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let req = test::TestRequest::post()
+            .uri("/collection/test/search")
+            .set_json(&SearchRequest {
+                limit: Some(1),
+                ..search_request("Hello")
+            })
+            .to_request();
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(results[0]["id"], "hello-world");
+    }
+
+    #[actix_web::test]
+    async fn test_upsert_rejects_mismatched_id() {
+        let (_, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(&CreateCollectionRequest {
+                vector_size: Some(4),
+                ..create_request("test")
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::put()
+            .uri("/collection/test/first")
+            .set_json(&CreateVectorRequest {
+                id: Some("second".to_string()),
+                text: "text".to_string(),
+                metadata: None,
+                vector: None,
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_metadata_returned_in_search() {
+        let (_, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(&CreateCollectionRequest {
+                vector_size: Some(768),
+                ..create_request("test")
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let metadata = serde_json::json!({
+            "title": "Hello world",
+            "url": "/posts/hello-world",
+            "tags": ["rust", "sqlite"],
+            "published_at": "2024-05-01",
+            "body": "x".repeat(64 * 1024),
+        });
+        let req = test::TestRequest::put()
+            .uri("/collection/test/hello-world")
+            .set_json(serde_json::json!({"text": "Hello world", "metadata": metadata}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/collection/test/search")
+            .set_json(&SearchRequest {
+                limit: Some(1),
+                ..search_request("Hello")
+            })
+            .to_request();
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(results[0]["metadata"], metadata);
+
+        // Changing only the metadata keeps the vector but rewrites the payload.
+        let req = test::TestRequest::put()
+            .uri("/collection/test/hello-world")
+            .set_json(serde_json::json!({"text": "Hello world", "metadata": {"draft": true}}))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(body, "Vector unchanged");
+
+        let req = test::TestRequest::post()
+            .uri("/collection/test/search")
+            .set_json(&SearchRequest {
+                limit: Some(1),
+                ..search_request("Hello")
+            })
+            .to_request();
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(results[0]["metadata"], serde_json::json!({"draft": true}));
+    }
+
+    #[actix_web::test]
+    async fn test_metadata_must_be_object() {
+        let (_, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(&CreateCollectionRequest {
+                vector_size: Some(4),
+                ..create_request("test")
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/collection/test")
+            .set_json(serde_json::json!({"text": "Hello", "metadata": ["not", "an", "object"]}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_search_with_filter() {
+        let (_, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(&CreateCollectionRequest {
+                vector_size: Some(768),
+                ..create_request("test")
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let posts = [
+            ("rust-1", "Ownership in Rust", "rust", false),
+            ("rust-2", "Borrowing in Rust", "rust", true),
+            ("rust-3", "Lifetimes in Rust", "rust", false),
+            ("go-1", "Goroutines in Go", "go", false),
+            ("go-2", "Channels in Go", "go", false),
+        ];
+        for (id, text, category, draft) in posts {
+            let req = test::TestRequest::put()
+                .uri(&format!("/collection/test/{}", id))
+                .set_json(serde_json::json!({
+                    "text": text,
+                    "metadata": {"category": category, "draft": draft},
+                }))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/collection/test/search")
+            .set_json(&SearchRequest {
+                limit: Some(2),
+                filter: Some(r#"category = "rust" AND draft != true"#.to_string()),
+                ..search_request("Goroutines in Go")
+            })
+            .to_request();
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        let mut ids: Vec<_> = results.iter().map(|r| r["id"].as_str().unwrap()).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["rust-1", "rust-3"]);
+    }
+
+    #[actix_web::test]
+    async fn test_search_rejects_invalid_filter() {
+        let (_, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(&CreateCollectionRequest {
+                vector_size: Some(4),
+                ..create_request("test")
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/collection/test/search")
+            .set_json(&SearchRequest {
+                filter: Some("tag = ".to_string()),
+                ..search_request("query")
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = test::read_body(resp).await;
+        assert!(String::from_utf8_lossy(&body).starts_with("invalid filter"));
+    }
+
+    #[actix_web::test]
+    async fn test_similar_vectors() {
+        let (app_data, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(&CreateCollectionRequest {
+                vector_size: Some(2),
+                ..create_request("test")
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // Store vectors directly so the lookup is known not to need the model.
+        let conn = app_data.pool.get().await.unwrap();
+        conn.interact(|conn| {
+            let name = "test".parse().unwrap();
+            let schema = collection::schema(conn, &name).unwrap().unwrap();
+            for (id, vector) in [
+                ("a", [1.0, 0.0]),
+                ("b", [1.0, 0.1]),
+                ("c", [0.0, 1.0]),
+                ("d", [1.0, 0.2]),
+            ] {
+                let metadata: document::Metadata =
+                    serde_json::from_value(serde_json::json!({"group": id == "d"})).unwrap();
+                document::insert(conn, &name, &schema, Some(id), id, &vector, Some(&metadata))
+                    .unwrap();
+            }
+        })
+        .await
+        .unwrap();
+        drop(conn);
+
+        let req = test::TestRequest::get()
+            .uri("/collection/test/a/similar?limit=2")
+            .to_request();
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        let ids: Vec<_> = results.iter().map(|r| r["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, vec!["b", "d"]);
+
+        let req = test::TestRequest::get()
+            .uri("/collection/test/a/similar?filter=group%20%3D%20true")
+            .to_request();
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        let ids: Vec<_> = results.iter().map(|r| r["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, vec!["d"]);
+
+        let req = test::TestRequest::get()
+            .uri("/collection/test/missing/similar")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        assert_eq!(app_data.models.load_count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_batch_insert() {
+        let (app_data, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(&CreateCollectionRequest {
+                vector_size: Some(768),
+                ..create_request("test")
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::put()
+            .uri("/collection/test/existing")
+            .set_json(serde_json::json!({"text": "Old text"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let mut documents: Vec<_> = (0..300)
+            .map(|i| {
+                serde_json::json!({
+                    "id": format!("post-{}", i),
+                    "text": format!("Post number {}", i),
+                    "metadata": {"n": i},
+                })
+            })
+            .collect();
+        documents.push(serde_json::json!({"id": "existing", "text": "New text"}));
+        documents.push(serde_json::json!({"id": "post-0", "text": "Duplicate"}));
+        documents.push(serde_json::json!({"id": "", "text": "No id"}));
+        documents.push(serde_json::json!({"text": "Anonymous"}));
+
+        let req = test::TestRequest::post()
+            .uri("/collection/test/batch")
+            .set_json(serde_json::json!({"documents": documents}))
+            .to_request();
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(results.len(), 304);
+        assert!(results[..300].iter().all(|r| r["status"] == "inserted"));
+        assert_eq!(results[300]["status"], "updated");
+        assert_eq!(results[301]["status"], "failed");
+        assert_eq!(results[301]["error"], "Duplicate id in batch");
+        assert_eq!(results[302]["status"], "failed");
+        assert_eq!(results[303]["status"], "inserted");
+        assert_eq!(results[303]["index"], 303);
+
+        let req = test::TestRequest::get()
+            .uri("/collection/test")
+            .to_request();
+        let info: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(info["count"], 302);
+
+        let conn = app_data.pool.get().await.unwrap();
+        let key: String = conn
+            .interact(|conn| {
+                let name = "test".parse().unwrap();
+                document::find(conn, &name, "existing").map(|doc| doc.unwrap().text)
+            })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(key, "New text");
+    }
+
+    #[actix_web::test]
+    async fn test_batch_reports_failures_without_embedding() {
+        let (app_data, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(&CreateCollectionRequest {
+                vector_size: Some(2),
+                ..create_request("test")
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let conn = app_data.pool.get().await.unwrap();
+        conn.interact(|conn| {
+            let name = "test".parse().unwrap();
+            let schema = collection::schema(conn, &name).unwrap().unwrap();
+            for id in ["a", "b"] {
+                document::insert(conn, &name, &schema, Some(id), id, &[1.0, 0.0], None).unwrap();
+            }
+        })
+        .await
+        .unwrap();
+        drop(conn);
+
+        // Unchanged texts only rewrite metadata, so nothing here needs the model.
+        let req = test::TestRequest::post()
+            .uri("/collection/test/batch")
+            .set_json(serde_json::json!({"documents": [
+                {"id": "a", "text": "a", "metadata": {"seen": true}},
+                {"id": "", "text": "empty id"},
+                {"id": "a", "text": "a again"},
+                {"id": "b", "text": "b"},
+            ]}))
+            .to_request();
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        let statuses: Vec<_> = results.iter().map(|r| r["status"].clone()).collect();
+        assert_eq!(statuses, vec!["unchanged", "failed", "failed", "unchanged"]);
+        assert_eq!(results[1]["error"], "Document id must not be empty");
+        assert!(results[0].get("error").is_none());
+        assert_eq!(app_data.models.load_count(), 0);
+
+        let req = test::TestRequest::get()
+            .uri("/collection/test/b/similar?filter=seen%20%3D%20true")
+            .to_request();
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        let ids: Vec<_> = results.iter().map(|r| r["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, vec!["a"]);
+
+        let req = test::TestRequest::post()
+            .uri("/collection/missing/batch")
+            .set_json(serde_json::json!({"documents": []}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_precomputed_vectors() {
+        let (app_data, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(&CreateCollectionRequest {
+                vector_size: Some(3),
+                ..create_request("test")
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/collection/test")
+            .set_json(&CreateVectorRequest {
+                id: Some("x".to_string()),
+                text: "x axis".to_string(),
+                metadata: None,
+                vector: Some(vec![1.0, 0.0, 0.0]),
+            })
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::put()
+            .uri("/collection/test/y")
+            .set_json(serde_json::json!({"text": "y axis", "vector": [0.0, 1.0, 0.0]}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::post()
+            .uri("/collection/test/batch")
+            .set_json(serde_json::json!({"documents": [
+                {"id": "z", "text": "z axis", "vector": [0.0, 0.0, 1.0]},
+                {"id": "short", "text": "too short", "vector": [1.0, 0.0]},
+            ]}))
+            .to_request();
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(results[0]["status"], "inserted");
+        assert_eq!(results[1]["status"], "failed");
+        assert_eq!(
+            results[1]["error"],
+            "vector has 2 dimensions but the collection expects 3"
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/collection/test")
+            .set_json(serde_json::json!({"text": "too long", "vector": [1.0, 0.0, 0.0, 0.0]}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::post()
+            .uri("/collection/test/search")
+            .set_json(serde_json::json!({"vector": [0.1, 1.0, 0.0], "limit": 2}))
+            .to_request();
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        let ids: Vec<_> = results.iter().map(|r| r["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, vec!["y", "x"]);
+        assert_eq!(results[0]["key"], "y axis");
+
+        for body in [
+            serde_json::json!({"vector": [1.0, 0.0]}),
+            serde_json::json!({"text": "x", "vector": [1.0, 0.0, 0.0]}),
+            serde_json::json!({"limit": 1}),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/collection/test/search")
+                .set_json(&body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
+
+        assert_eq!(app_data.models.load_count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_collection_metrics() {
+        let (_, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        for metric in ["dot", "l2"] {
+            let req = test::TestRequest::post()
+                .uri("/collection")
+                .set_json(serde_json::json!({"name": metric, "vector_size": 2, "metric": metric}))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+
+            for (id, vector) in [("near", [1.0, 0.0]), ("long", [3.0, 3.0])] {
+                let req = test::TestRequest::put()
+                    .uri(&format!("/collection/{}/{}", metric, id))
+                    .set_json(serde_json::json!({"text": id, "vector": vector}))
+                    .to_request();
+                let resp = test::call_service(&app, req).await;
+                assert_eq!(resp.status(), StatusCode::OK);
+            }
+
+            let req = test::TestRequest::get()
+                .uri(&format!("/collection/{}", metric))
+                .to_request();
+            let info: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(info["metric"], metric);
+        }
+
+        let search = |metric: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/collection/{}/search", metric))
+                .set_json(serde_json::json!({"vector": [1.0, 0.0]}))
+                .to_request()
+        };
+
+        let results: Vec<serde_json::Value> =
+            test::call_and_read_body_json(&app, search("dot")).await;
+        let similarity = |result: &serde_json::Value| result["similarity"].as_f64().unwrap();
+        assert_eq!(results[0]["id"], "long");
+        assert!((similarity(&results[0]) - 3.0).abs() < 1e-4);
+        assert!((similarity(&results[1]) - 1.0).abs() < 1e-4);
+
+        let results: Vec<serde_json::Value> =
+            test::call_and_read_body_json(&app, search("l2")).await;
+        assert_eq!(results[0]["id"], "near");
+        assert_eq!(results[0]["distance"], 0.0);
+        assert_eq!(results[0]["similarity"], 1.0);
+
+        let req = test::TestRequest::get()
+            .uri("/collection/dot/near/similar")
+            .to_request();
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(results[0]["id"], "long");
+        assert!((similarity(&results[0]) - 3.0).abs() < 1e-4);
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(serde_json::json!({"name": "bad", "vector_size": 2, "metric": "hamming"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_quantized_storage() {
+        let (_, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let collections = [
+            serde_json::json!({"name": "int8", "vector_size": 2, "metric": "l2", "storage": "int8"}),
+            serde_json::json!({"name": "binary", "vector_size": 8, "storage": "binary"}),
+        ];
+        for body in collections {
+            let req = test::TestRequest::post()
+                .uri("/collection")
+                .set_json(&body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK, "{}", body);
+        }
+        let put = |collection: &str, id: &str, vector: Vec<f32>| {
+            test::TestRequest::put()
+                .uri(&format!("/collection/{}/{}", collection, id))
+                .set_json(serde_json::json!({"text": id, "vector": vector}))
+                .to_request()
+        };
+        let search = |collection: &str, body: serde_json::Value| {
+            test::TestRequest::post()
+                .uri(&format!("/collection/{}/search", collection))
+                .set_json(body)
+                .to_request()
+        };
+        let similarity = |result: &serde_json::Value| result["similarity"].as_f64().unwrap();
+
+        for (id, vector) in [("near", vec![0.6, 0.8]), ("far", vec![-0.6, 0.8])] {
+            let resp = test::call_service(&app, put("int8", id, vector)).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let req = test::TestRequest::get()
+            .uri("/collection/int8")
+            .to_request();
+        let info: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(info["storage"], "int8");
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(
+            &app,
+            search("int8", serde_json::json!({"vector": [0.6, 0.8]})),
+        )
+        .await;
+        assert_eq!(results[0]["id"], "near");
+        assert!(results[0]["distance"].as_f64().unwrap() < 0.01);
+        assert!((results[1]["distance"].as_f64().unwrap() - 1.2).abs() < 0.01);
+
+        // Each document differs from the query's signs in one component, so
+        // they tie on Hamming distance until rescored against the query.
+        let flipped = |i: usize| -> Vec<f32> {
+            let mut vector = vec![0.9, 0.2, 0.2, 0.2, -0.5, -0.5, -0.5, -0.5];
+            vector[i] = -vector[i];
+            vector
+        };
+        for (id, vector) in [("strong", flipped(0)), ("weak", flipped(3))] {
+            let resp = test::call_service(&app, put("binary", id, vector)).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let query = [0.9, 0.2, 0.2, 0.2, -0.5, -0.5, -0.5, -0.5];
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(
+            &app,
+            search("binary", serde_json::json!({"vector": query})),
+        )
+        .await;
+        assert_eq!(results.len(), 2);
+        assert_eq!(similarity(&results[0]), 0.75);
+        assert_eq!(similarity(&results[1]), 0.75);
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(
+            &app,
+            search(
+                "binary",
+                serde_json::json!({"vector": query, "rescore": {}}),
+            ),
+        )
+        .await;
+        let ids: Vec<_> = results.iter().map(|r| r["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, vec!["weak", "strong"]);
+        // Rescoring compares the query with the float32 originals, not with
+        // their signs.
+        assert!((similarity(&results[0]) - 1.85 / 1.93).abs() < 1e-4);
+        assert!(similarity(&results[0]) > similarity(&results[1]));
+
+        let req = test::TestRequest::get()
+            .uri("/collection/binary/weak/similar")
+            .to_request();
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(results[0]["id"], "strong");
+        assert_eq!(similarity(&results[0]), 0.5);
+
+        for body in [
+            serde_json::json!({"name": "bad", "vector_size": 8, "metric": "l2", "storage": "binary"}),
+            serde_json::json!({"name": "bad", "vector_size": 12, "storage": "binary"}),
+            serde_json::json!({"name": "bad", "vector_size": 8, "metric": "dot", "storage": "int8"}),
+            serde_json::json!({"name": "bad", "vector_size": 8, "storage": "float16"}),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/collection")
+                .set_json(&body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(serde_json::json!({"name": "float", "vector_size": 2}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        for (collection, body) in [
+            (
+                "float",
+                serde_json::json!({"vector": [1.0, 0.0], "rescore": {}}),
+            ),
+            (
+                "binary",
+                serde_json::json!({"vector": query, "limit": 3, "rescore": {"top_n": 2}}),
+            ),
+            (
+                "binary",
+                serde_json::json!({"text": "x", "mode": "keyword", "rescore": {}}),
+            ),
+        ] {
+            let resp = test::call_service(&app, search(collection, body.clone())).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_hybrid_search() {
+        let (app_data, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(serde_json::json!({"name": "posts", "vector_size": 2, "keyword_index": true}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let posts = [
+            (
+                "semantic",
+                "Finding related articles by meaning",
+                [1.0, 0.0],
+            ),
+            ("close", "Nearest neighbours in practice", [0.9, 0.1]),
+            ("exact", "Using vec_distance_l2 in queries", [0.0, 1.0]),
+        ];
+        for (id, text, vector) in posts {
+            let req = test::TestRequest::put()
+                .uri(&format!("/collection/posts/{}", id))
+                .set_json(serde_json::json!({"text": text, "vector": vector}))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        let search = |body: serde_json::Value| {
+            test::TestRequest::post()
+                .uri("/collection/posts/search")
+                .set_json(body)
+                .to_request()
+        };
+        let ids = |results: &[serde_json::Value]| -> Vec<String> {
+            results
+                .iter()
+                .map(|r| r["id"].as_str().unwrap().to_string())
+                .collect()
+        };
+
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(
+            &app,
+            search(serde_json::json!({"text": "vec_distance_l2", "mode": "keyword"})),
+        )
+        .await;
+        assert_eq!(ids(&results), vec!["exact"]);
+        assert!(results[0]["keyword_score"].as_f64().unwrap() > 0.0);
+        assert!(results[0].get("distance").is_none());
+
+        // The exact identifier is the furthest from the query vector, but being
+        // found by both rankings puts it ahead of the nearest neighbour.
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(
+            &app,
+            search(serde_json::json!({
+                "text": "vec_distance_l2",
+                "vector": [1.0, 0.0],
+                "mode": "hybrid",
+                "limit": 2,
+            })),
+        )
+        .await;
+        assert_eq!(ids(&results), vec!["exact", "semantic"]);
+        assert!(results[0]["distance"].is_number());
+        assert!(results[0]["keyword_score"].is_number());
+        assert!(results[1].get("keyword_score").is_none());
+
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(
+            &app,
+            search(serde_json::json!({
+                "text": "vec_distance_l2",
+                "vector": [1.0, 0.0],
+                "mode": "hybrid",
+                "keyword_weight": 0.0,
+            })),
+        )
+        .await;
+        assert_eq!(ids(&results), vec!["semantic", "close", "exact"]);
+
+        // Grouping and hybrid search each multiply how many neighbours are
+        // asked for, up to what sqlite-vec returns from one query.
+        let grouped = |limit: usize| {
+            search(serde_json::json!({
+                "text": "vec_distance_l2",
+                "vector": [1.0, 0.0],
+                "mode": "hybrid",
+                "limit": limit,
+                "group_by_document": true,
+            }))
+        };
+        let results: Vec<serde_json::Value> =
+            test::call_and_read_body_json(&app, grouped(200)).await;
+        assert_eq!(results.len(), 3);
+        let resp = test::call_service(&app, grouped(300)).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        for body in [
+            serde_json::json!({"vector": [1.0, 0.0], "limit": 5000}),
+            serde_json::json!({"vector": [1.0, 0.0], "diversity": {"fetch_k": 5000}}),
+            serde_json::json!({"text": "x", "vector": [1.0, 0.0], "rerank": {"top_n": 5000}}),
+            serde_json::json!({"vector": [1.0, 0.0], "mode": "keyword"}),
+            serde_json::json!({"vector": [1.0, 0.0], "mode": "hybrid"}),
+            serde_json::json!({"text": "x", "mode": "hybrid", "keyword_weight": 1.5}),
+            serde_json::json!({"text": "x", "mode": "fuzzy"}),
+        ] {
+            let resp = test::call_service(&app, search(body.clone())).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/collection/posts")
+            .to_request();
+        let info: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(info["keyword_index"], true);
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(serde_json::json!({"name": "plain", "vector_size": 2}))
             .to_request();
         let resp = test::call_service(&app, req).await;
-
         assert_eq!(resp.status(), StatusCode::OK);
+        let req = test::TestRequest::post()
+            .uri("/collection/plain/search")
+            .set_json(serde_json::json!({"text": "x", "mode": "keyword"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
 
-        // Check if table exists and verify its structure
+        let req = test::TestRequest::delete()
+            .uri("/collection/posts")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
         let conn = app_data.pool.get().await.unwrap();
-        let table_info = conn
+        let leftover: i64 = conn
             .interact(|conn| {
-                conn.prepare("SELECT sql FROM sqlite_master WHERE type='table' AND name='test'")?
-                    .query_row([], |row| row.get::<_, String>(0))
+                conn.query_row(
+                    "SELECT count(*) FROM sqlite_master WHERE name LIKE '_rusticle:%:fts%'",
+                    [],
+                    |row| row.get(0),
+                )
             })
             .await
             .unwrap()
             .unwrap();
+        assert_eq!(leftover, 0);
+        assert_eq!(app_data.models.load_count(), 0);
+    }
 
-        assert!(table_info.contains("vec float[768]"));
-        assert!(table_info.contains("key TEXT"));
+    #[actix_web::test]
+    async fn test_rerank_validation() {
+        let (app_data, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(serde_json::json!({"name": "posts", "vector_size": 2}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        for body in [
+            serde_json::json!({"vector": [1.0, 0.0], "rerank": {}}),
+            serde_json::json!({"text": "x", "rerank": {"model": "bge-small-en"}}),
+            serde_json::json!({"text": "x", "limit": 5, "rerank": {"top_n": 3}}),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/collection/posts/search")
+                .set_json(body.clone())
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
+        assert_eq!(app_data.models.load_count(), 0);
     }
 
     #[actix_web::test]
-    async fn test_delete_collection() {
+    async fn test_diversity() {
         let (app_data, app) = create_test_app().await;
         let app = test::init_service(app).await;
 
         let req = test::TestRequest::post()
             .uri("/collection")
-            .set_json(&CreateCollectionRequest {
-                name: "test".to_string(),
-                vector_size: 10,
-            })
+            .set_json(serde_json::json!({"name": "posts", "vector_size": 2}))
             .to_request();
         let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let posts = [
+            ("series-1", [1.0, 0.05]),
+            ("series-2", [1.0, 0.0]),
+            ("other", [0.5, 0.5]),
+        ];
+        for (id, vector) in posts {
+            let req = test::TestRequest::put()
+                .uri(&format!("/collection/posts/{}", id))
+                .set_json(serde_json::json!({"text": id, "vector": vector}))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        let search = |body: serde_json::Value| {
+            test::TestRequest::post()
+                .uri("/collection/posts/search")
+                .set_json(body)
+                .to_request()
+        };
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(
+            &app,
+            search(serde_json::json!({
+                "vector": [1.0, 0.1],
+                "limit": 2,
+                "diversity": {"lambda": 0.3},
+            })),
+        )
+        .await;
+        let ids: Vec<_> = results.iter().map(|r| r["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, vec!["series-1", "other"]);
+        assert!(results[1]["similarity"].is_number());
+        assert!(results[1]["distance"].is_number());
+
+        for body in [
+            serde_json::json!({"vector": [1.0, 0.1], "diversity": {"lambda": 2.0}}),
+            serde_json::json!({"vector": [1.0, 0.1], "limit": 3, "diversity": {"fetch_k": 2}}),
+            serde_json::json!({"text": "x", "mode": "keyword", "diversity": {}}),
+            serde_json::json!({"text": "x", "rerank": {}, "diversity": {}}),
+        ] {
+            let resp = test::call_service(&app, search(body.clone())).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
+        assert_eq!(app_data.models.load_count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_chunked_documents() {
+        let (_, app) = create_test_app().await;
+        let app = test::init_service(app).await;
 
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(serde_json::json!({
+                "name": "guides",
+                "vector_size": 768,
+                "chunking": {"strategy": "headings", "size": 50},
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::OK);
 
+        let guides = [
+            (
+                "cricket",
+                "# Batting\nSachin Tendulkar scored a hundred centuries.\n\
+                 # Bowling\nShane Warne was a leg spinner.",
+            ),
+            (
+                "tennis",
+                "# Grand slams\nRoger Federer won Wimbledon eight times.",
+            ),
+        ];
+        for (id, text) in guides {
+            let req = test::TestRequest::put()
+                .uri(&format!("/collection/guides/{}", id))
+                .set_json(serde_json::json!({"text": text}))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/collection/guides")
+            .to_request();
+        let info: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(info["count"], 2);
+        assert_eq!(info["chunking"]["strategy"], "headings");
+
+        let search = |group_by_document: bool| {
+            test::TestRequest::post()
+                .uri("/collection/guides/search")
+                .set_json(serde_json::json!({
+                    "text": "famous cricketers",
+                    "group_by_document": group_by_document,
+                }))
+                .to_request()
+        };
+        let results: Vec<serde_json::Value> =
+            test::call_and_read_body_json(&app, search(false)).await;
+        assert_eq!(results.len(), 3);
+        let results: Vec<serde_json::Value> =
+            test::call_and_read_body_json(&app, search(true)).await;
+        let ids: Vec<_> = results.iter().map(|r| r["id"].as_str().unwrap()).collect();
+        assert_eq!(ids, vec!["cricket", "tennis"]);
+        assert!(results[0]["key"].as_str().unwrap().starts_with("# "));
+
         let req = test::TestRequest::delete()
-            .uri("/collection/test")
+            .uri("/collection/guides/cricket")
             .to_request();
         let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let results: Vec<serde_json::Value> =
+            test::call_and_read_body_json(&app, search(false)).await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["id"], "tennis");
+    }
+
+    #[actix_web::test]
+    async fn test_token_chunks() {
+        let (_, app) = create_test_app().await;
+        let app = test::init_service(app).await;
 
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(serde_json::json!({
+                "name": "notes",
+                "chunking": {"strategy": "tokens", "size": 2},
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::OK);
 
-        // Check if table exists
-        let conn = app_data.pool.get().await.unwrap();
-        let table_info = conn
-            .interact(|conn| {
-                conn.prepare("SELECT sql FROM sqlite_master WHERE type='table' AND name='test'")?
-                    .query_row([], |row| row.get::<_, String>(0))
-            })
-            .await
-            .unwrap();
+        let req = test::TestRequest::put()
+            .uri("/collection/notes/bread")
+            .set_json(serde_json::json!({"text": "Sourdough, bread! rye"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
 
-        assert!(table_info.is_err());
+        let req = test::TestRequest::post()
+            .uri("/collection/notes/search")
+            .set_json(serde_json::json!({"text": "rye"}))
+            .to_request();
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        let keys: Vec<_> = results.iter().map(|r| r["key"].as_str().unwrap()).collect();
+        assert_eq!(keys, vec!["rye", "Sourdough, bread"]);
     }
 
     #[actix_web::test]
-    async fn test_insert_vector() {
+    async fn test_chunking_rejections() {
         let (app_data, app) = create_test_app().await;
         let app = test::init_service(app).await;
 
         let req = test::TestRequest::post()
             .uri("/collection")
-            .set_json(&CreateCollectionRequest {
-                name: "test".to_string(),
-                vector_size: 768,
-            })
+            .set_json(serde_json::json!({
+                "name": "guides",
+                "vector_size": 2,
+                "chunking": {"size": 10, "overlap": 10},
+            }))
             .to_request();
         let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
 
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(serde_json::json!({
+                "name": "guides",
+                "vector_size": 2,
+                "chunking": {"strategy": "sentences", "size": 2},
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::OK);
 
+        let req = test::TestRequest::put()
+            .uri("/collection/guides/post")
+            .set_json(serde_json::json!({"text": "Hi.", "vector": [1.0, 0.0]}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test::TestRequest::delete()
+            .uri("/collection/guides/post")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(app_data.models.load_count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_sparse_search() {
+        let (_, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
         let req = test::TestRequest::post()
-            .uri("/collection/test")
-            .set_json(&CreateVectorRequest {
-                text: "test".to_string(),
-            })
+            .uri("/collection")
+            .set_json(serde_json::json!({"name": "recipes", "sparse_model": "hash"}))
             .to_request();
         let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .uri("/collection/recipes")
+            .to_request();
+        let info: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(info["sparse_model"], "hash");
 
+        let recipes = [
+            ("bread", "Sourdough bread with a crisp crust"),
+            ("soup", "Tomato soup for cold evenings"),
+        ];
+        for (id, text) in recipes {
+            let req = test::TestRequest::put()
+                .uri(&format!("/collection/recipes/{}", id))
+                .set_json(serde_json::json!({"text": text}))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let req = test::TestRequest::post()
+            .uri("/collection/recipes/batch")
+            .set_json(serde_json::json!({
+                "documents": [{"id": "salad", "text": "Tomato salad with basil"}]
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::OK);
 
-        // Check if vector was inserted
-        let conn = app_data.pool.get().await.unwrap();
-        let vector = conn
-            .interact(|conn| {
-                conn.prepare("SELECT vec FROM test WHERE key = 'test'")?
-                    .query_row([], |row| row.get::<_, Vec<u8>>(0))
-            })
-            .await
-            .unwrap()
-            .unwrap();
+        let search = |body: serde_json::Value| {
+            test::TestRequest::post()
+                .uri("/collection/recipes/search")
+                .set_json(body)
+                .to_request()
+        };
 
-        assert_eq!(vector.len(), 768 * 4);
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(
+            &app,
+            search(serde_json::json!({"text": "sourdough bread", "mode": "sparse"})),
+        )
+        .await;
+        assert_eq!(results[0]["id"], "bread");
+        assert!(results[0]["sparse_score"].as_f64().unwrap() > 0.0);
+        assert!(results[0].get("distance").is_none());
+
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(
+            &app,
+            search(serde_json::json!({"text": "tomato salad", "mode": "sparse", "limit": 1})),
+        )
+        .await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["id"], "salad");
+
+        // Without a keyword index, hybrid search fuses with the sparse ranking.
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(
+            &app,
+            search(serde_json::json!({"text": "sourdough bread", "mode": "hybrid"})),
+        )
+        .await;
+        assert_eq!(results[0]["id"], "bread");
+        assert!(results[0]["distance"].is_number());
+        assert!(results[0]["sparse_score"].is_number());
+        assert!(results[0].get("keyword_score").is_none());
+
+        let req = test::TestRequest::delete()
+            .uri("/collection/recipes/bread")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let results: Vec<serde_json::Value> = test::call_and_read_body_json(
+            &app,
+            search(serde_json::json!({"text": "sourdough bread", "mode": "sparse"})),
+        )
+        .await;
+        assert!(results.iter().all(|r| r["id"] != "bread"));
+
+        for body in [
+            serde_json::json!({"vector": [1.0, 0.0], "mode": "sparse"}),
+            serde_json::json!({"mode": "sparse"}),
+            serde_json::json!({"text": "x", "mode": "keyword"}),
+            serde_json::json!({"text": "x", "mode": "sparse", "diversity": {}}),
+        ] {
+            let resp = test::call_service(&app, search(body.clone())).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(serde_json::json!({"name": "plain", "sparse_model": "bm25"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let body = test::read_body(resp).await;
+        assert_eq!(body, "Unknown sparse model: bm25");
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(serde_json::json!({"name": "plain"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        for mode in ["sparse", "hybrid"] {
+            let req = test::TestRequest::post()
+                .uri("/collection/plain/search")
+                .set_json(serde_json::json!({"text": "x", "mode": mode}))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", mode);
+        }
+    }
+
+    /// An image as far as the upload checks go: a PNG signature. The hash
+    /// model then reads the words after it as the image's content.
+    fn fake_png(content: &str) -> Vec<u8> {
+        let mut image = b"\x89PNG\r\n\x1a\n".to_vec();
+        image.extend_from_slice(content.as_bytes());
+        image
+    }
+
+    fn base64(bytes: &[u8]) -> String {
+        use base64::Engine;
+        base64::engine::general_purpose::STANDARD.encode(bytes)
     }
 
     #[actix_web::test]
-    async fn test_search_vectors() {
+    async fn test_image_collections() {
         let (app_data, app) = create_test_app().await;
         let app = test::init_service(app).await;
 
         let req = test::TestRequest::post()
             .uri("/collection")
-            .set_json(&CreateCollectionRequest {
-                name: "test".to_string(),
-                vector_size: 768,
-            })
+            .set_json(serde_json::json!({"name": "gallery", "image_model": "hash"}))
             .to_request();
         let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let req = test::TestRequest::get()
+            .uri("/collection/gallery")
+            .to_request();
+        let info: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(info["image_model"], "hash");
+        assert_eq!(info["model"], "hash");
 
+        let upload = |body: serde_json::Value| {
+            test::TestRequest::post()
+                .uri("/collection/gallery/images")
+                .set_json(body)
+                .to_request()
+        };
+        let resp = test::call_service(
+            &app,
+            upload(serde_json::json!({
+                "id": "sunset",
+                "image": base64(&fake_png("red sunset over the sea")),
+                "metadata": {"album": "summer"},
+            })),
+        )
+        .await;
         assert_eq!(resp.status(), StatusCode::OK);
 
+        let mut form = Vec::new();
+        form.extend_from_slice(
+            b"--boundary\r\nContent-Disposition: form-data; name=\"id\"\r\n\r\nharbour\r\n\
+            --boundary\r\nContent-Disposition: form-data; name=\"metadata\"\r\n\r\n\
+            {\"album\": \"winter\"}\r\n\
+            --boundary\r\nContent-Disposition: form-data; name=\"image\"; \
+            filename=\"harbour.png\"\r\nContent-Type: image/png\r\n\r\n",
+        );
+        form.extend_from_slice(&fake_png("fishing boats in the harbour"));
+        form.extend_from_slice(b"\r\n--boundary--\r\n");
         let req = test::TestRequest::post()
-            .uri("/collection/test")
-            .set_json(&CreateVectorRequest {
-                text: "Cricket legend Sachin tendulkar".to_string(),
-            })
+            .uri("/collection/gallery/images")
+            .insert_header(("content-type", "multipart/form-data; boundary=boundary"))
+            .set_payload(form)
             .to_request();
         let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let search = |body: serde_json::Value| {
+            test::TestRequest::post()
+                .uri("/collection/gallery/search")
+                .set_json(body)
+                .to_request()
+        };
+        let results: Vec<serde_json::Value> =
+            test::call_and_read_body_json(&app, search(serde_json::json!({"text": "sunset"})))
+                .await;
+        assert_eq!(results[0]["id"], "sunset");
+        assert_eq!(results[0]["metadata"]["album"], "summer");
+
+        let query = base64(&fake_png("boats in a harbour"));
+        let results: Vec<serde_json::Value> =
+            test::call_and_read_body_json(&app, search(serde_json::json!({"image": query}))).await;
+        assert_eq!(results[0]["id"], "harbour");
+        assert_eq!(results[0]["key"], "harbour.png");
+        assert_eq!(results[0]["metadata"]["album"], "winter");
 
+        // Uploading under a stored id replaces the image.
+        let resp = test::call_service(
+            &app,
+            upload(serde_json::json!({
+                "id": "sunset",
+                "image": format!("data:image/png;base64,{}", base64(&fake_png("a sunrise"))),
+            })),
+        )
+        .await;
         assert_eq!(resp.status(), StatusCode::OK);
+        let req = test::TestRequest::get()
+            .uri("/collection/gallery")
+            .to_request();
+        let info: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(info["count"], 2);
+
+        for body in [
+            serde_json::json!({"image": "not base64!"}),
+            serde_json::json!({"image": base64(b"plain text")}),
+            serde_json::json!({"id": "", "image": base64(&fake_png("empty id"))}),
+            serde_json::json!({"text": "no image"}),
+        ] {
+            let resp = test::call_service(&app, upload(body.clone())).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
+        for body in [
+            serde_json::json!({"image": query, "text": "boats"}),
+            serde_json::json!({"image": query, "mode": "keyword"}),
+            serde_json::json!({"image": "not base64!"}),
+        ] {
+            let resp = test::call_service(&app, search(body.clone())).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
 
         let req = test::TestRequest::post()
-            .uri("/collection/test/search")
-            .set_json(&SearchRequest {
-                text: "Roger Federer is a great tennis player".to_string(),
-                limit: Some(1),
-            })
+            .uri("/collection")
+            .set_json(serde_json::json!({"name": "photos", "image_model": "clip-vit-b-32-vision"}))
             .to_request();
         let resp = test::call_service(&app, req).await;
         assert_eq!(resp.status(), StatusCode::OK);
-        // let body = test::read_body(resp).await;
-        // assert_eq!(body, "[]");
+        let req = test::TestRequest::get()
+            .uri("/collection/photos")
+            .to_request();
+        let info: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(info["model"], "clip-vit-b-32-text");
+        assert_eq!(info["dimension"], 512);
+
+        for (body, message) in [
+            (
+                serde_json::json!({"name": "a", "image_model": "word2vec"}),
+                "Unknown image model: word2vec",
+            ),
+            (
+                serde_json::json!({"name": "a", "image_model": "resnet50"}),
+                "Image model resnet50 has no text encoder, name a model for texts",
+            ),
+            (
+                serde_json::json!({"name": "a", "image_model": "resnet50", "model": "hash"}),
+                "Image model resnet50 produces 2048 dimensions, not 768",
+            ),
+            (
+                serde_json::json!({
+                    "name": "a",
+                    "image_model": "hash",
+                    "chunking": {"size": 100},
+                }),
+                "Image collections can't be chunked",
+            ),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/collection")
+                .set_json(&body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", body);
+            assert_eq!(test::read_body(resp).await, message);
+        }
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(serde_json::json!({"name": "posts"}))
+            .to_request();
+        test::call_service(&app, req).await;
+        let req = test::TestRequest::post()
+            .uri("/collection/posts/images")
+            .set_json(serde_json::json!({"image": query}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(test::read_body(resp).await, "Collection has no image model");
+
+        // The text and image sides of the hash model load once each.
+        assert_eq!(app_data.models.load_count(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_collection_models() {
+        let (app_data, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(serde_json::json!({"name": "tags", "model": "all-minilm-l6-v2"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .uri("/collection/tags")
+            .to_request();
+        let info: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(info["dimension"], 384);
+        assert_eq!(info["model"], "all-minilm-l6-v2");
+
+        for body in [
+            serde_json::json!({"name": "posts", "model": "word2vec"}),
+            serde_json::json!({"name": "posts", "model": "all-minilm-l6-v2", "vector_size": 768}),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/collection")
+                .set_json(&body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
+
+        assert_eq!(app_data.models.load_count(), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_collection_templates() {
+        let (app_data, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(serde_json::json!({
+                "name": "posts",
+                "model": "nomic-embed-text-v1.5-q",
+                "templates": {"query": "{text}"},
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .uri("/collection/posts")
+            .to_request();
+        let info: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(info["templates"], serde_json::json!({"query": "{text}"}));
+
+        // The collection's query template replaces the model's, while its
+        // passage template is still the model's own.
+        let conn = app_data.pool.get().await.unwrap();
+        let schema = conn
+            .interact(|conn| collection::schema(conn, &"posts".parse().unwrap()))
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let model = app_data.models.resolve(&schema.model).unwrap();
+        let templates = app_data.models.templates(&model, schema.templates.as_ref());
+        assert_eq!(templates.apply(Role::Query, "rust"), "rust");
+        assert_eq!(
+            templates.apply(Role::Passage, "rust"),
+            "search_document: rust"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_embedding_cache() {
+        let (_, app) = create_test_app().await;
+        let app = test::init_service(app).await;
+
+        let req = test::TestRequest::post()
+            .uri("/collection")
+            .set_json(serde_json::json!({"name": "posts"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        for _ in 0..2 {
+            let req = test::TestRequest::post()
+                .uri("/collection/posts")
+                .set_json(serde_json::json!({"text": "Rust is fast"}))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        // The hash model has no templates, so a query for the same text is
+        // embedded exactly like the passages.
+        for text in ["Rust is fast", "Rust is safe", "Rust is safe"] {
+            let req = test::TestRequest::post()
+                .uri("/collection/posts/search")
+                .set_json(serde_json::json!({"text": text}))
+                .to_request();
+            let results: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+            assert_eq!(results.len(), 2);
+        }
+
+        let req = test::TestRequest::get().uri("/cache").to_request();
+        let stats: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            stats,
+            serde_json::json!({"entries": 2, "max_entries": 100_000, "hits": 3, "misses": 2})
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_reranked_orders_by_score() {
+        let candidate = |rowid: i64, similarity: f32| SearchResult {
+            rowid,
+            document: rowid,
+            id: None,
+            key: format!("doc {}", rowid),
+            similarity,
+            distance: Some(1.0 - similarity),
+            keyword_score: None,
+            sparse_score: None,
+            rerank_score: None,
+            metadata: serde_json::Value::Null,
+        };
+        let scored = |i: usize, score: f32| RerankResult {
+            document: None,
+            score,
+            index: i,
+        };
+
+        let results = SearchResult::reranked(
+            vec![candidate(1, 0.9), candidate(2, 0.8), candidate(3, 0.7)],
+            vec![scored(0, -2.0), scored(2, 4.5), scored(1, 0.5)],
+            2,
+        );
+        let order: Vec<_> = results
+            .iter()
+            .map(|r| (r.rowid, r.rerank_score.unwrap()))
+            .collect();
+        assert_eq!(order, vec![(3, 4.5), (2, 0.5)]);
+        // The first stage score is kept alongside the reranker's.
+        assert_eq!(results[0].similarity, 0.7);
     }
 }